@@ -0,0 +1,49 @@
+use std::{
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Exposes git and build metadata to /version and /v4/info
+fn main() {
+    let git = |args: &[&str]| {
+        Command::new("git")
+            .args(args)
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+            .unwrap_or_default()
+    };
+
+    let commit = git(&["rev-parse", "--short", "HEAD"]);
+    let branch = git(&["rev-parse", "--abbrev-ref", "HEAD"]);
+    let commit_time = git(&["log", "-1", "--format=%ct"])
+        .parse::<u64>()
+        .map(|secs| secs * 1000)
+        .unwrap_or(0);
+    let build_time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_owned());
+    let rustc_version = Command::new(rustc)
+        .arg("--version")
+        .output()
+        .ok()
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+        .unwrap_or_default();
+
+    println!("cargo:rustc-env=JUKEBOX_GIT_COMMIT={}", commit);
+    println!("cargo:rustc-env=JUKEBOX_GIT_BRANCH={}", branch);
+    println!("cargo:rustc-env=JUKEBOX_GIT_COMMIT_TIME={}", commit_time);
+    println!("cargo:rustc-env=JUKEBOX_BUILD_TIME={}", build_time);
+    println!("cargo:rustc-env=JUKEBOX_RUSTC_VERSION={}", rustc_version);
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    // anything that rebuilds the crate gets a fresh build time
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=Cargo.toml");
+    println!("cargo:rerun-if-changed=Cargo.lock");
+}
@@ -1,22 +1,26 @@
 pub mod payloads;
 pub mod player;
+pub mod recorder;
+pub mod session;
 
-use std::collections::HashMap;
+use std::{future::Future, sync::Arc, time::Duration};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use derivative::Derivative;
 use futures_util::{
     stream::{SplitSink, SplitStream},
     SinkExt, StreamExt,
 };
-use player::Player;
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
-use payloads::ClientPayload;
+use payloads::{ClientPayload, IncomingPayload, Ready, ServerPayload, SessionOpcode};
+use session::{ResumeConfig, Session, SessionRegistry};
 
 use crate::{
+    config::VoiceConfiguration,
     server::Headers,
+    source::SourceRegistry,
     utils::{parse_msg, ReadMessageError},
 };
 
@@ -24,17 +28,30 @@ use crate::{
 type WebSocket = axum::extract::ws::WebSocket;
 type Message = axum::extract::ws::Message;
 
+/// Lavalink protocol spoken over the websocket. v3 clients control players with
+/// websocket ops, v4 clients only receive events and use the REST api instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolVersion {
+    V3,
+    V4,
+}
+
 #[derive(Derivative)]
 #[derivative(Debug)]
 pub struct Client {
-    user_id: String,
-    client_name: String,
-    players: HashMap<String, Player>,
+    session: Arc<Session>,
+    version: ProtocolVersion,
+    resumed: bool,
 
     #[derivative(Debug = "ignore")]
-    from_players_rx: UnboundedReceiver<ClientPayload>,
+    sessions: Arc<SessionRegistry>,
+    #[derivative(Debug = "ignore")]
+    from_players_rx: Option<UnboundedReceiver<ServerPayload>>,
+    // results of the ops that run on their own task
+    #[derivative(Debug = "ignore")]
+    op_results_tx: UnboundedSender<Result<()>>,
     #[derivative(Debug = "ignore")]
-    to_client_tx: UnboundedSender<ClientPayload>,
+    op_results_rx: Option<UnboundedReceiver<Result<()>>>,
 
     #[derivative(Debug = "ignore")]
     ws_reader: SplitStream<WebSocket>,
@@ -43,57 +60,166 @@ pub struct Client {
 }
 
 impl Client {
-    pub fn new(headers: Headers, ws: WebSocket) -> Self {
+    /// Resumes the detached session that the client's resume key refers to, or
+    /// creates and registers a new session if there is none
+    pub fn new(
+        headers: Headers,
+        version: ProtocolVersion,
+        sources: Arc<SourceRegistry>,
+        voice: Arc<VoiceConfiguration>,
+        sessions: Arc<SessionRegistry>,
+        ws: WebSocket,
+    ) -> Self {
         let (ws_writer, ws_reader) = ws.split();
-        let (to_client_tx, from_players_rx) = unbounded_channel();
+        let resumed_session = headers
+            .resume_key
+            .as_deref()
+            .and_then(|key| sessions.resume(key, &headers.user_id));
+        let (session, from_players_rx, resumed) = match resumed_session {
+            Some((session, from_players_rx)) => {
+                info!("Session {} resumed", session.id());
+                (session, from_players_rx, true)
+            }
+            None => {
+                let session = Arc::new(Session::new(
+                    headers.user_id,
+                    headers.client_name,
+                    sources,
+                    voice,
+                ));
+                let from_players_rx = session
+                    .attach()
+                    .expect("nothing else knows about a new session");
+                sessions.insert(session.clone());
+                (session, from_players_rx, false)
+            }
+        };
+        let (op_results_tx, op_results_rx) = unbounded_channel();
         Self {
-            user_id: headers.user_id,
-            client_name: headers.client_name,
-            players: HashMap::new(),
-            to_client_tx,
-            from_players_rx,
+            session,
+            version,
+            resumed,
+            sessions,
+            from_players_rx: Some(from_players_rx),
+            op_results_tx,
+            op_results_rx: Some(op_results_rx),
             ws_reader,
             ws_writer,
         }
     }
 
     pub fn user_id(&self) -> &str {
-        &self.user_id
+        self.session.user_id()
     }
 
     pub fn client_name(&self) -> &str {
-        &self.client_name
+        self.session.client_name()
+    }
+
+    pub fn session(&self) -> &Arc<Session> {
+        &self.session
     }
 
+    pub fn version(&self) -> ProtocolVersion {
+        self.version
+    }
+
+    pub fn resumed(&self) -> bool {
+        self.resumed
+    }
+
+    /// Creates the guild's player right away, so that ops sent after the voice
+    /// update find it, and connects it to the voice server in the background
     #[tracing::instrument(level = "trace")]
-    pub async fn add_player(&mut self, voice_update: payloads::VoiceUpdate) -> Result<()> {
-        let guild_id = voice_update.event.guild_id.clone();
-        let player = Player::new(
-            &self.user_id,
-            voice_update,
-        )
-        .await?;
-        player.connection_manager.play_audio("Ghost Town.webm").await?;
-        self.players.insert(guild_id, player);
-        Ok(())
+    pub async fn add_player(&mut self, voice_update: payloads::VoiceUpdate) {
+        self.session
+            .ensure_player(&voice_update.event.guild_id)
+            .await;
+        let session = self.session.clone();
+        self.spawn_op(async move {
+            session
+                .update_voice(voice_update)
+                .await
+                .context("Error adding player")
+        });
     }
 
     #[tracing::instrument(level = "debug")]
     pub async fn send_to_player(&mut self, client_payload: ClientPayload) -> Result<()> {
-        if let payloads::Opcode::Destroy(_) = client_payload.op {
-            self.players.remove(&client_payload.guild_id);
-            return Ok(());
+        // these need more than the player itself
+        match client_payload.op {
+            payloads::Opcode::Destroy(_) => {
+                self.session.destroy_player(&client_payload.guild_id).await;
+                return Ok(());
+            }
+            payloads::Opcode::StartRecording(_) => {
+                self.session
+                    .start_recording(&client_payload.guild_id)
+                    .await?;
+                return Ok(());
+            }
+            payloads::Opcode::StopRecording(_) => {
+                self.session.stop_recording(&client_payload.guild_id).await;
+                return Ok(());
+            }
+            _ => {}
         }
-        match self.players.get_mut(&client_payload.guild_id) {
-            None => Err(anyhow::anyhow!(
-                "no player found for guild {}",
-                client_payload.guild_id
-            )),
-            Some(player) => {
-                player.handle_client_payload(client_payload).await?;
-                Ok(())
+        // decoded up front so that a bad track leaves the player alone
+        let track = match &client_payload.op {
+            payloads::Opcode::Play(play) => Some(self.session.sources().decode_track(&play.track)?),
+            _ => None,
+        };
+        match self
+            .session
+            .players
+            .lock()
+            .await
+            .get_mut(&client_payload.guild_id)
+        {
+            None => {
+                return Err(anyhow::anyhow!(
+                    "no player found for guild {}",
+                    client_payload.guild_id
+                ))
             }
+            Some(player) => player.handle_client_payload(&client_payload)?,
         }
+
+        // these open the track's source, the session does that without the players
+        // locked
+        let session = self.session.clone();
+        let guild_id = client_payload.guild_id;
+        match (client_payload.op, track) {
+            (payloads::Opcode::Play(play), Some(track)) => self.spawn_op(async move {
+                session
+                    .play(
+                        &guild_id,
+                        track,
+                        Duration::from_millis(play.start_time.unwrap_or(0)),
+                        play.end_time.map(Duration::from_millis),
+                        play.no_replace.unwrap_or(false),
+                    )
+                    .await
+                    .context("Error sending to player")
+            }),
+            (payloads::Opcode::Seek(seek), _) => self.spawn_op(async move {
+                session
+                    .seek(&guild_id, Duration::from_millis(seek.position))
+                    .await
+                    .context("Error sending to player")
+            }),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // voice handshakes and opening sources take a while, so they get their own
+    // task instead of holding up the websocket. `listen` logs how they went
+    fn spawn_op(&self, op: impl Future<Output = Result<()>> + Send + 'static) {
+        let op_results_tx = self.op_results_tx.clone();
+        tokio::spawn(async move {
+            _ = op_results_tx.send(op.await);
+        });
     }
 
     #[tracing::instrument(level = "trace")]
@@ -105,10 +231,26 @@ impl Client {
 
     #[tracing::instrument]
     pub async fn listen(&mut self) -> () {
+        let mut from_players_rx = self
+            .from_players_rx
+            .take()
+            .expect("listen should only be called once");
+        let mut op_results_rx = self
+            .op_results_rx
+            .take()
+            .expect("listen should only be called once");
+
+        // sent before anything buffered while detached
+        let ready = ServerPayload::Ready(Ready {
+            resumed: self.resumed,
+            session_id: self.session.id().to_owned(),
+        });
+        self.send(ready.into()).await;
+
         loop {
             tokio::select! {
                 msg = self.ws_reader.next() => {
-                    let parsed_msg: Result<ClientPayload, ReadMessageError> = parse_msg(msg).await;
+                    let parsed_msg: Result<IncomingPayload, ReadMessageError> = parse_msg(msg).await;
                     match parsed_msg {
                         Ok(payload) => self.handle_payload(payload).await,
                         Err(e)=> match e {
@@ -118,21 +260,37 @@ impl Client {
                         }
                     }
                 },
-                msg = self.from_players_rx.recv() => match msg {
-                    Some(client_payload) => self.send(client_payload.into()).await,
-                    None => unreachable!("a copy of the associated tx always exists inside client"),
+                msg = from_players_rx.recv() => match msg {
+                    Some(server_payload) => self.send(server_payload.into_message(self.version)).await,
+                    None => unreachable!("a copy of the associated tx always exists inside the session"),
+                },
+                result = op_results_rx.recv() => {
+                    // the client holds a tx, so this never gets None
+                    if let Some(Err(e)) = result {
+                        error!("{:#}", e);
+                    }
                 },
             }
         }
+        self.session.detach(from_players_rx, self.sessions.clone());
     }
 
-    async fn handle_payload(&mut self, payload: ClientPayload) {
+    async fn handle_payload(&mut self, payload: IncomingPayload) {
+        if self.version == ProtocolVersion::V4 {
+            warn!(
+                "Ignoring websocket op from v4 client, use the REST api: {:?}",
+                payload
+            );
+            return;
+        }
+        let payload = match payload {
+            IncomingPayload::Session(op) => return self.handle_session_op(op),
+            IncomingPayload::Player(payload) => *payload,
+        };
         match payload.op {
             payloads::Opcode::VoiceUpdate(voice_update) => {
                 info!("Voice update: {:?}", voice_update);
-                if let Err(e) = self.add_player(voice_update).await {
-                    error!("Error adding player: {}", e);
-                }
+                self.add_player(voice_update).await;
             }
             _ => {
                 info!("recieved: {:?}", payload);
@@ -142,4 +300,21 @@ impl Client {
             }
         }
     }
+
+    fn handle_session_op(&mut self, op: SessionOpcode) {
+        match op {
+            SessionOpcode::ConfigureResuming(configure) => {
+                let mut config = ResumeConfig {
+                    resuming: configure.key.is_some(),
+                    key: configure.key,
+                    ..self.session.resume_config()
+                };
+                if let Some(timeout) = configure.timeout {
+                    config.timeout = Duration::from_secs(timeout);
+                }
+                info!("Resuming configured: {:?}", config);
+                self.session.set_resume_config(config);
+            }
+        }
+    }
 }
@@ -1,8 +1,39 @@
 mod transformations;
 
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use serde_with::skip_serializing_none;
 use transformations::*;
 
+use crate::server::payloads::v4::{Exception, Track};
+
+use super::ProtocolVersion;
+
+/// Anything a v3 client can send over the websocket
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum IncomingPayload {
+    Session(SessionOpcode),
+    Player(Box<ClientPayload>),
+}
+
+/// Ops that configure the whole session rather than a single guild's player
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+#[serde(tag = "op")]
+pub enum SessionOpcode {
+    ConfigureResuming(ConfigureResuming),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigureResuming {
+    /// `None` disables resuming
+    pub key: Option<String>,
+    /// Seconds
+    pub timeout: Option<u64>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct ClientPayload {
@@ -18,6 +49,177 @@ impl From<ClientPayload> for axum::extract::ws::Message {
     }
 }
 
+/// Messages sent from jukebox to the client
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+#[serde(tag = "op")]
+pub enum ServerPayload {
+    Ready(Ready),
+    PlayerUpdate(PlayerUpdate),
+    // events carry whole tracks, boxed to keep the other payloads small
+    Event(Box<Event>),
+}
+
+impl From<ServerPayload> for axum::extract::ws::Message {
+    fn from(value: ServerPayload) -> Self {
+        let json = serde_json::to_string(&value).unwrap();
+        axum::extract::ws::Message::Text(json.into())
+    }
+}
+
+impl ServerPayload {
+    /// The payload as a client speaking `version` expects it. Payloads are built
+    /// the v4 way, v3 track events only carry the encoded track and spell their
+    /// enums in upper case.
+    pub fn into_message(self, version: ProtocolVersion) -> axum::extract::ws::Message {
+        if version == ProtocolVersion::V4 {
+            return self.into();
+        }
+        let mut json = serde_json::to_value(&self).unwrap();
+        if let Some(encoded) = json.pointer("/track/encoded").cloned() {
+            json["track"] = encoded;
+        }
+        if json["type"] == "TrackEndEvent" {
+            upper_case(&mut json["reason"]);
+        }
+        if let Some(exception) = json.get_mut("exception") {
+            upper_case(&mut exception["severity"]);
+            json["error"] = json["exception"]["message"].clone();
+        }
+        axum::extract::ws::Message::Text(json.to_string().into())
+    }
+}
+
+// loadFailed to LOAD_FAILED
+fn upper_case(value: &mut Value) {
+    if let Value::String(s) = value {
+        *s = s
+            .chars()
+            .flat_map(|c| c.is_uppercase().then_some('_').into_iter().chain([c]))
+            .collect::<String>()
+            .to_uppercase();
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Ready {
+    pub resumed: bool,
+    pub session_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayerUpdate {
+    pub guild_id: String,
+    pub state: PlayerState,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayerState {
+    pub time: u64,
+    /// Milliseconds
+    pub position: u64,
+    pub connected: bool,
+    /// Milliseconds, -1 if not connected
+    pub ping: i64,
+    /// Missing until discord sends its first receiver report
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rtcp: Option<RtcpReport>,
+}
+
+/// How the audio we send arrives at discord
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RtcpReport {
+    /// Packets lost since the previous report, from 0 to 1
+    pub fraction_lost: f32,
+    /// Packets lost over the whole connection
+    pub packets_lost: i32,
+    /// Milliseconds
+    pub jitter: f64,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Event {
+    pub guild_id: String,
+    #[serde(flatten)]
+    pub event: EventType,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "type")]
+pub enum EventType {
+    TrackStartEvent(TrackStart),
+    TrackEndEvent(TrackEnd),
+    TrackExceptionEvent(TrackException),
+    TrackStuckEvent(TrackStuck),
+    WebSocketClosedEvent(WebSocketClosed),
+    RecordingFinishedEvent(RecordingFinished),
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TrackStart {
+    pub track: Track,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TrackEnd {
+    pub track: Track,
+    pub reason: TrackEndReason,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum TrackEndReason {
+    /// Played to the end, or to its end time
+    Finished,
+    /// The source couldn't be opened
+    LoadFailed,
+    Stopped,
+    /// Another track was played over it
+    Replaced,
+    /// The player was destroyed
+    Cleanup,
+}
+
+/// The track's source failed, it ends right after
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TrackException {
+    pub track: Track,
+    pub exception: Exception,
+}
+
+/// The source delivered no audio for a while, the track keeps waiting for it
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TrackStuck {
+    pub track: Track,
+    pub threshold_ms: u64,
+}
+
+/// A recording was stopped and its files are complete
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RecordingFinished {
+    pub id: String,
+    pub files: Vec<String>,
+}
+
+/// The voice gateway websocket to discord was closed
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct WebSocketClosed {
+    pub code: u16,
+    pub reason: String,
+    pub by_remote: bool,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 #[serde(tag = "op")]
@@ -30,16 +232,21 @@ pub enum Opcode {
     Volume(Volume),
     Filters(Filters),
     Destroy(Destroy),
+    StartRecording(StartRecording),
+    StopRecording(StopRecording),
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 pub struct VoiceUpdate {
     pub session_id: String,
+    /// Needed for end to end encryption, which is skipped without it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel_id: Option<String>,
     pub event: VoiceUpdateEvent,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct VoiceUpdateEvent {
     pub token: String,
     pub guild_id: String,
@@ -57,6 +264,17 @@ pub struct Play {
     pub volume: Option<i16>,
     pub no_replace: Option<bool>,
     pub pause: Option<bool>,
+    pub speaking: Option<SpeakingFlags>,
+}
+
+/// How discord treats our audio, on top of plain speaking
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct SpeakingFlags {
+    /// Priority speaker, lowers everyone else's volume while we talk
+    pub priority: bool,
+    /// Marks the audio as shared sound, like a stream's
+    pub soundshare: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -81,7 +299,12 @@ pub struct Volume {
     pub volume: i16,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Filters that are actually applied to the audio. Opus frames are passed through
+/// without decoding, so for now the rest are only stored.
+pub const SUPPORTED_FILTERS: &[&str] = &[];
+
+#[skip_serializing_none]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct Filters {
     pub volume: Option<f64>,
@@ -90,6 +313,7 @@ pub struct Filters {
     pub timescale: Option<Timescale>,
     pub tremolo: Option<Tremolo>,
     pub vibrato: Option<Vibrato>,
+    pub rotation: Option<Rotation>,
     pub distortion: Option<Distortion>,
     pub channel_mix: Option<ChannelMix>,
     pub low_pass: Option<LowPass>,
@@ -98,3 +322,20 @@ pub struct Filters {
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct Destroy {}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct StartRecording {}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct StopRecording {}
+
+/// A recording in progress
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RecordingState {
+    pub id: String,
+    /// Unix time in milliseconds
+    pub started_at: u64,
+}
@@ -1,13 +1,27 @@
-use std::time::Duration;
+use std::{path::Path, time::Duration};
 
 use anyhow::Result;
 
 use derivative::Derivative;
+use tokio::sync::{broadcast, mpsc::UnboundedSender};
 use tracing::info;
 
-use crate::voice::{VoiceError, VoiceManager};
+use crate::{
+    server::payloads::v4::{now_millis, Exception},
+    source::{AudioTrack, PacketStream, SourceError},
+    voice::{ChannelUser, RtcpStats, VoiceEvent, VoiceManager, VoicePacket},
+};
 
-use super::payloads::{ClientPayload, Opcode, VoiceUpdate};
+use super::{
+    payloads::{
+        ClientPayload, Event, EventType, Filters, Opcode, PlayerState, RtcpReport, ServerPayload,
+        SpeakingFlags, TrackEnd, TrackEndReason, TrackException, TrackStart, TrackStuck,
+        VoiceUpdate, VoiceUpdateEvent,
+    },
+    recorder::Recording,
+};
+
+pub const DEFAULT_VOLUME: i16 = 100;
 
 #[derive(Derivative)]
 #[derivative(Debug)]
@@ -15,52 +29,98 @@ pub struct Player {
     user_id: String,
     guild_id: String,
     session_id: String,
+    channel_id: Option<String>,
+    endpoint: String,
     #[derivative(Debug = "ignore")]
     token: String,
 
-    pub connection_manager: VoiceManager,
+    // `None` until the client sends a voice state
+    pub connection_manager: Option<VoiceManager>,
+    #[derivative(Debug = "ignore")]
+    voice_events_tx: UnboundedSender<VoiceEvent>,
+    // track events for the client
+    #[derivative(Debug = "ignore")]
+    events_tx: UnboundedSender<ServerPayload>,
 
-    track: Option<String>,
-    start_time: Option<Duration>,
+    track: Option<AudioTrack>,
+    // where the track starts once there is a connection to play it on
+    start_time: Duration,
     end_time: Option<Duration>,
-    volume: Option<i16>,
-    no_replace: Option<bool>,
-    pause: Option<bool>,
+    paused: bool,
+    speaking: SpeakingFlags,
+    volume: i16,
+    filters: Filters,
+    recording: Option<Recording>,
+    // paused by us because the channel emptied, rather than by the client
+    auto_paused: bool,
 }
 
 impl Player {
-    #[tracing::instrument]
-    pub async fn new(
+    /// A player that isn't connected to a voice server yet. Tracks can be played
+    /// already, they start once [`Self::migrate`] hands it a connection.
+    pub fn new(
         user_id: &str,
-        voice_update: VoiceUpdate,
-    ) -> Result<Self, VoiceError> {
-        let connection_manager = VoiceManager::new(user_id, voice_update.clone()).await?;
-        Ok(Self {
-            connection_manager,
+        guild_id: &str,
+        voice_events_tx: UnboundedSender<VoiceEvent>,
+        events_tx: UnboundedSender<ServerPayload>,
+    ) -> Self {
+        Self {
+            connection_manager: None,
+            voice_events_tx,
+            events_tx,
             user_id: user_id.to_owned(),
-            guild_id: voice_update.event.guild_id,
-            session_id: voice_update.session_id,
-            token: voice_update.event.token,
+            guild_id: guild_id.to_owned(),
+            session_id: String::new(),
+            channel_id: None,
+            endpoint: String::new(),
+            token: String::new(),
             track: None,
-            start_time: None,
+            start_time: Duration::ZERO,
             end_time: None,
-            volume: None,
-            no_replace: None,
-            pause: None,
-        })
+            paused: false,
+            speaking: SpeakingFlags::default(),
+            volume: DEFAULT_VOLUME,
+            filters: Filters::default(),
+            recording: None,
+            auto_paused: false,
+        }
     }
 
-    pub async fn handle_client_payload(&mut self, client_payload: ClientPayload) -> Result<()> {
-        match client_payload.op {
+    /// Applies everything in `client_payload` that doesn't need the track's source,
+    /// starting or seeking the track is up to the session
+    pub fn handle_client_payload(&mut self, client_payload: &ClientPayload) -> Result<()> {
+        match &client_payload.op {
             Opcode::Destroy(_) => {
                 info!("Destroying player");
                 Err(anyhow::anyhow!("Destroying player"))
             }
-            Opcode::Play(_) => {
-                info!("Playing track");
-                self.connection_manager
-                    .play_audio(String::from("Ghost Town.webm"))
-                    .await?;
+            Opcode::Play(play) => {
+                if let Some(volume) = play.volume {
+                    self.set_volume(volume);
+                }
+                if let Some(pause) = play.pause {
+                    self.set_paused(pause);
+                }
+                if let Some(speaking) = play.speaking {
+                    self.set_speaking_flags(speaking);
+                }
+                Ok(())
+            }
+            Opcode::Stop(_) => {
+                self.stop();
+                Ok(())
+            }
+            Opcode::Pause(pause) => {
+                self.set_paused(pause.pause);
+                Ok(())
+            }
+            Opcode::Seek(_) => Ok(()),
+            Opcode::Volume(volume) => {
+                self.set_volume(volume.volume);
+                Ok(())
+            }
+            Opcode::Filters(filters) => {
+                self.set_filters(filters.clone());
                 Ok(())
             }
             _ => {
@@ -70,6 +130,247 @@ impl Player {
         }
     }
 
+    /// Starts playing `track` from `stream`, its opened source. With `no_replace`,
+    /// nothing happens if a track is already playing.
+    pub async fn play(
+        &mut self,
+        track: AudioTrack,
+        stream: PacketStream,
+        start_time: Duration,
+        end_time: Option<Duration>,
+        no_replace: bool,
+    ) -> Result<()> {
+        if no_replace && self.track().is_some() {
+            info!("Not replacing {}", track.info.title);
+            return Ok(());
+        }
+        info!("Playing track {}", track.info.title);
+        self.end_track(TrackEndReason::Replaced);
+        // without a connection the track waits for one, the stream is opened again then
+        if let Some(connection_manager) = &mut self.connection_manager {
+            connection_manager
+                .play_stream(stream, start_time, end_time)
+                .await?;
+        }
+        self.emit(EventType::TrackStartEvent(TrackStart {
+            track: track.clone().into(),
+        }));
+        self.track = Some(track);
+        self.start_time = start_time;
+        self.end_time = end_time;
+        Ok(())
+    }
+
+    pub fn stop(&mut self) {
+        self.end_track(TrackEndReason::Stopped);
+        if let Some(connection_manager) = &mut self.connection_manager {
+            connection_manager.stop();
+        }
+        self.track = None;
+        self.end_time = None;
+    }
+
+    /// Called when the connection reports the track ran out
+    pub fn track_finished(&mut self) {
+        if !self.is_playing() {
+            self.end_track(TrackEndReason::Finished);
+        }
+    }
+
+    /// Called when the connection reports the track's source broke off
+    pub fn track_failed(&mut self, error: &SourceError) {
+        if self.is_playing() {
+            return;
+        }
+        if let Some(track) = self.track.take() {
+            self.load_failed(&track, error);
+        }
+    }
+
+    pub fn track_stuck(&self, threshold: Duration) {
+        if let Some(track) = self.track() {
+            self.emit(EventType::TrackStuckEvent(TrackStuck {
+                track: track.into(),
+                threshold_ms: threshold.as_millis() as u64,
+            }));
+        }
+    }
+
+    /// Lets the client know that `track` couldn't be opened, which ends it
+    pub fn load_failed(&self, track: &AudioTrack, error: &SourceError) {
+        self.emit(EventType::TrackExceptionEvent(TrackException {
+            track: track.clone().into(),
+            exception: Exception {
+                message: error.to_string(),
+                severity: "common",
+                cause: format!("{:?}", error),
+            },
+        }));
+        self.emit(EventType::TrackEndEvent(TrackEnd {
+            track: track.clone().into(),
+            reason: TrackEndReason::LoadFailed,
+        }));
+    }
+
+    /// The current track couldn't be opened again for a new connection
+    pub fn resume_failed(&mut self, error: &SourceError) {
+        if let Some(track) = self.track.take() {
+            self.load_failed(&track, error);
+        }
+    }
+
+    /// Ends the track for good, the player is being destroyed
+    pub fn clean_up(&mut self) {
+        self.end_track(TrackEndReason::Cleanup);
+    }
+
+    // takes the track off the player and tells the client why it ended. one that
+    // ran out in the meantime finished, whatever else ended it
+    fn end_track(&mut self, reason: TrackEndReason) {
+        let playing = self.is_playing();
+        let Some(track) = self.track.take() else {
+            return;
+        };
+        self.emit(EventType::TrackEndEvent(TrackEnd {
+            track: track.into(),
+            reason: if playing {
+                reason
+            } else {
+                TrackEndReason::Finished
+            },
+        }));
+    }
+
+    fn emit(&self, event: EventType) {
+        // nobody is listening once the session is gone
+        _ = self.events_tx.send(ServerPayload::Event(Box::new(Event {
+            guild_id: self.guild_id.clone(),
+            event,
+        })));
+    }
+
+    /// Restarts the current track from `position`. The demuxers can't seek, so
+    /// `stream` is the track's source opened again, and everything before
+    /// `position` is skipped.
+    pub async fn seek(&mut self, stream: PacketStream, position: Duration) -> Result<()> {
+        self.start_time = position;
+        match &mut self.connection_manager {
+            Some(connection_manager) => {
+                connection_manager
+                    .play_stream(stream, position, self.end_time)
+                    .await
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Moves the player to the voice server in `voice_update`, which
+    /// `connection_manager` is already connected to. `stream` is the current
+    /// track's source opened again, which picks the track back up where the old
+    /// connection left off. The old gateway and udp socket are torn down when the
+    /// old connection is dropped.
+    pub async fn migrate(
+        &mut self,
+        voice_update: VoiceUpdate,
+        mut connection_manager: VoiceManager,
+        stream: Option<PacketStream>,
+    ) -> Result<()> {
+        let track = self.track();
+        let position = self.position();
+
+        self.session_id = voice_update.session_id;
+        self.channel_id = voice_update.channel_id;
+        self.endpoint = voice_update.event.endpoint;
+        self.token = voice_update.event.token;
+        connection_manager.set_paused(self.paused);
+        connection_manager.set_speaking_flags(self.speaking);
+        if let Some(recording) = &self.recording {
+            recording.resubscribe(connection_manager.subscribe());
+        }
+        let connection_manager = self.connection_manager.insert(connection_manager);
+        let Some(stream) = stream.filter(|_| track.is_some()) else {
+            return Ok(());
+        };
+        connection_manager
+            .play_stream(stream, position, self.end_time)
+            .await
+    }
+
+    /// Starts recording everyone else in the channel to files in `directory`
+    pub fn start_recording(&mut self, directory: &Path) -> Result<&Recording> {
+        if let Some(recording) = &self.recording {
+            return Err(anyhow::anyhow!("already recording as {}", recording.id()));
+        }
+        let Some(connection_manager) = &self.connection_manager else {
+            return Err(anyhow::anyhow!("not connected to a voice channel"));
+        };
+        let recording =
+            Recording::start(&self.guild_id, directory, connection_manager.subscribe())?;
+        Ok(self.recording.insert(recording))
+    }
+
+    /// Hands over the current recording, [`Recording::finish`] it to stop
+    pub fn stop_recording(&mut self) -> Option<Recording> {
+        self.recording.take()
+    }
+
+    pub fn recording(&self) -> Option<&Recording> {
+        self.recording.as_ref()
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.auto_paused = false;
+        self.paused = paused;
+        if let Some(connection_manager) = &mut self.connection_manager {
+            connection_manager.set_paused(paused);
+        }
+    }
+
+    /// Pauses the track because nobody is listening, to be undone by
+    /// [`Self::auto_resume`] unless the client pauses or resumes it first
+    pub fn auto_pause(&mut self) {
+        if self.track.is_some() && !self.paused() {
+            info!("Pausing, the voice channel is empty");
+            self.set_paused(true);
+            self.auto_paused = true;
+        }
+    }
+
+    pub fn auto_resume(&mut self) {
+        if self.auto_paused {
+            info!("Resuming, someone joined the voice channel");
+            self.set_paused(false);
+        }
+    }
+
+    /// Everyone else in the voice channel
+    pub fn users(&self) -> Vec<ChannelUser> {
+        self.connection_manager
+            .as_ref()
+            .map_or_else(Vec::new, VoiceManager::users)
+    }
+
+    pub fn speaking_flags(&self) -> SpeakingFlags {
+        self.speaking
+    }
+
+    pub fn set_speaking_flags(&mut self, flags: SpeakingFlags) {
+        self.speaking = flags;
+        if let Some(connection_manager) = &self.connection_manager {
+            connection_manager.set_speaking_flags(flags);
+        }
+    }
+
+    // opus frames are passed through untouched, so volume and filters are only
+    // remembered for clients that read them back
+    pub fn set_volume(&mut self, volume: i16) {
+        self.volume = volume.clamp(0, 1000);
+    }
+
+    pub fn set_filters(&mut self, filters: Filters) {
+        self.filters = filters;
+    }
+
     pub fn user_id(&self) -> &str {
         &self.user_id
     }
@@ -82,31 +383,106 @@ impl Player {
         self.session_id.clone()
     }
 
+    pub fn endpoint(&self) -> String {
+        self.endpoint.clone()
+    }
+
+    pub fn channel_id(&self) -> Option<String> {
+        self.channel_id.clone()
+    }
+
     pub fn token(&self) -> String {
         self.token.clone()
     }
 
-    pub fn track(&self) -> Option<String> {
-        self.track.clone()
+    /// The voice state the player is connected with, empty without a connection
+    pub fn voice_update(&self) -> VoiceUpdate {
+        VoiceUpdate {
+            session_id: self.session_id.clone(),
+            channel_id: self.channel_id.clone(),
+            event: VoiceUpdateEvent {
+                token: self.token.clone(),
+                guild_id: self.guild_id.clone(),
+                endpoint: self.endpoint.clone(),
+            },
+        }
+    }
+
+    pub fn voice_events(&self) -> UnboundedSender<VoiceEvent> {
+        self.voice_events_tx.clone()
+    }
+
+    /// The track being played, `None` once it has finished or been stopped. A
+    /// player without a connection keeps it until it gets one.
+    pub fn track(&self) -> Option<AudioTrack> {
+        self.track.clone().filter(|_| self.is_playing())
+    }
+
+    // a track without a connection is still waiting to be played
+    fn is_playing(&self) -> bool {
+        self.connection_manager
+            .as_ref()
+            .is_none_or(VoiceManager::is_playing)
+    }
+
+    pub fn position(&self) -> Duration {
+        self.connection_manager
+            .as_ref()
+            .map_or(self.start_time, VoiceManager::position)
+    }
+
+    /// Opus frames sent by the other users in the channel, see
+    /// [`VoiceManager::subscribe`]. `None` without a connection.
+    pub fn receive(&self) -> Option<broadcast::Receiver<VoicePacket>> {
+        self.connection_manager
+            .as_ref()
+            .map(VoiceManager::subscribe)
+    }
+
+    /// Round trip time to the voice server, `None` until it has been measured
+    pub fn ping(&self) -> Option<Duration> {
+        self.connection_manager.as_ref()?.ping()
     }
 
-    pub fn start_time(&self) -> Option<Duration> {
-        self.start_time
+    /// Packet loss and jitter discord reports for this player's audio
+    pub fn rtcp_stats(&self) -> Option<RtcpStats> {
+        self.connection_manager.as_ref()?.rtcp_stats()
+    }
+
+    pub fn state(&self) -> PlayerState {
+        let connected = self
+            .connection_manager
+            .as_ref()
+            .is_some_and(VoiceManager::is_connected);
+        PlayerState {
+            time: now_millis(),
+            position: self.position().as_millis() as u64,
+            connected,
+            ping: self
+                .ping()
+                .filter(|_| connected)
+                .map_or(-1, |ping| ping.as_millis() as i64),
+            rtcp: self.rtcp_stats().map(|stats| RtcpReport {
+                fraction_lost: stats.fraction_lost,
+                packets_lost: stats.packets_lost,
+                jitter: stats.jitter.as_secs_f64() * 1000.0,
+            }),
+        }
     }
 
     pub fn end_time(&self) -> Option<Duration> {
         self.end_time
     }
 
-    pub fn volume(&self) -> Option<i16> {
+    pub fn volume(&self) -> i16 {
         self.volume
     }
 
-    pub fn no_replace(&self) -> Option<bool> {
-        self.no_replace
+    pub fn filters(&self) -> &Filters {
+        &self.filters
     }
 
-    pub fn pause(&self) -> Option<bool> {
-        self.pause
+    pub fn paused(&self) -> bool {
+        self.paused
     }
 }
@@ -0,0 +1,326 @@
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind},
+    path::{Component, Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+use tokio::{
+    fs::{self, File},
+    io::{AsyncWriteExt, BufWriter},
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+    task::JoinHandle,
+};
+use tracing::{info, warn};
+
+use crate::{opus_write::OggOpusWriter, server::payloads::v4::now_millis, voice::VoicePacket};
+
+// discord sends 20ms stereo frames
+const SAMPLES_PER_FRAME: u64 = 960;
+const FRAME_DURATION: Duration = Duration::from_millis(20);
+const SILENCE_FRAME: [u8; 3] = [0xf8, 0xff, 0xfe];
+// larger timestamp jumps are treated as a discontinuity rather than silence
+const MAX_RTP_GAP: u32 = 48000 * 60;
+
+/// A recording in progress. Dropping it stops the recording as well, but only
+/// [`Recording::finish`] waits for the files to be written.
+#[derive(Debug)]
+pub struct Recording {
+    id: String,
+    started_at: u64,
+    stop_tx: oneshot::Sender<()>,
+    packets_tx: UnboundedSender<broadcast::Receiver<VoicePacket>>,
+    task: JoinHandle<Vec<PathBuf>>,
+}
+
+impl Recording {
+    /// Starts writing the packets of `packets` to a file per user in `directory`
+    pub fn start(
+        guild_id: &str,
+        directory: &Path,
+        packets: broadcast::Receiver<VoicePacket>,
+    ) -> io::Result<Self> {
+        // ids end up in file names
+        if !is_snowflake(guild_id) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("{:?} is not a guild id", guild_id),
+            ));
+        }
+        let started_at = now_millis();
+        let id = format!("{}-{}", guild_id, started_at);
+        let (stop_tx, stop_rx) = oneshot::channel();
+        let (packets_tx, packets_rx) = unbounded_channel();
+        let task = RecordingTask {
+            directory: directory.to_owned(),
+            prefix: id.clone(),
+            started: Instant::now(),
+            tracks: HashMap::new(),
+        };
+        let task = tokio::spawn(task.run(packets, packets_rx, stop_rx));
+        info!("Started recording {}", id);
+        Ok(Self {
+            id,
+            started_at,
+            stop_tx,
+            packets_tx,
+            task,
+        })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Unix time in milliseconds
+    pub fn started_at(&self) -> u64 {
+        self.started_at
+    }
+
+    /// Continues the recording with the packets of a new voice connection, after a
+    /// reconnect or a move to another voice server
+    pub fn resubscribe(&self, packets: broadcast::Receiver<VoicePacket>) {
+        _ = self.packets_tx.send(packets);
+    }
+
+    /// Stops recording and returns the files that were written
+    pub async fn finish(self) -> Vec<PathBuf> {
+        _ = self.stop_tx.send(());
+        self.task.await.unwrap_or_default()
+    }
+}
+
+struct Track {
+    writer: OggOpusWriter,
+    path: PathBuf,
+    file: BufWriter<File>,
+    frames: u64,
+    last_timestamp: Option<u32>,
+}
+
+struct RecordingTask {
+    directory: PathBuf,
+    prefix: String,
+    started: Instant,
+    tracks: HashMap<String, Track>,
+}
+
+impl RecordingTask {
+    async fn run(
+        mut self,
+        mut packets: broadcast::Receiver<VoicePacket>,
+        mut packets_rx: UnboundedReceiver<broadcast::Receiver<VoicePacket>>,
+        mut stop_rx: oneshot::Receiver<()>,
+    ) -> Vec<PathBuf> {
+        if let Err(e) = fs::create_dir_all(&self.directory).await {
+            warn!("Could not create {}: {}", self.directory.display(), e);
+            return Vec::new();
+        }
+
+        loop {
+            tokio::select! {
+                // also fires when the recording is dropped
+                _ = &mut stop_rx => break,
+                new_packets = packets_rx.recv() => {
+                    let Some(new_packets) = new_packets else { break };
+                    packets = new_packets;
+                    self.restart_timestamps();
+                }
+                packet = packets.recv() => match packet {
+                    Ok(packet) => {
+                        if let Err(e) = self.write(packet).await {
+                            warn!("Recording {} failed: {}", self.prefix, e);
+                            break;
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("Recording {} fell behind, lost {} packets", self.prefix, skipped);
+                    }
+                    // the connection is gone, wait for a new one or the stop
+                    Err(RecvError::Closed) => {
+                        tokio::select! {
+                            _ = &mut stop_rx => break,
+                            new_packets = packets_rx.recv() => {
+                                let Some(new_packets) = new_packets else { break };
+                                packets = new_packets;
+                                self.restart_timestamps();
+                            }
+                        }
+                    }
+                },
+            }
+        }
+
+        let files = match self.finish().await {
+            Ok(files) => files,
+            Err(e) => {
+                warn!("Could not finish recording {}: {}", self.prefix, e);
+                Vec::new()
+            }
+        };
+        info!("Finished recording {}", self.prefix);
+        files
+    }
+
+    // timestamps start over on a new connection
+    fn restart_timestamps(&mut self) {
+        for track in self.tracks.values_mut() {
+            track.last_timestamp = None;
+        }
+    }
+
+    async fn write(&mut self, packet: VoicePacket) -> io::Result<()> {
+        // packets of users we can't name yet are kept apart by ssrc. so are user
+        // ids that aren't, the voice server picks them
+        let key = match &packet.user_id {
+            Some(user_id) if is_snowflake(user_id) => user_id.clone(),
+            _ => format!("ssrc-{}", packet.ssrc),
+        };
+        if !self.tracks.contains_key(&key) {
+            let track = self.open_track(&key).await?;
+            self.tracks.insert(key.clone(), track);
+        }
+
+        let elapsed_frames =
+            (self.started.elapsed().as_millis() / FRAME_DURATION.as_millis()) as u64;
+        let track = self.tracks.get_mut(&key).unwrap();
+        let silent_frames = match track.last_timestamp {
+            Some(last) => {
+                let delta = packet.timestamp.wrapping_sub(last);
+                // a late packet, its slot has already been filled
+                if delta == 0 || delta > u32::MAX / 2 {
+                    return Ok(());
+                }
+                if delta <= MAX_RTP_GAP {
+                    (delta as u64 / SAMPLES_PER_FRAME).saturating_sub(1)
+                } else {
+                    elapsed_frames.saturating_sub(track.frames)
+                }
+            }
+            // line the track up with the start of the recording
+            None => elapsed_frames.saturating_sub(track.frames),
+        };
+        track.last_timestamp = Some(packet.timestamp);
+
+        let mut pages = Vec::new();
+        for _ in 0..silent_frames {
+            pages.extend(
+                track
+                    .writer
+                    .push(SILENCE_FRAME.to_vec(), SAMPLES_PER_FRAME)
+                    .unwrap_or_default(),
+            );
+        }
+        pages.extend(
+            track
+                .writer
+                .push(packet.opus, SAMPLES_PER_FRAME)
+                .unwrap_or_default(),
+        );
+        track.frames += silent_frames + 1;
+        track.file.write_all(&pages).await
+    }
+
+    async fn open_track(&self, key: &str) -> io::Result<Track> {
+        let mut writer = OggOpusWriter::new(rand::thread_rng().gen());
+        let headers = writer.headers(2);
+
+        let path = file_in(&self.directory, &format!("{}-{}.ogg", self.prefix, key))?;
+        let mut track = Track {
+            writer,
+            file: BufWriter::new(File::create(&path).await?),
+            path,
+            frames: 0,
+            last_timestamp: None,
+        };
+        track.file.write_all(&headers).await?;
+        Ok(track)
+    }
+
+    async fn finish(&mut self) -> io::Result<Vec<PathBuf>> {
+        let mut files = Vec::with_capacity(self.tracks.len());
+        for (_, mut track) in self.tracks.drain() {
+            let page = track.writer.finish();
+            track.file.write_all(&page).await?;
+            track.file.flush().await?;
+            files.push(track.path);
+        }
+        Ok(files)
+    }
+}
+
+fn is_snowflake(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 20 && id.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// `name` in `directory`, unless joining them leads anywhere else
+fn file_in(directory: &Path, name: &str) -> io::Result<PathBuf> {
+    let path = directory.join(name);
+    match path.components().next_back() {
+        Some(Component::Normal(_)) if path.parent() == Some(directory) => Ok(path),
+        _ => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("{:?} is not a file in {}", name, directory.display()),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_names_stay_in_the_directory() {
+        let directory = Path::new("recordings");
+        assert_eq!(
+            file_in(directory, "1-2-3.ogg").unwrap(),
+            directory.join("1-2-3.ogg")
+        );
+        for name in ["..", "../x.ogg", "1-2-../../x.ogg", "/etc/x.ogg", "a/x.ogg"] {
+            assert!(file_in(directory, name).is_err(), "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn traversal_ids_are_not_used_as_file_names() {
+        let root = std::env::temp_dir().join(format!("jukebox-recorder-{}", rand::random::<u64>()));
+        let directory = root.join("recordings");
+        let (packets_tx, packets) = broadcast::channel(16);
+        assert!(Recording::start("../../x", &directory, packets_tx.subscribe()).is_err());
+
+        let recording = Recording::start("1", &directory, packets).unwrap();
+        for (ssrc, user_id) in [(1, "../../escaped"), (2, "/tmp/escaped"), (3, "42")] {
+            packets_tx
+                .send(VoicePacket {
+                    ssrc,
+                    user_id: Some(user_id.to_owned()),
+                    sequence: 0,
+                    timestamp: 0,
+                    opus: SILENCE_FRAME.to_vec(),
+                })
+                .unwrap();
+        }
+        // the files are created as the packets come in
+        for _ in 0..100 {
+            if std::fs::read_dir(&directory).map_or(0, |files| files.count()) == 3 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        let id = recording.id().to_owned();
+        let mut files = recording.finish().await;
+        files.sort();
+        assert_eq!(
+            files,
+            ["42", "ssrc-1", "ssrc-2"].map(|key| directory.join(format!("{}-{}.ogg", id, key)))
+        );
+        assert!(!root.join("escaped.ogg").exists());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,655 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
+
+use derivative::Derivative;
+use rand::{distributions::Alphanumeric, Rng};
+use tokio::{
+    sync::{
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        Mutex,
+    },
+    time,
+};
+use tracing::{info, warn};
+
+use crate::{
+    config::VoiceConfiguration,
+    source::{AudioTrack, SourceRegistry},
+    voice::{VoiceError, VoiceEvent, VoiceManager},
+};
+
+use super::{
+    payloads::{
+        Event, EventType, PlayerUpdate, RecordingFinished, RecordingState, ServerPayload,
+        VoiceUpdate, WebSocketClosed,
+    },
+    player::Player,
+};
+
+const SESSION_ID_LENGTH: usize = 16;
+const PLAYER_UPDATE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Resuming configuration set by the client, either through `configureResuming`
+/// or `PATCH /v4/sessions/{sessionId}`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResumeConfig {
+    pub resuming: bool,
+    /// How long players are kept alive after the websocket disconnects
+    pub timeout: Duration,
+    /// v3 resume key. v4 clients resume with the session id instead.
+    pub key: Option<String>,
+}
+
+impl Default for ResumeConfig {
+    fn default() -> Self {
+        Self {
+            resuming: false,
+            timeout: Duration::from_secs(60),
+            key: None,
+        }
+    }
+}
+
+/// State of a client connection that outlives any single request: its players
+/// and settings. Shared between the websocket [`super::Client`] and the REST api,
+/// and kept alive for a while after the websocket closes if resuming is enabled.
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct Session {
+    id: String,
+    user_id: String,
+    client_name: String,
+    pub players: Mutex<HashMap<String, Player>>,
+    resume_config: std::sync::Mutex<ResumeConfig>,
+    // bumped every time a client attaches, so a stale resume timeout can tell that
+    // the session was resumed in the meantime
+    generation: AtomicU64,
+
+    #[derivative(Debug = "ignore")]
+    sources: Arc<SourceRegistry>,
+    #[derivative(Debug = "ignore")]
+    voice_config: Arc<VoiceConfiguration>,
+    #[derivative(Debug = "ignore")]
+    to_client_tx: UnboundedSender<ServerPayload>,
+    // held by the attached client, parked here while detached so that events
+    // pile up in the channel until the client resumes
+    #[derivative(Debug = "ignore")]
+    from_players_rx: std::sync::Mutex<Option<UnboundedReceiver<ServerPayload>>>,
+}
+
+impl Session {
+    pub fn new(
+        user_id: String,
+        client_name: String,
+        sources: Arc<SourceRegistry>,
+        voice_config: Arc<VoiceConfiguration>,
+    ) -> Self {
+        let id = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(SESSION_ID_LENGTH)
+            .map(|c| (c as char).to_ascii_lowercase())
+            .collect();
+        let (to_client_tx, from_players_rx) = unbounded_channel();
+        Self {
+            id,
+            user_id,
+            client_name,
+            players: Mutex::new(HashMap::new()),
+            resume_config: std::sync::Mutex::new(ResumeConfig::default()),
+            generation: AtomicU64::new(0),
+            sources,
+            voice_config,
+            to_client_tx,
+            from_players_rx: std::sync::Mutex::new(Some(from_players_rx)),
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn user_id(&self) -> &str {
+        &self.user_id
+    }
+
+    pub fn client_name(&self) -> &str {
+        &self.client_name
+    }
+
+    pub fn sources(&self) -> &Arc<SourceRegistry> {
+        &self.sources
+    }
+
+    pub fn resume_config(&self) -> ResumeConfig {
+        self.resume_config.lock().unwrap().clone()
+    }
+
+    pub fn set_resume_config(&self, config: ResumeConfig) {
+        *self.resume_config.lock().unwrap() = config;
+    }
+
+    pub fn is_attached(&self) -> bool {
+        self.from_players_rx.lock().unwrap().is_none()
+    }
+
+    /// Hands the event receiver to a newly connected client. Returns `None` if
+    /// another client is already attached.
+    pub fn attach(&self) -> Option<UnboundedReceiver<ServerPayload>> {
+        let rx = self.from_players_rx.lock().unwrap().take()?;
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        Some(rx)
+    }
+
+    /// Called when the client's websocket closes. Without resuming the session is
+    /// dropped along with its players right away, otherwise that happens once the
+    /// resume timeout passes without the client coming back.
+    pub fn detach(
+        self: &Arc<Self>,
+        from_players_rx: UnboundedReceiver<ServerPayload>,
+        sessions: Arc<SessionRegistry>,
+    ) {
+        *self.from_players_rx.lock().unwrap() = Some(from_players_rx);
+
+        let config = self.resume_config();
+        if !config.resuming {
+            sessions.remove(&self.id);
+            return;
+        }
+
+        info!(
+            "Session {} detached, resumable for {:?}",
+            self.id, config.timeout
+        );
+        let generation = self.generation.load(Ordering::SeqCst);
+        let session = Arc::downgrade(self);
+        tokio::spawn(async move {
+            time::sleep(config.timeout).await;
+            let Some(session) = session.upgrade() else {
+                return;
+            };
+            if !session.is_attached() && session.generation.load(Ordering::SeqCst) == generation {
+                info!("Session {} was not resumed in time", session.id);
+                sessions.remove(&session.id);
+            }
+        });
+    }
+
+    /// Connects a player to the voice server in `voice_update`. A guild that already
+    /// has a player is moved over to the new voice server in place, keeping its track
+    /// and position. Nothing happens if the player is already connected with the same
+    /// voice state.
+    #[tracing::instrument(skip(self), fields(session = %self.id))]
+    pub async fn update_voice(
+        self: &Arc<Self>,
+        voice_update: VoiceUpdate,
+    ) -> Result<(), VoiceError> {
+        let guild_id = voice_update.event.guild_id.clone();
+        let existing = self
+            .players
+            .lock()
+            .await
+            .get(&guild_id)
+            .map(|player| (player.voice_update(), player.voice_events()));
+
+        // connecting takes a few round trips to discord, don't hold the lock for it
+        match existing {
+            Some((current, _)) if current == voice_update => Ok(()),
+            Some((current, voice_events_tx)) => {
+                match current.event.endpoint.as_str() {
+                    "" => info!(
+                        "Connecting guild {} to {}",
+                        guild_id, voice_update.event.endpoint
+                    ),
+                    endpoint => info!(
+                        "Migrating guild {} from {} to {}",
+                        guild_id, endpoint, voice_update.event.endpoint
+                    ),
+                }
+                let connection_manager = VoiceManager::new(
+                    &self.user_id,
+                    voice_update.clone(),
+                    &self.voice_config,
+                    voice_events_tx,
+                )
+                .await
+                .inspect_err(|e| self.report_connect_error(&guild_id, e))?;
+                self.hand_over(&guild_id, &current, voice_update, connection_manager)
+                    .await;
+                Ok(())
+            }
+            None => {
+                let (voice_events_tx, voice_events_rx) = unbounded_channel();
+                let connection_manager = VoiceManager::new(
+                    &self.user_id,
+                    voice_update.clone(),
+                    &self.voice_config,
+                    voice_events_tx.clone(),
+                )
+                .await
+                .inspect_err(|e| self.report_connect_error(&guild_id, e))?;
+                match self.players.lock().await.entry(guild_id.clone()) {
+                    Entry::Vacant(entry) => {
+                        let player = entry.insert(Player::new(
+                            &self.user_id,
+                            &guild_id,
+                            voice_events_tx,
+                            self.to_client_tx.clone(),
+                        ));
+                        // nothing to resume on a new player, so this can't fail
+                        _ = player.migrate(voice_update, connection_manager, None).await;
+                        self.spawn_player_task(guild_id, voice_events_rx);
+                    }
+                    // another voice update got there first
+                    Entry::Occupied(_) => {
+                        info!(
+                            "Guild {} already has a player, dropping connection",
+                            guild_id
+                        )
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Creates a player without a connection for the guild if it has none yet.
+    /// Its track starts once a voice update connects it.
+    pub async fn ensure_player(self: &Arc<Self>, guild_id: &str) {
+        if let Entry::Vacant(entry) = self.players.lock().await.entry(guild_id.to_owned()) {
+            let (voice_events_tx, voice_events_rx) = unbounded_channel();
+            entry.insert(Player::new(
+                &self.user_id,
+                guild_id,
+                voice_events_tx,
+                self.to_client_tx.clone(),
+            ));
+            self.spawn_player_task(guild_id.to_owned(), voice_events_rx);
+        }
+    }
+
+    /// Gives the guild's player a new connection to the voice server in
+    /// `voice_update`, if the player is still connected with `current`. Its track
+    /// is reopened for the new connection without the players locked, and
+    /// reopened again if a different track starts in the meantime.
+    async fn hand_over(
+        &self,
+        guild_id: &str,
+        current: &VoiceUpdate,
+        voice_update: VoiceUpdate,
+        connection_manager: VoiceManager,
+    ) {
+        let mut track = self
+            .players
+            .lock()
+            .await
+            .get(guild_id)
+            .and_then(Player::track);
+        loop {
+            let stream = match &track {
+                Some(track) => self.sources.open_stream(track).await.map(Some),
+                None => Ok(None),
+            };
+
+            let mut players = self.players.lock().await;
+            // the player may have been destroyed or moved while we were connecting
+            let Some(player) = players
+                .get_mut(guild_id)
+                .filter(|player| player.voice_update() == *current)
+            else {
+                info!("Player for guild {} is gone, dropping connection", guild_id);
+                return;
+            };
+            let playing = player.track();
+            if playing != track {
+                track = playing;
+                continue;
+            }
+            let stream = stream.unwrap_or_else(|e| {
+                warn!("Could not resume playback in guild {}: {}", guild_id, e);
+                player.resume_failed(&e);
+                None
+            });
+            if let Err(e) = player
+                .migrate(voice_update, connection_manager, stream)
+                .await
+            {
+                warn!("Could not resume playback in guild {}: {}", guild_id, e);
+            }
+            return;
+        }
+    }
+
+    /// Reacts to the voice events of a guild's player and sends its playerUpdate
+    /// periodically. Ends once the player and with it every sender is dropped.
+    fn spawn_player_task(
+        self: &Arc<Self>,
+        guild_id: String,
+        mut voice_events_rx: UnboundedReceiver<VoiceEvent>,
+    ) {
+        let session = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut interval = time::interval(PLAYER_UPDATE_INTERVAL);
+            loop {
+                tokio::select! {
+                    event = voice_events_rx.recv() => {
+                        let (Some(event), Some(session)) = (event, session.upgrade()) else {
+                            break;
+                        };
+                        session.handle_voice_event(&guild_id, event).await;
+                    }
+                    _ = interval.tick() => {
+                        let Some(session) = session.upgrade() else {
+                            break;
+                        };
+                        session.send_player_update(&guild_id).await;
+                    }
+                }
+            }
+        });
+    }
+
+    async fn send_player_update(&self, guild_id: &str) {
+        // updates go stale right away, don't queue them up for a resuming client
+        if !self.is_attached() {
+            return;
+        }
+        let Some(state) = self.players.lock().await.get(guild_id).map(Player::state) else {
+            return;
+        };
+        self.send(ServerPayload::PlayerUpdate(PlayerUpdate {
+            guild_id: guild_id.to_owned(),
+            state,
+        }));
+    }
+
+    async fn handle_voice_event(&self, guild_id: &str, event: VoiceEvent) {
+        match event {
+            VoiceEvent::GatewayClosed {
+                code,
+                reason,
+                by_remote,
+                reconnect,
+            } => {
+                self.send(ServerPayload::Event(Box::new(Event {
+                    guild_id: guild_id.to_owned(),
+                    event: EventType::WebSocketClosedEvent(WebSocketClosed {
+                        code,
+                        reason,
+                        by_remote,
+                    }),
+                })));
+                if reconnect {
+                    self.reconnect_player(guild_id).await;
+                }
+            }
+            VoiceEvent::UdpTimeout => {
+                warn!("Voice udp path for guild {} is dead", guild_id);
+                self.reconnect_player(guild_id).await;
+            }
+            VoiceEvent::ChannelEmpty if self.voice_config.auto_pause => {
+                if let Some(player) = self.players.lock().await.get_mut(guild_id) {
+                    player.auto_pause();
+                }
+            }
+            VoiceEvent::ChannelOccupied if self.voice_config.auto_pause => {
+                if let Some(player) = self.players.lock().await.get_mut(guild_id) {
+                    player.auto_resume();
+                }
+            }
+            VoiceEvent::ChannelEmpty | VoiceEvent::ChannelOccupied => {}
+            VoiceEvent::TrackFinished => {
+                if let Some(player) = self.players.lock().await.get_mut(guild_id) {
+                    player.track_finished();
+                }
+            }
+            VoiceEvent::TrackFailed(error) => {
+                if let Some(player) = self.players.lock().await.get_mut(guild_id) {
+                    player.track_failed(&error);
+                }
+            }
+            VoiceEvent::TrackStuck { threshold } => {
+                if let Some(player) = self.players.lock().await.get(guild_id) {
+                    player.track_stuck(threshold);
+                }
+            }
+        }
+    }
+
+    /// Lets the client know about connection failures it can't see in the response
+    /// to its own request, the same way discord would have reported them
+    fn report_connect_error(&self, guild_id: &str, error: &VoiceError) {
+        if let VoiceError::NoEncryptionMode(_) = error {
+            self.send(ServerPayload::Event(Box::new(Event {
+                guild_id: guild_id.to_owned(),
+                event: EventType::WebSocketClosedEvent(WebSocketClosed {
+                    // discord's close code for an unknown encryption mode
+                    code: 4016,
+                    reason: error.to_string(),
+                    by_remote: false,
+                }),
+            })));
+        }
+    }
+
+    /// Redoes the whole voice handshake for a player whose gateway session is gone,
+    /// keeping its track and position
+    async fn reconnect_player(&self, guild_id: &str) {
+        let Some((voice_update, voice_events_tx)) = self
+            .players
+            .lock()
+            .await
+            .get(guild_id)
+            .map(|player| (player.voice_update(), player.voice_events()))
+        else {
+            return;
+        };
+
+        info!("Reconnecting voice for guild {}", guild_id);
+        let connection_manager = match VoiceManager::new(
+            &self.user_id,
+            voice_update.clone(),
+            &self.voice_config,
+            voice_events_tx,
+        )
+        .await
+        {
+            Ok(connection_manager) => connection_manager,
+            Err(e) => {
+                warn!("Voice reconnect for guild {} failed: {}", guild_id, e);
+                self.report_connect_error(guild_id, &e);
+                return;
+            }
+        };
+
+        self.hand_over(
+            guild_id,
+            &voice_update,
+            voice_update.clone(),
+            connection_manager,
+        )
+        .await;
+    }
+
+    /// Plays `track` on the guild's player. With `no_replace`, nothing happens if a
+    /// track is already playing.
+    pub async fn play(
+        &self,
+        guild_id: &str,
+        track: AudioTrack,
+        start_time: Duration,
+        end_time: Option<Duration>,
+        no_replace: bool,
+    ) -> anyhow::Result<()> {
+        if no_replace && self.track(guild_id).await?.is_some() {
+            info!("Not replacing {}", track.info.title);
+            return Ok(());
+        }
+        // opening a source can take a while, the players aren't locked for it
+        let stream = match self.sources.open_stream(&track).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                if let Some(player) = self.players.lock().await.get(guild_id) {
+                    player.load_failed(&track, &e);
+                }
+                return Err(e.into());
+            }
+        };
+        let mut players = self.players.lock().await;
+        let player = players
+            .get_mut(guild_id)
+            .ok_or_else(|| no_player(guild_id))?;
+        player
+            .play(track, stream, start_time, end_time, no_replace)
+            .await
+    }
+
+    /// Restarts the guild's track from `position`. The demuxers can't seek, so the
+    /// source is reopened and everything before `position` is skipped.
+    pub async fn seek(&self, guild_id: &str, position: Duration) -> anyhow::Result<()> {
+        let Some(track) = self.track(guild_id).await? else {
+            return Ok(());
+        };
+        if !track.info.is_seekable {
+            return Err(anyhow::anyhow!("{} is not seekable", track.info.title));
+        }
+        let stream = self.sources.open_stream(&track).await?;
+        let mut players = self.players.lock().await;
+        let player = players
+            .get_mut(guild_id)
+            .ok_or_else(|| no_player(guild_id))?;
+        // stopped or replaced while the source was opening
+        if player.track().as_ref() != Some(&track) {
+            return Ok(());
+        }
+        player.seek(stream, position).await
+    }
+
+    async fn track(&self, guild_id: &str) -> anyhow::Result<Option<AudioTrack>> {
+        self.players
+            .lock()
+            .await
+            .get(guild_id)
+            .map(Player::track)
+            .ok_or_else(|| no_player(guild_id))
+    }
+
+    pub async fn start_recording(&self, guild_id: &str) -> anyhow::Result<RecordingState> {
+        let mut players = self.players.lock().await;
+        let player = players
+            .get_mut(guild_id)
+            .ok_or_else(|| no_player(guild_id))?;
+        let directory = Path::new(&self.voice_config.recording_directory);
+        let recording = player.start_recording(directory)?;
+        Ok(RecordingState {
+            id: recording.id().to_owned(),
+            started_at: recording.started_at(),
+        })
+    }
+
+    /// Stops the guild's recording and lets the client know which files were
+    /// written. Returns `None` if nothing was being recorded.
+    pub async fn stop_recording(&self, guild_id: &str) -> Option<RecordingFinished> {
+        let recording = self
+            .players
+            .lock()
+            .await
+            .get_mut(guild_id)?
+            .stop_recording()?;
+        let id = recording.id().to_owned();
+        // flushing the files may take a while, the players are not locked for it
+        let files: Vec<String> = recording
+            .finish()
+            .await
+            .into_iter()
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect();
+
+        self.send(ServerPayload::Event(Box::new(Event {
+            guild_id: guild_id.to_owned(),
+            event: EventType::RecordingFinishedEvent(RecordingFinished {
+                id: id.clone(),
+                files: files.clone(),
+            }),
+        })));
+        Some(RecordingFinished { id, files })
+    }
+
+    pub async fn destroy_player(&self, guild_id: &str) -> bool {
+        let Some(mut player) = self.players.lock().await.remove(guild_id) else {
+            return false;
+        };
+        player.clean_up();
+        true
+    }
+
+    pub fn send(&self, payload: ServerPayload) {
+        // the receiver only goes away with the client, at which point nobody is
+        // listening for events anyway
+        _ = self.to_client_tx.send(payload);
+    }
+}
+
+fn no_player(guild_id: &str) -> anyhow::Error {
+    anyhow::anyhow!("no player found for guild {}", guild_id)
+}
+
+/// Every live session on the server, keyed by session id
+#[derive(Debug, Default)]
+pub struct SessionRegistry {
+    sessions: RwLock<HashMap<String, Arc<Session>>>,
+}
+
+impl SessionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&self, session: Arc<Session>) {
+        info!("Session {} registered", session.id());
+        self.sessions
+            .write()
+            .unwrap()
+            .insert(session.id().to_owned(), session);
+    }
+
+    pub fn get(&self, session_id: &str) -> Option<Arc<Session>> {
+        self.sessions.read().unwrap().get(session_id).cloned()
+    }
+
+    pub fn remove(&self, session_id: &str) -> Option<Arc<Session>> {
+        self.sessions.write().unwrap().remove(session_id)
+    }
+
+    /// Attaches to the detached session of `user_id` that `key` refers to, either
+    /// by session id or by v3 resume key
+    pub fn resume(
+        &self,
+        key: &str,
+        user_id: &str,
+    ) -> Option<(Arc<Session>, UnboundedReceiver<ServerPayload>)> {
+        let sessions = self.sessions.read().unwrap();
+        sessions
+            .values()
+            .filter(|session| session.user_id == user_id)
+            .filter(|session| {
+                let config = session.resume_config();
+                config.resuming && (session.id == key || config.key.as_deref() == Some(key))
+            })
+            .find_map(|session| Some((session.clone(), session.attach()?)))
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
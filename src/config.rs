@@ -1,4 +1,4 @@
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
 
 use anyhow::Result;
@@ -6,7 +6,7 @@ use serde::Deserialize;
 use thiserror::Error;
 use tokio::fs;
 
-use crate::server::Server;
+use crate::{crypto::EncryptionMode, server::Server, source::SourceRegistry};
 
 #[derive(Error, Debug)]
 pub enum ConfigError {
@@ -18,19 +18,12 @@ pub enum ConfigError {
     AddressParseError(#[from] std::net::AddrParseError),
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Default)]
 pub struct Configuration {
     pub server: ServerConfiguration,
     pub media: MediaConfiguration,
-}
-
-impl Default for Configuration {
-    fn default() -> Self {
-        Self {
-            server: ServerConfiguration::default(),
-            media: MediaConfiguration::default(),
-        }
-    }
+    #[serde(default)]
+    pub voice: VoiceConfiguration,
 }
 
 impl Configuration {
@@ -42,7 +35,13 @@ impl Configuration {
 
     pub fn compose(self) -> Result<Server, ConfigError> {
         let addr = SocketAddr::from_str(&format!("{}:{}", self.server.address, self.server.port))?;
-        Ok(Server::_new(self.media.server.password, addr))
+        let sources = SourceRegistry::from_config(&self.media);
+        Ok(Server::_new(
+            self.media.server.password,
+            addr,
+            sources,
+            self.voice,
+        ))
     }
 }
 
@@ -61,28 +60,118 @@ impl Default for ServerConfiguration {
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Default)]
 pub struct MediaConfiguration {
     pub server: MediaServerConfiguration,
+    #[serde(default)]
+    pub sources: SourcesConfiguration,
+    #[serde(default)]
+    pub library: LibraryConfiguration,
+}
+
+#[derive(Deserialize)]
+pub struct MediaServerConfiguration {
+    pub password: String,
 }
 
-impl Default for MediaConfiguration {
+impl Default for MediaServerConfiguration {
     fn default() -> Self {
         Self {
-            server: MediaServerConfiguration::default(),
+            password: "youshallnotpass".to_string(),
         }
     }
 }
 
+/// Toggles for the built in source managers
 #[derive(Deserialize)]
-pub struct MediaServerConfiguration {
-    pub password: String,
+#[serde(default)]
+pub struct SourcesConfiguration {
+    pub local: bool,
+    /// Directories local files can be played from, besides the library roots
+    pub local_roots: Vec<String>,
+    pub http: bool,
+    /// Bytes, http files are downloaded in full and larger ones fail to load
+    pub http_max_size: u64,
+    pub stream: bool,
 }
 
-impl Default for MediaServerConfiguration {
+impl Default for SourcesConfiguration {
     fn default() -> Self {
         Self {
-            password: "youshallnotpass".to_string(),
+            local: false,
+            local_roots: Vec::new(),
+            http: true,
+            http_max_size: 100 * 1024 * 1024,
+            stream: true,
+        }
+    }
+}
+
+/// Directories indexed for `localsearch:` identifiers
+#[derive(Deserialize)]
+pub struct LibraryConfiguration {
+    pub roots: Vec<String>,
+    /// Seconds between rescans of the roots, 0 to only scan them on startup
+    pub refresh_interval: u64,
+    /// Where the index is persisted between restarts, if anywhere
+    pub index_path: Option<String>,
+}
+
+impl Default for LibraryConfiguration {
+    fn default() -> Self {
+        Self {
+            roots: Vec::new(),
+            refresh_interval: 3600,
+            index_path: None,
+        }
+    }
+}
+
+/// How players talk to discord's voice servers
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct VoiceConfiguration {
+    /// Voice gateway version, 8 or the older 7
+    pub gateway_version: u8,
+    /// Encryption modes in order of preference, the first one discord offers is used
+    pub encryption_modes: Vec<EncryptionMode>,
+    /// Where recordings of voice channels are written to
+    pub recording_directory: String,
+    /// Frames (20ms each) read ahead from the source before a track starts playing
+    pub prebuffer_frames: usize,
+    /// Send the audio of all players from one dedicated thread instead of a task
+    /// per player, cheaper with many players on small machines
+    pub shared_sender: bool,
+    /// Local address of the udp sockets, any address of the voice server's family
+    /// (IPv4 or IPv6) if unset
+    pub bind_address: Option<IpAddr>,
+    /// Network interface the udp sockets are bound to, linux only
+    pub interface: Option<String>,
+    /// Pause players whose voice channel empties out, and resume them once someone
+    /// joins again
+    pub auto_pause: bool,
+    /// End to end encrypt audio with DAVE in channels that support it, discord
+    /// needs the channel id in voice updates for this. Off by default until
+    /// it's seen more real calls.
+    pub dave: bool,
+    /// Milliseconds a track's source may deliver no audio before the client is
+    /// told it's stuck
+    pub track_stuck_threshold: u64,
+}
+
+impl Default for VoiceConfiguration {
+    fn default() -> Self {
+        Self {
+            gateway_version: 8,
+            encryption_modes: EncryptionMode::ALL.to_vec(),
+            recording_directory: "recordings".to_string(),
+            prebuffer_frames: 5,
+            shared_sender: false,
+            bind_address: None,
+            interface: None,
+            auto_pause: false,
+            dave: false,
+            track_stuck_threshold: 10000,
         }
     }
 }
@@ -1,11 +1,20 @@
+use aes_gcm::Aes256Gcm;
 use anyhow::Result;
 use byteorder::{ByteOrder, NetworkEndian};
+use chacha20poly1305::XChaCha20Poly1305;
 use serde::{Deserialize, Serialize};
 
-use crypto_secretbox::{aead::AeadMut, AeadCore, XSalsa20Poly1305};
+use crypto_secretbox::{
+    aead::{Aead, AeadMut, KeyInit, Payload},
+    AeadCore, XSalsa20Poly1305,
+};
 
 #[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum EncryptionMode {
+    #[serde(rename = "aead_aes256_gcm_rtpsize")]
+    Aes256GcmRtpSize(#[serde(skip)] u32),
+    #[serde(rename = "aead_xchacha20_poly1305_rtpsize")]
+    XChaCha20Poly1305RtpSize(#[serde(skip)] u32),
     #[serde(rename = "xsalsa20_poly1305_lite")]
     XSalsa20Poly1305Lite(#[serde(skip)] u32),
     #[serde(rename = "xsalsa20_poly1305_suffix")]
@@ -14,38 +23,196 @@ pub enum EncryptionMode {
     XSalsa20Poly1305,
 }
 
+/// Cipher keyed with the secret key from SessionDescription, the algorithm depends
+/// on the negotiated [`EncryptionMode`]
+pub enum Cipher {
+    XSalsa20Poly1305(XSalsa20Poly1305),
+    Aes256Gcm(Box<Aes256Gcm>),
+    XChaCha20Poly1305(XChaCha20Poly1305),
+}
+
+impl Cipher {
+    pub fn new(mode: EncryptionMode, secret_key: &[u8]) -> Result<Self> {
+        let cipher = match mode {
+            EncryptionMode::Aes256GcmRtpSize(_) => {
+                Cipher::Aes256Gcm(Box::new(Aes256Gcm::new_from_slice(secret_key)?))
+            }
+            EncryptionMode::XChaCha20Poly1305RtpSize(_) => {
+                Cipher::XChaCha20Poly1305(XChaCha20Poly1305::new_from_slice(secret_key)?)
+            }
+            EncryptionMode::XSalsa20Poly1305Lite(_)
+            | EncryptionMode::XSalsa20Poly1305Suffix
+            | EncryptionMode::XSalsa20Poly1305 => {
+                Cipher::XSalsa20Poly1305(XSalsa20Poly1305::new_from_slice(secret_key)?)
+            }
+        };
+        Ok(cipher)
+    }
+}
+
 impl EncryptionMode {
+    /// Every supported mode, most preferred first
+    pub const ALL: [EncryptionMode; 5] = [
+        EncryptionMode::Aes256GcmRtpSize(0),
+        EncryptionMode::XChaCha20Poly1305RtpSize(0),
+        EncryptionMode::XSalsa20Poly1305Lite(0),
+        EncryptionMode::XSalsa20Poly1305Suffix,
+        EncryptionMode::XSalsa20Poly1305,
+    ];
+
+    /// Picks the first mode of `preferences` that discord offers
+    pub fn negotiate(
+        preferences: &[EncryptionMode],
+        offered: &[EncryptionMode],
+    ) -> Option<EncryptionMode> {
+        preferences.iter().copied().find(|preferred| {
+            offered
+                .iter()
+                .any(|mode| std::mem::discriminant(mode) == std::mem::discriminant(preferred))
+        })
+    }
+
     pub fn encrypt(
         &mut self,
         data: &[u8],
         rtp_header: &[u8],
-        cipher: &mut XSalsa20Poly1305,
+        cipher: &mut Cipher,
     ) -> Result<Vec<u8>> {
-        match self {
-            EncryptionMode::XSalsa20Poly1305Lite(ref mut nonce) => {
+        match (self, cipher) {
+            // the rtpsize modes authenticate the unencrypted header and append the
+            // 4 byte nonce counter, the rest of the nonce is zeroes
+            (EncryptionMode::Aes256GcmRtpSize(ref mut nonce), Cipher::Aes256Gcm(cipher)) => {
+                let mut nonce_buf = [0u8; 12];
+                NetworkEndian::write_u32(&mut nonce_buf[..4], *nonce);
+                *nonce = nonce.wrapping_add(1);
+                let payload = Payload {
+                    msg: data,
+                    aad: rtp_header,
+                };
+                match cipher.encrypt(&nonce_buf.into(), payload) {
+                    Ok(ciphertext) => Ok([rtp_header, &ciphertext, &nonce_buf[..4]].concat()),
+                    Err(e) => Err(anyhow::anyhow!(e)),
+                }
+            }
+            (
+                EncryptionMode::XChaCha20Poly1305RtpSize(ref mut nonce),
+                Cipher::XChaCha20Poly1305(cipher),
+            ) => {
+                let mut nonce_buf = [0u8; 24];
+                NetworkEndian::write_u32(&mut nonce_buf[..4], *nonce);
+                *nonce = nonce.wrapping_add(1);
+                let payload = Payload {
+                    msg: data,
+                    aad: rtp_header,
+                };
+                match cipher.encrypt(&nonce_buf.into(), payload) {
+                    Ok(ciphertext) => Ok([rtp_header, &ciphertext, &nonce_buf[..4]].concat()),
+                    Err(e) => Err(anyhow::anyhow!(e)),
+                }
+            }
+            (
+                EncryptionMode::XSalsa20Poly1305Lite(ref mut nonce),
+                Cipher::XSalsa20Poly1305(cipher),
+            ) => {
                 let mut nonce_buf: [u8; 24] = [0u8; 24];
                 NetworkEndian::write_u32(&mut nonce_buf[..4], *nonce);
                 *nonce += 1u32;
-                match cipher.encrypt(&nonce_buf.into(), data.as_ref()) {
+                match AeadMut::encrypt(cipher, &nonce_buf.into(), data) {
                     Ok(ciphertext) => Ok([rtp_header, &ciphertext, &nonce_buf[..4]].concat()),
-                    Err(e) => return Err(anyhow::anyhow!(e)),
+                    Err(e) => Err(anyhow::anyhow!(e)),
                 }
             }
-            EncryptionMode::XSalsa20Poly1305Suffix => {
+            (EncryptionMode::XSalsa20Poly1305Suffix, Cipher::XSalsa20Poly1305(cipher)) => {
                 let nonce = XSalsa20Poly1305::generate_nonce(&mut rand::thread_rng());
-                match cipher.encrypt(&nonce, data.as_ref()) {
+                match AeadMut::encrypt(cipher, &nonce, data) {
                     Ok(ciphertext) => Ok([rtp_header, &ciphertext, nonce.as_ref()].concat()),
-                    Err(e) => return Err(anyhow::anyhow!(e)),
+                    Err(e) => Err(anyhow::anyhow!(e)),
                 }
             }
-            EncryptionMode::XSalsa20Poly1305 => {
+            (EncryptionMode::XSalsa20Poly1305, Cipher::XSalsa20Poly1305(cipher)) => {
+                // the header is the nonce, rtcp headers are only 8 bytes long
+                let header = &rtp_header[..rtp_header.len().min(12)];
                 let mut nonce_buf = [0u8; 24];
-                nonce_buf[..12].copy_from_slice(rtp_header);
-                match cipher.encrypt(&nonce_buf.into(), data.as_ref()) {
+                nonce_buf[..header.len()].copy_from_slice(header);
+                match AeadMut::encrypt(cipher, &nonce_buf.into(), data) {
                     Ok(ciphertext) => Ok([rtp_header, &ciphertext].concat()),
                     Err(e) => Err(anyhow::anyhow!(e)),
                 }
             }
+            (mode, _) => Err(anyhow::anyhow!(
+                "cipher does not match encryption mode {:?}",
+                mode
+            )),
+        }
+    }
+
+    /// Whether the rtpsize layout is used, which leaves the header extension's
+    /// 4 byte preamble unencrypted and authenticates it with the rest of the header
+    pub fn is_rtpsize(&self) -> bool {
+        matches!(
+            self,
+            EncryptionMode::Aes256GcmRtpSize(_) | EncryptionMode::XChaCha20Poly1305RtpSize(_)
+        )
+    }
+
+    /// Decrypts an incoming packet whose first `header_len` bytes are unencrypted.
+    /// The returned payload still starts with the header extension, if any.
+    pub fn decrypt(&self, packet: &[u8], header_len: usize, cipher: &Cipher) -> Result<Vec<u8>> {
+        let nonce_len = match self {
+            EncryptionMode::Aes256GcmRtpSize(_)
+            | EncryptionMode::XChaCha20Poly1305RtpSize(_)
+            | EncryptionMode::XSalsa20Poly1305Lite(_) => 4,
+            EncryptionMode::XSalsa20Poly1305Suffix => 24,
+            EncryptionMode::XSalsa20Poly1305 => 0,
+        };
+        if packet.len() < header_len + nonce_len {
+            return Err(anyhow::anyhow!("packet too short"));
         }
+        let (rest, nonce) = packet.split_at(packet.len() - nonce_len);
+        let (header, ciphertext) = rest.split_at(header_len);
+
+        let plaintext = match (self, cipher) {
+            (EncryptionMode::Aes256GcmRtpSize(_), Cipher::Aes256Gcm(cipher)) => {
+                let mut nonce_buf = [0u8; 12];
+                nonce_buf[..4].copy_from_slice(nonce);
+                let payload = Payload {
+                    msg: ciphertext,
+                    aad: header,
+                };
+                cipher.decrypt(&nonce_buf.into(), payload)
+            }
+            (EncryptionMode::XChaCha20Poly1305RtpSize(_), Cipher::XChaCha20Poly1305(cipher)) => {
+                let mut nonce_buf = [0u8; 24];
+                nonce_buf[..4].copy_from_slice(nonce);
+                let payload = Payload {
+                    msg: ciphertext,
+                    aad: header,
+                };
+                cipher.decrypt(&nonce_buf.into(), payload)
+            }
+            (EncryptionMode::XSalsa20Poly1305Lite(_), Cipher::XSalsa20Poly1305(cipher)) => {
+                let mut nonce_buf = [0u8; 24];
+                nonce_buf[..4].copy_from_slice(nonce);
+                Aead::decrypt(cipher, &nonce_buf.into(), ciphertext)
+            }
+            (EncryptionMode::XSalsa20Poly1305Suffix, Cipher::XSalsa20Poly1305(cipher)) => {
+                let mut nonce_buf = [0u8; 24];
+                nonce_buf.copy_from_slice(nonce);
+                Aead::decrypt(cipher, &nonce_buf.into(), ciphertext)
+            }
+            (EncryptionMode::XSalsa20Poly1305, Cipher::XSalsa20Poly1305(cipher)) => {
+                let mut nonce_buf = [0u8; 24];
+                let header = &header[..header.len().min(12)];
+                nonce_buf[..header.len()].copy_from_slice(header);
+                Aead::decrypt(cipher, &nonce_buf.into(), ciphertext)
+            }
+            (mode, _) => {
+                return Err(anyhow::anyhow!(
+                    "cipher does not match encryption mode {:?}",
+                    mode
+                ))
+            }
+        };
+        plaintext.map_err(|e| anyhow::anyhow!(e))
     }
 }
@@ -3,6 +3,8 @@ pub mod config;
 pub mod crypto;
 pub mod voice;
 pub mod opus_parse;
+pub mod opus_write;
 pub mod server;
+pub mod source;
 pub mod utils;
 pub mod webm_parse;
@@ -1,4 +1,5 @@
 use std::{
+    io::{self, ErrorKind},
     pin::Pin,
     task::{Context, Poll},
     vec,
@@ -7,7 +8,7 @@ use std::{
 use packed_struct::prelude::*;
 use tokio::io::{AsyncRead, ReadBuf};
 use tokio_stream::Stream;
-use tracing::{error, info, trace, warn};
+use tracing::trace;
 
 #[derive(PackedStruct, Debug, Copy, Clone)]
 #[packed_struct(endian = "lsb", bit_numbering = "msb0", size_bytes = "27")]
@@ -37,6 +38,8 @@ pub struct OggPageHeader {
     segnum: u8,
 }
 
+/// Yields the opus packets of an Ogg stream. It can't go on after an error,
+/// malformed pages included.
 pub struct OggStream<T: AsyncRead + Unpin> {
     stream: T,
     segment_table: Option<Vec<u8>>,
@@ -55,9 +58,9 @@ enum ReadMode {
 }
 
 impl<T: AsyncRead + Unpin> Stream for OggStream<T> {
-    type Item = Vec<u8>;
+    type Item = io::Result<Vec<u8>>;
 
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Vec<u8>>> {
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         match self.read_mode {
             ReadMode::Header => {
                 let mut buf = self.buf.take().unwrap_or(vec![0u8; 27]);
@@ -70,16 +73,28 @@ impl<T: AsyncRead + Unpin> Stream for OggStream<T> {
                 match Pin::new(&mut self.stream).poll_read(cx, &mut read_buf) {
                     Poll::Ready(Ok(())) => {
                         // detect if buffer is unchanged
-                        if read_buf.filled().len() == 0 {
+                        if read_buf.filled().is_empty() {
                             return Poll::Ready(None);
                         }
                         if read_buf.filled().len() < read_buf.capacity() {
+                            // short read, keep reading instead of waiting for a wakeup
+                            // that will never come
+                            if read_buf.filled().len() == self.cursor {
+                                return Poll::Ready(None);
+                            }
                             self.cursor = read_buf.filled().len();
                             self.buf = Some(buf);
-                            return Poll::Pending;
+                            return self.poll_next(cx);
                         }
-                        let header = OggPageHeader::unpack(&buf.try_into().unwrap())
-                            .expect("Invalid header");
+                        let header = match OggPageHeader::unpack(&buf.try_into().unwrap()) {
+                            Ok(header) if header.capture_pattern == *b"OggS" => header,
+                            _ => {
+                                return Poll::Ready(Some(Err(io::Error::new(
+                                    ErrorKind::InvalidData,
+                                    "invalid ogg page header",
+                                ))))
+                            }
+                        };
                         trace!("{:?}", &header);
                         self.current_page_header = Some(header);
                         self.read_mode = ReadMode::Segtable;
@@ -87,11 +102,11 @@ impl<T: AsyncRead + Unpin> Stream for OggStream<T> {
                         self.cursor = 0;
                         self.poll_next(cx)
                     }
-                    Poll::Ready(Err(e)) => {
-                        error!("error reading header: {}", e);
-                        Poll::Ready(None)
+                    Poll::Ready(Err(e)) => Poll::Ready(Some(Err(e))),
+                    Poll::Pending => {
+                        self.buf = Some(buf);
+                        Poll::Pending
                     }
-                    Poll::Pending => Poll::Pending,
                 }
             }
             ReadMode::Segtable => {
@@ -103,12 +118,16 @@ impl<T: AsyncRead + Unpin> Stream for OggStream<T> {
                         as usize
                 ]);
                 let mut read_buf = ReadBuf::new(&mut buf);
+                read_buf.advance(self.cursor);
                 match Pin::new(&mut self.stream).poll_read(cx, &mut read_buf) {
                     Poll::Ready(Ok(())) => {
                         if read_buf.filled().len() < read_buf.capacity() {
+                            if read_buf.filled().len() == self.cursor {
+                                return Poll::Ready(None);
+                            }
                             self.cursor = read_buf.filled().len();
                             self.buf = Some(buf);
-                            return Poll::Pending;
+                            return self.poll_next(cx);
                         }
                         self.segment_table = Some(buf);
                         self.read_mode = ReadMode::Packet;
@@ -116,11 +135,11 @@ impl<T: AsyncRead + Unpin> Stream for OggStream<T> {
                         self.cursor = 0;
                         self.poll_next(cx)
                     }
-                    Poll::Ready(Err(e)) => {
-                        error!("error reading segtable: {}", e);
-                        Poll::Ready(None)
+                    Poll::Ready(Err(e)) => Poll::Ready(Some(Err(e))),
+                    Poll::Pending => {
+                        self.buf = Some(buf);
+                        Poll::Pending
                     }
-                    Poll::Pending => Poll::Pending,
                 }
             }
             ReadMode::Packet => match self
@@ -141,15 +160,17 @@ impl<T: AsyncRead + Unpin> Stream for OggStream<T> {
                     match Pin::new(&mut self.stream).poll_read(cx, &mut read_buf) {
                         Poll::Ready(Ok(())) => {
                             if read_buf.filled().len() < read_buf.capacity() {
-                                info!("Had to read more than once");
-                                warn!(
-                                    "2: filled portion: {:?}, capacity: {:?}",
-                                    read_buf.filled(),
+                                trace!(
+                                    "short packet read, filled: {}, capacity: {}",
+                                    read_buf.filled().len(),
                                     read_buf.capacity()
                                 );
+                                if read_buf.filled().len() == self.cursor {
+                                    return Poll::Ready(None);
+                                }
                                 self.cursor = read_buf.filled().len();
                                 self.buf = Some(buf);
-                                return Poll::Pending;
+                                return self.poll_next(cx);
                             }
 
                             self.seg_idx += 1;
@@ -160,12 +181,9 @@ impl<T: AsyncRead + Unpin> Stream for OggStream<T> {
                                 return self.poll_next(cx);
                             }
                             self.cursor = 0;
-                            Poll::Ready(Some(buf))
-                        }
-                        Poll::Ready(Err(e)) => {
-                            error!("error reading packet: {}", e);
-                            Poll::Ready(None)
+                            Poll::Ready(Some(Ok(buf)))
                         }
+                        Poll::Ready(Err(e)) => Poll::Ready(Some(Err(e))),
                         Poll::Pending => {
                             self.buf = Some(buf);
                             Poll::Pending
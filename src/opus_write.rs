@@ -0,0 +1,150 @@
+use byteorder::{ByteOrder, LittleEndian};
+
+const CAPTURE_PATTERN: &[u8; 4] = b"OggS";
+const FLAG_BEGINNING_OF_STREAM: u8 = 0x02;
+const FLAG_END_OF_STREAM: u8 = 0x04;
+// a page holds at most 255 lacing segments
+const MAX_SEGMENTS_PER_PAGE: usize = 255;
+// keeps pages around a second long for 20ms frames
+const MAX_PACKETS_PER_PAGE: usize = 50;
+
+/// Packs opus packets into the pages of a single logical Ogg bitstream, see
+/// RFC 7845. Pages come out as bytes so several streams can be interleaved into
+/// one file.
+#[derive(Debug)]
+pub struct OggOpusWriter {
+    serial: u32,
+    page_sequence: u32,
+    granule_position: u64,
+    pending: Vec<Vec<u8>>,
+    pending_segments: usize,
+}
+
+impl OggOpusWriter {
+    pub fn new(serial: u32) -> Self {
+        Self {
+            serial,
+            page_sequence: 0,
+            granule_position: 0,
+            pending: Vec::new(),
+            pending_segments: 0,
+        }
+    }
+
+    /// The OpusHead and OpusTags pages every stream has to start with
+    pub fn headers(&mut self, channels: u8) -> Vec<u8> {
+        let mut head = Vec::with_capacity(19);
+        head.extend_from_slice(b"OpusHead");
+        head.push(1); // version
+        head.push(channels);
+        head.extend_from_slice(&0u16.to_le_bytes()); // pre-skip
+        head.extend_from_slice(&48000u32.to_le_bytes()); // input sample rate
+        head.extend_from_slice(&0i16.to_le_bytes()); // output gain
+        head.push(0); // channel mapping family
+
+        let vendor = concat!("jukebox ", env!("CARGO_PKG_VERSION"));
+        let mut tags = Vec::new();
+        tags.extend_from_slice(b"OpusTags");
+        tags.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+        tags.extend_from_slice(vendor.as_bytes());
+        tags.extend_from_slice(&0u32.to_le_bytes()); // no user comments
+
+        let mut pages = self.page(&[head], 0, FLAG_BEGINNING_OF_STREAM);
+        pages.extend(self.page(&[tags], 0, 0));
+        pages
+    }
+
+    /// Queues a packet covering `samples` samples at 48kHz. Returns a page once
+    /// enough packets have been queued to fill one.
+    pub fn push(&mut self, packet: Vec<u8>, samples: u64) -> Option<Vec<u8>> {
+        // big packets take more segments, the page goes out before they'd overflow it
+        let segments = lacing_segments(&packet);
+        let full = (self.pending_segments + segments > MAX_SEGMENTS_PER_PAGE
+            && !self.pending.is_empty())
+        .then(|| self.flush(0));
+
+        self.granule_position += samples;
+        self.pending.push(packet);
+        self.pending_segments += segments;
+        full.or_else(|| (self.pending.len() >= MAX_PACKETS_PER_PAGE).then(|| self.flush(0)))
+    }
+
+    /// Writes out whatever is queued along with the end of stream flag
+    pub fn finish(&mut self) -> Vec<u8> {
+        self.flush(FLAG_END_OF_STREAM)
+    }
+
+    fn flush(&mut self, flags: u8) -> Vec<u8> {
+        let packets = std::mem::take(&mut self.pending);
+        self.pending_segments = 0;
+        self.page(&packets, self.granule_position, flags)
+    }
+
+    fn page(&mut self, packets: &[Vec<u8>], granule_position: u64, flags: u8) -> Vec<u8> {
+        // lacing: every packet takes len / 255 segments of 255 plus one shorter one,
+        // which is 0 if the length is a multiple of 255
+        let segments: Vec<u8> = packets
+            .iter()
+            .flat_map(|packet| {
+                std::iter::repeat_n(255, packet.len() / 255)
+                    .chain(std::iter::once((packet.len() % 255) as u8))
+            })
+            .collect();
+        debug_assert!(
+            segments.len() <= MAX_SEGMENTS_PER_PAGE,
+            "too many segments for one page"
+        );
+
+        let mut page =
+            Vec::with_capacity(27 + segments.len() + packets.iter().map(Vec::len).sum::<usize>());
+        page.extend_from_slice(CAPTURE_PATTERN);
+        page.push(0); // version
+        page.push(flags);
+        page.extend_from_slice(&granule_position.to_le_bytes());
+        page.extend_from_slice(&self.serial.to_le_bytes());
+        page.extend_from_slice(&self.page_sequence.to_le_bytes());
+        page.extend_from_slice(&[0; 4]); // crc, filled in below
+        page.push(segments.len() as u8);
+        page.extend_from_slice(&segments);
+        for packet in packets {
+            page.extend_from_slice(packet);
+        }
+
+        let crc = crc32(&page);
+        LittleEndian::write_u32(&mut page[22..26], crc);
+        self.page_sequence += 1;
+        page
+    }
+}
+
+// len / 255 segments of 255 and the shorter one at the end
+fn lacing_segments(packet: &[u8]) -> usize {
+    packet.len() / 255 + 1
+}
+
+/// The CRC Ogg uses: polynomial 0x04c11db7, no reflection, initial value 0
+fn crc32(data: &[u8]) -> u32 {
+    data.iter().fold(0u32, |crc, &byte| {
+        (crc << 8) ^ CRC_TABLE[((crc >> 24) as u8 ^ byte) as usize]
+    })
+}
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u32) << 24;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
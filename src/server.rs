@@ -1,5 +1,13 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, sync::Arc};
 
+use crate::{
+    client::session::SessionRegistry,
+    config::VoiceConfiguration,
+    source::{AudioSourceManager, SourceRegistry},
+};
+
+pub mod info;
+pub mod payloads;
 mod routes;
 
 #[derive(Debug, Clone)]
@@ -7,14 +15,22 @@ pub struct Headers {
     pub client_addr: SocketAddr,
     pub user_id: String,
     pub client_name: String,
+    /// `Resume-Key` for v3 clients, `Session-Id` for v4 clients
+    pub resume_key: Option<String>,
 }
 
 impl Headers {
-    pub fn new(client_addr: SocketAddr, user_id: &str, client_name: &str) -> Self {
+    pub fn new(
+        client_addr: SocketAddr,
+        user_id: &str,
+        client_name: &str,
+        resume_key: Option<&str>,
+    ) -> Self {
         Self {
             client_addr,
             user_id: user_id.to_string(),
             client_name: client_name.to_string(),
+            resume_key: resume_key.map(str::to_string),
         }
     }
 }
@@ -23,16 +39,45 @@ impl Headers {
 pub struct Server {
     password: String,
     address: SocketAddr,
+    sources: SourceRegistry,
+    voice: VoiceConfiguration,
 }
 
 impl Server {
-    pub fn _new(password: String, address: SocketAddr) -> Self {
-        Self { password, address }
+    pub fn _new(
+        password: String,
+        address: SocketAddr,
+        sources: SourceRegistry,
+        voice: VoiceConfiguration,
+    ) -> Self {
+        Self {
+            password,
+            address,
+            sources,
+            voice,
+        }
+    }
+
+    /// Registers a source manager after the ones enabled in the configuration, for
+    /// sources that don't ship with jukebox
+    pub fn register_source(&mut self, manager: impl AudioSourceManager + 'static) {
+        self.sources.register(manager);
     }
 
     pub async fn run(self) -> Result<(), std::io::Error> {
-        let app = routes::app(self.password);
         let listener = tokio::net::TcpListener::bind(self.address).await?;
+        self.serve(listener).await
+    }
+
+    /// Like [`Server::run`], on a listener that is already bound. The configured
+    /// address is ignored, which lets tests listen on an ephemeral port.
+    pub async fn serve(self, listener: tokio::net::TcpListener) -> Result<(), std::io::Error> {
+        let state = routes::AppState {
+            sources: Arc::new(self.sources),
+            sessions: Arc::new(SessionRegistry::new()),
+            voice: Arc::new(self.voice),
+        };
+        let app = routes::app(self.password, state);
         axum::serve(
             listener,
             app.into_make_service_with_connect_info::<SocketAddr>(),
@@ -0,0 +1,63 @@
+use crate::{client::payloads::SUPPORTED_FILTERS, source::SourceRegistry};
+
+use super::payloads::v4::{Git, Info, Plugin, Version};
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const GIT_COMMIT: &str = env!("JUKEBOX_GIT_COMMIT");
+pub const GIT_BRANCH: &str = env!("JUKEBOX_GIT_BRANCH");
+pub const RUSTC_VERSION: &str = env!("JUKEBOX_RUSTC_VERSION");
+
+// optional parts of jukebox, reported as plugins since lavalink has no
+// better place for them
+const PLUGINS: &[&str] = &[
+    #[cfg(feature = "dave")]
+    "dave",
+];
+
+pub fn build_time() -> u64 {
+    env!("JUKEBOX_BUILD_TIME").parse().unwrap_or(0)
+}
+
+pub fn commit_time() -> u64 {
+    env!("JUKEBOX_GIT_COMMIT_TIME").parse().unwrap_or(0)
+}
+
+/// Describes this build and what it can do, for clients deciding which features
+/// to use
+pub fn info(sources: &SourceRegistry) -> Info {
+    let (version, pre_release) = VERSION
+        .split_once('-')
+        .map_or((VERSION, None), |(version, pre)| {
+            (version, Some(pre.to_owned()))
+        });
+    let mut parts = version.split('.').map(|part| part.parse().unwrap_or(0));
+
+    Info {
+        version: Version {
+            semver: VERSION.to_owned(),
+            major: parts.next().unwrap_or(0),
+            minor: parts.next().unwrap_or(0),
+            patch: parts.next().unwrap_or(0),
+            pre_release,
+            build: None,
+        },
+        build_time: build_time(),
+        git: Git {
+            branch: GIT_BRANCH.to_owned(),
+            commit: GIT_COMMIT.to_owned(),
+            commit_time: commit_time(),
+        },
+        // there is no jvm or lavaplayer, report what stands in for them instead
+        jvm: RUSTC_VERSION.to_owned(),
+        lavaplayer: format!("jukebox {}", VERSION),
+        source_managers: sources.managers().map(|m| m.name().to_owned()).collect(),
+        filters: SUPPORTED_FILTERS.iter().map(|f| f.to_string()).collect(),
+        plugins: PLUGINS
+            .iter()
+            .map(|name| Plugin {
+                name: name.to_string(),
+                version: VERSION.to_owned(),
+            })
+            .collect(),
+    }
+}
@@ -0,0 +1,108 @@
+pub mod v4;
+
+use serde::Serialize;
+
+use crate::source::{AudioTrack, LoadResult, TrackInfo};
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum LoadType {
+    TrackLoaded,
+    PlaylistLoaded,
+    SearchResult,
+    NoMatches,
+    LoadFailed,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct LoadTracksResponse {
+    pub load_type: LoadType,
+    pub playlist_info: PlaylistInfo,
+    pub tracks: Vec<Track>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exception: Option<LoadException>,
+}
+
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct PlaylistInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub selected_track: Option<i64>,
+}
+
+// v3 clients read the encoded track from `track`, newer ones from `encoded`
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Track {
+    pub encoded: String,
+    pub track: String,
+    pub info: TrackInfo,
+}
+
+impl From<AudioTrack> for Track {
+    fn from(value: AudioTrack) -> Self {
+        Self {
+            track: value.encoded.clone(),
+            encoded: value.encoded,
+            info: value.info,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct LoadException {
+    pub message: String,
+    pub severity: &'static str,
+}
+
+impl From<LoadResult> for LoadTracksResponse {
+    fn from(value: LoadResult) -> Self {
+        let (load_type, playlist_info, tracks, exception) = match value {
+            LoadResult::Track(track) => (
+                LoadType::TrackLoaded,
+                PlaylistInfo::default(),
+                vec![track],
+                None,
+            ),
+            LoadResult::Playlist {
+                name,
+                selected_track,
+                tracks,
+            } => (
+                LoadType::PlaylistLoaded,
+                PlaylistInfo {
+                    name: Some(name),
+                    selected_track: Some(selected_track.map_or(-1, |i| i as i64)),
+                },
+                tracks,
+                None,
+            ),
+            LoadResult::Search(tracks) => (
+                LoadType::SearchResult,
+                PlaylistInfo::default(),
+                tracks,
+                None,
+            ),
+            LoadResult::Empty => (LoadType::NoMatches, PlaylistInfo::default(), vec![], None),
+            LoadResult::Error(e) => (
+                LoadType::LoadFailed,
+                PlaylistInfo::default(),
+                vec![],
+                Some(LoadException {
+                    message: e.to_string(),
+                    severity: "COMMON",
+                }),
+            ),
+        };
+        Self {
+            load_type,
+            playlist_info,
+            tracks: tracks.into_iter().map(Track::from).collect(),
+            exception,
+        }
+    }
+}
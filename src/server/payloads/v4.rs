@@ -0,0 +1,276 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    client::{
+        payloads::{Filters, PlayerState, SpeakingFlags},
+        player::Player as ClientPlayer,
+        session::{ResumeConfig, Session as ClientSession},
+    },
+    source::{self, AudioTrack, TrackInfo},
+    voice,
+};
+
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Track {
+    pub encoded: String,
+    pub info: TrackInfo,
+    pub plugin_info: Value,
+    pub user_data: Value,
+}
+
+impl From<AudioTrack> for Track {
+    fn from(value: AudioTrack) -> Self {
+        Self {
+            encoded: value.encoded,
+            info: value.info,
+            plugin_info: Value::Object(Default::default()),
+            user_data: Value::Object(Default::default()),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase", tag = "loadType", content = "data")]
+pub enum LoadResult {
+    Track(Track),
+    Playlist(Playlist),
+    Search(Vec<Track>),
+    Empty {},
+    Error(Exception),
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Playlist {
+    pub info: PlaylistInfo,
+    pub plugin_info: Value,
+    pub tracks: Vec<Track>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PlaylistInfo {
+    pub name: String,
+    pub selected_track: i64,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Exception {
+    pub message: String,
+    pub severity: &'static str,
+    pub cause: String,
+}
+
+impl From<source::LoadResult> for LoadResult {
+    fn from(value: source::LoadResult) -> Self {
+        match value {
+            source::LoadResult::Track(track) => LoadResult::Track(track.into()),
+            source::LoadResult::Playlist {
+                name,
+                selected_track,
+                tracks,
+            } => LoadResult::Playlist(Playlist {
+                info: PlaylistInfo {
+                    name,
+                    selected_track: selected_track.map_or(-1, |i| i as i64),
+                },
+                plugin_info: Value::Object(Default::default()),
+                tracks: tracks.into_iter().map(Track::from).collect(),
+            }),
+            source::LoadResult::Search(tracks) => {
+                LoadResult::Search(tracks.into_iter().map(Track::from).collect())
+            }
+            source::LoadResult::Empty => LoadResult::Empty {},
+            source::LoadResult::Error(e) => LoadResult::Error(Exception {
+                message: e.to_string(),
+                severity: "common",
+                cause: format!("{:?}", e),
+            }),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Player {
+    pub guild_id: String,
+    pub track: Option<Track>,
+    pub volume: i16,
+    pub paused: bool,
+    pub state: PlayerState,
+    pub voice: VoiceState,
+    pub filters: Filters,
+    pub speaking: SpeakingFlags,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct VoiceState {
+    pub token: String,
+    pub endpoint: String,
+    pub session_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel_id: Option<String>,
+}
+
+impl From<&ClientPlayer> for Player {
+    fn from(player: &ClientPlayer) -> Self {
+        let mut track = player.track().map(Track::from);
+        if let Some(track) = &mut track {
+            track.info.position = player.position().as_millis() as u64;
+        }
+        Self {
+            guild_id: player.guild_id(),
+            track,
+            volume: player.volume(),
+            paused: player.paused(),
+            state: player.state(),
+            voice: VoiceState {
+                token: player.token(),
+                endpoint: player.endpoint(),
+                session_id: player.session_id(),
+                channel_id: player.channel_id(),
+            },
+            filters: player.filters().clone(),
+            speaking: player.speaking_flags(),
+        }
+    }
+}
+
+/// Someone else in a player's voice channel
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ChannelUser {
+    pub user_id: String,
+    pub flags: Option<u32>,
+    pub platform: Option<u8>,
+}
+
+impl From<voice::ChannelUser> for ChannelUser {
+    fn from(user: voice::ChannelUser) -> Self {
+        Self {
+            user_id: user.user_id,
+            flags: user.flags,
+            platform: user.platform,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdatePlayer {
+    pub track: Option<UpdatePlayerTrack>,
+    /// Deprecated in favour of `track.encoded`, `null` stops the player
+    #[serde(default, with = "serde_with::rust::double_option")]
+    pub encoded_track: Option<Option<String>>,
+    /// Deprecated in favour of `track.identifier`
+    pub identifier: Option<String>,
+    pub position: Option<u64>,
+    #[serde(default, with = "serde_with::rust::double_option")]
+    pub end_time: Option<Option<u64>>,
+    pub volume: Option<i16>,
+    pub paused: Option<bool>,
+    pub filters: Option<Filters>,
+    pub voice: Option<VoiceState>,
+    pub speaking: Option<SpeakingFlags>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdatePlayerTrack {
+    #[serde(default, with = "serde_with::rust::double_option")]
+    pub encoded: Option<Option<String>>,
+    pub identifier: Option<String>,
+    pub user_data: Option<Value>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateSession {
+    pub resuming: Option<bool>,
+    /// Seconds
+    pub timeout: Option<u64>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Session {
+    pub resuming: bool,
+    pub timeout: u64,
+}
+
+impl From<ResumeConfig> for Session {
+    fn from(config: ResumeConfig) -> Self {
+        Self {
+            resuming: config.resuming,
+            timeout: config.timeout.as_secs(),
+        }
+    }
+}
+
+impl From<&ClientSession> for Session {
+    fn from(session: &ClientSession) -> Self {
+        session.resume_config().into()
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Error {
+    pub timestamp: u64,
+    pub status: u16,
+    pub error: String,
+    pub message: String,
+    pub path: String,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Info {
+    pub version: Version,
+    pub build_time: u64,
+    pub git: Git,
+    pub jvm: String,
+    pub lavaplayer: String,
+    pub source_managers: Vec<String>,
+    pub filters: Vec<String>,
+    pub plugins: Vec<Plugin>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Version {
+    pub semver: String,
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+    pub pre_release: Option<String>,
+    pub build: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Git {
+    pub branch: String,
+    pub commit: String,
+    pub commit_time: u64,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Plugin {
+    pub name: String,
+    pub version: String,
+}
@@ -1,3 +1,5 @@
+mod v4;
+
 use std::{net::SocketAddr, sync::Arc};
 
 use axum::{
@@ -6,29 +8,51 @@ use axum::{
     middleware::{self, Next},
     response::Response,
     routing::get,
-    Extension, Router,
+    Extension, Json, Router,
 };
 use serde::Deserialize;
 use tower::ServiceBuilder;
 use tower_http::trace::TraceLayer;
 use tracing::info;
 
-use crate::client::Client;
+use crate::{
+    client::{session::SessionRegistry, Client, ProtocolVersion},
+    config::VoiceConfiguration,
+    source::SourceRegistry,
+};
 
-use super::Headers;
+use super::{
+    info::VERSION,
+    payloads::{LoadTracksResponse, Track},
+    Headers,
+};
+
+/// Everything the handlers share across requests
+#[derive(Clone)]
+pub struct AppState {
+    pub sources: Arc<SourceRegistry>,
+    pub sessions: Arc<SessionRegistry>,
+    pub voice: Arc<VoiceConfiguration>,
+}
 
-pub fn app(password: impl Into<Arc<String>>) -> Router {
+pub fn app(password: impl Into<Arc<String>>, state: AppState) -> Router {
     let password = password.into();
 
     Router::new()
         .route("/loadtracks", get(loadtracks_handler))
         .route("/decodetrack", get(decodetracks_handler))
-        .route("/", get(ws_handler))
+        .route("/version", get(version_handler))
+        .route(
+            "/",
+            get(ws_handler).route_layer(middleware::from_fn(with_headers)),
+        )
+        .nest("/v4", v4::router())
         .layer(
             ServiceBuilder::new()
-                .layer(middleware::from_fn_with_state(password, with_headers))
+                .layer(middleware::from_fn_with_state(password, with_auth))
                 .layer(TraceLayer::new_for_http()),
         )
+        .with_state(state)
 }
 
 fn get_header<'a>(req: &'a Request, header_name: &str) -> Result<&'a str, StatusCode> {
@@ -38,10 +62,9 @@ fn get_header<'a>(req: &'a Request, header_name: &str) -> Result<&'a str, Status
         .ok_or(StatusCode::BAD_REQUEST)
 }
 
-async fn with_headers(
+async fn with_auth(
     extract::State(password): State<Arc<String>>,
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
-    mut req: Request,
+    req: Request,
     next: Next,
 ) -> Result<Response, StatusCode> {
     let auth_token = get_header(&req, "Authorization")?;
@@ -50,42 +73,85 @@ async fn with_headers(
         return Err(StatusCode::UNAUTHORIZED);
     }
 
+    Ok(next.run(req).await)
+}
+
+// only the websocket needs to know who the client is, REST requests just authorize
+async fn with_headers(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    mut req: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
     let user_id = get_header(&req, "User-Id")?;
     let client_name = get_header(&req, "Client-Name")?;
+    let resume_key = get_header(&req, "Resume-Key")
+        .or_else(|_| get_header(&req, "Session-Id"))
+        .ok();
 
-    let final_headers = Headers::new(addr, user_id, client_name);
+    let final_headers = Headers::new(addr, user_id, client_name, resume_key);
 
     req.extensions_mut().insert(final_headers);
 
     Ok(next.run(req).await)
 }
 
+async fn version_handler() -> &'static str {
+    VERSION
+}
+
 #[derive(Deserialize)]
 struct LoadTracksParams {
     identifier: String,
 }
 
-async fn loadtracks_handler(Query(_): Query<LoadTracksParams>) -> String {
-    todo!();
+async fn loadtracks_handler(
+    State(state): State<AppState>,
+    Query(params): Query<LoadTracksParams>,
+) -> Json<LoadTracksResponse> {
+    Json(state.sources.load_item(&params.identifier).await.into())
 }
 
 #[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
 struct DecodeTrackParams {
+    #[serde(alias = "track")]
     encoded_track: String,
 }
 
-async fn decodetracks_handler(Query(_): Query<DecodeTrackParams>) -> String {
-    todo!();
+async fn decodetracks_handler(
+    State(state): State<AppState>,
+    Query(params): Query<DecodeTrackParams>,
+) -> Result<Json<Track>, StatusCode> {
+    match state.sources.decode_track(&params.encoded_track) {
+        Ok(track) => Ok(Json(track.into())),
+        Err(_) => Err(StatusCode::BAD_REQUEST),
+    }
 }
 
-async fn ws_handler(ws: WebSocketUpgrade, Extension(headers): Extension<Headers>) -> Response {
-    ws.on_upgrade(move |socket| spawn_client_session(headers, socket))
+async fn ws_handler(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    Extension(headers): Extension<Headers>,
+) -> Response {
+    ws.on_upgrade(move |socket| spawn_client_session(headers, ProtocolVersion::V3, state, socket))
 }
 
-#[tracing::instrument(skip(websocket))]
-async fn spawn_client_session(headers: Headers, websocket: axum::extract::ws::WebSocket) {
+#[tracing::instrument(skip(state, websocket))]
+async fn spawn_client_session(
+    headers: Headers,
+    version: ProtocolVersion,
+    state: AppState,
+    websocket: axum::extract::ws::WebSocket,
+) {
     info!("Connection with {} established", headers.user_id);
-    let mut client = Client::new(headers, websocket);
+    let mut client = Client::new(
+        headers,
+        version,
+        state.sources,
+        state.voice,
+        state.sessions,
+        websocket,
+    );
     client.listen().await;
     info!("Connection with {} closed", client.user_id());
 }
@@ -0,0 +1,389 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    extract::{OriginalUri, Path, Query, State, WebSocketUpgrade},
+    http::{StatusCode, Uri},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{get, patch, post},
+    Extension, Json, Router,
+};
+use serde::Deserialize;
+
+use crate::{
+    client::{
+        payloads::{RecordingFinished, RecordingState, VoiceUpdate, VoiceUpdateEvent},
+        session::{ResumeConfig, Session},
+        ProtocolVersion,
+    },
+    server::{
+        info::info,
+        payloads::v4::{self, now_millis, UpdatePlayer, UpdateSession},
+    },
+    source::{AudioTrack, LoadResult},
+};
+
+use super::{spawn_client_session, with_headers, AppState, Headers};
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route(
+            "/websocket",
+            get(ws_handler).route_layer(middleware::from_fn(with_headers)),
+        )
+        .route("/info", get(info_handler))
+        .route("/loadtracks", get(loadtracks_handler))
+        .route("/decodetrack", get(decodetrack_handler))
+        .route("/decodetracks", post(decodetracks_handler))
+        .route("/sessions/{session_id}", patch(update_session))
+        .route("/sessions/{session_id}/players", get(get_players))
+        .route(
+            "/sessions/{session_id}/players/{guild_id}",
+            get(get_player).patch(update_player).delete(destroy_player),
+        )
+        .route(
+            "/sessions/{session_id}/players/{guild_id}/recording",
+            get(get_recording)
+                .post(start_recording)
+                .delete(stop_recording),
+        )
+        .route(
+            "/sessions/{session_id}/players/{guild_id}/users",
+            get(get_channel_users),
+        )
+}
+
+/// Error in the shape Lavalink clients expect from the v4 api
+#[derive(Debug)]
+pub struct RestError {
+    status: StatusCode,
+    message: String,
+    path: String,
+}
+
+impl RestError {
+    pub fn new(status: StatusCode, message: impl Into<String>, uri: &Uri) -> Self {
+        Self {
+            status,
+            message: message.into(),
+            path: uri.path().to_owned(),
+        }
+    }
+}
+
+impl IntoResponse for RestError {
+    fn into_response(self) -> Response {
+        let body = v4::Error {
+            timestamp: now_millis(),
+            status: self.status.as_u16(),
+            error: self
+                .status
+                .canonical_reason()
+                .unwrap_or_default()
+                .to_owned(),
+            message: self.message,
+            path: self.path,
+        };
+        (self.status, Json(body)).into_response()
+    }
+}
+
+async fn ws_handler(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    Extension(headers): Extension<Headers>,
+) -> Response {
+    ws.on_upgrade(move |socket| spawn_client_session(headers, ProtocolVersion::V4, state, socket))
+}
+
+fn find_session(state: &AppState, session_id: &str, uri: &Uri) -> Result<Arc<Session>, RestError> {
+    state
+        .sessions
+        .get(session_id)
+        .ok_or_else(|| RestError::new(StatusCode::NOT_FOUND, "Session not found", uri))
+}
+
+async fn info_handler(State(state): State<AppState>) -> Json<v4::Info> {
+    Json(info(&state.sources))
+}
+
+#[derive(Deserialize)]
+struct LoadTracksParams {
+    identifier: String,
+}
+
+async fn loadtracks_handler(
+    State(state): State<AppState>,
+    Query(params): Query<LoadTracksParams>,
+) -> Json<v4::LoadResult> {
+    Json(state.sources.load_item(&params.identifier).await.into())
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DecodeTrackParams {
+    encoded_track: String,
+}
+
+async fn decodetrack_handler(
+    State(state): State<AppState>,
+    OriginalUri(uri): OriginalUri,
+    Query(params): Query<DecodeTrackParams>,
+) -> Result<Json<v4::Track>, RestError> {
+    state
+        .sources
+        .decode_track(&params.encoded_track)
+        .map(|track| Json(track.into()))
+        .map_err(|e| RestError::new(StatusCode::BAD_REQUEST, e.to_string(), &uri))
+}
+
+async fn decodetracks_handler(
+    State(state): State<AppState>,
+    OriginalUri(uri): OriginalUri,
+    Json(encoded_tracks): Json<Vec<String>>,
+) -> Result<Json<Vec<v4::Track>>, RestError> {
+    encoded_tracks
+        .iter()
+        .map(|encoded| {
+            state
+                .sources
+                .decode_track(encoded)
+                .map(v4::Track::from)
+                .map_err(|e| RestError::new(StatusCode::BAD_REQUEST, e.to_string(), &uri))
+        })
+        .collect::<Result<_, _>>()
+        .map(Json)
+}
+
+async fn update_session(
+    State(state): State<AppState>,
+    OriginalUri(uri): OriginalUri,
+    Path(session_id): Path<String>,
+    Json(update): Json<UpdateSession>,
+) -> Result<Json<v4::Session>, RestError> {
+    let session = find_session(&state, &session_id, &uri)?;
+    let mut config: ResumeConfig = session.resume_config();
+    if let Some(resuming) = update.resuming {
+        config.resuming = resuming;
+    }
+    if let Some(timeout) = update.timeout {
+        config.timeout = Duration::from_secs(timeout);
+    }
+    session.set_resume_config(config.clone());
+    Ok(Json(config.into()))
+}
+
+async fn get_players(
+    State(state): State<AppState>,
+    OriginalUri(uri): OriginalUri,
+    Path(session_id): Path<String>,
+) -> Result<Json<Vec<v4::Player>>, RestError> {
+    let session = find_session(&state, &session_id, &uri)?;
+    let players = session.players.lock().await;
+    Ok(Json(players.values().map(v4::Player::from).collect()))
+}
+
+async fn get_player(
+    State(state): State<AppState>,
+    OriginalUri(uri): OriginalUri,
+    Path((session_id, guild_id)): Path<(String, String)>,
+) -> Result<Json<v4::Player>, RestError> {
+    let session = find_session(&state, &session_id, &uri)?;
+    let players = session.players.lock().await;
+    players
+        .get(&guild_id)
+        .map(|player| Json(player.into()))
+        .ok_or_else(|| RestError::new(StatusCode::NOT_FOUND, "Player not found", &uri))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UpdatePlayerParams {
+    #[serde(default)]
+    no_replace: bool,
+}
+
+async fn update_player(
+    State(state): State<AppState>,
+    OriginalUri(uri): OriginalUri,
+    Path((session_id, guild_id)): Path<(String, String)>,
+    Query(params): Query<UpdatePlayerParams>,
+    Json(update): Json<UpdatePlayer>,
+) -> Result<Json<v4::Player>, RestError> {
+    let session = find_session(&state, &session_id, &uri)?;
+    let bad_request = |message: String| RestError::new(StatusCode::BAD_REQUEST, message, &uri);
+
+    if let Some(voice) = update.voice {
+        let voice_update = VoiceUpdate {
+            session_id: voice.session_id,
+            channel_id: voice.channel_id,
+            event: VoiceUpdateEvent {
+                token: voice.token,
+                guild_id: guild_id.clone(),
+                endpoint: voice.endpoint,
+            },
+        };
+        session
+            .update_voice(voice_update)
+            .await
+            .map_err(|e| RestError::new(StatusCode::INTERNAL_SERVER_ERROR, e.to_string(), &uri))?;
+    }
+
+    let (encoded, identifier) = match update.track {
+        Some(track) => (track.encoded, track.identifier),
+        None => (update.encoded_track, update.identifier),
+    };
+    // Some(None) stops the player, None leaves the current track alone
+    let new_track: Option<Option<AudioTrack>> = match (encoded, identifier) {
+        (Some(_), Some(_)) => {
+            return Err(bad_request(
+                "Only one of encoded and identifier may be set".to_owned(),
+            ))
+        }
+        (Some(Some(encoded)), None) => Some(Some(
+            session
+                .sources()
+                .decode_track(&encoded)
+                .map_err(|e| bad_request(e.to_string()))?,
+        )),
+        (Some(None), None) => Some(None),
+        (None, Some(identifier)) => match session.sources().load_item(&identifier).await {
+            LoadResult::Track(track) => Some(Some(track)),
+            _ => {
+                return Err(bad_request(format!(
+                    "{} did not resolve to a single track",
+                    identifier
+                )))
+            }
+        },
+        (None, None) => None,
+    };
+
+    // like Lavalink, players can exist before they have a voice state
+    session.ensure_player(&guild_id).await;
+    {
+        let mut players = session.players.lock().await;
+        let player = players
+            .get_mut(&guild_id)
+            .ok_or_else(|| RestError::new(StatusCode::NOT_FOUND, "Player not found", &uri))?;
+
+        if let Some(volume) = update.volume {
+            player.set_volume(volume);
+        }
+        if let Some(paused) = update.paused {
+            player.set_paused(paused);
+        }
+        if let Some(filters) = update.filters {
+            player.set_filters(filters);
+        }
+        if let Some(speaking) = update.speaking {
+            player.set_speaking_flags(speaking);
+        }
+        if let Some(None) = new_track {
+            player.stop();
+        }
+    }
+
+    // these open the track's source, which the players aren't locked for
+    let position = update.position.map(Duration::from_millis);
+    match new_track {
+        Some(Some(track)) => {
+            let end_time = update.end_time.flatten().map(Duration::from_millis);
+            session
+                .play(
+                    &guild_id,
+                    track,
+                    position.unwrap_or_default(),
+                    end_time,
+                    params.no_replace,
+                )
+                .await
+                .map_err(|e| {
+                    RestError::new(StatusCode::INTERNAL_SERVER_ERROR, e.to_string(), &uri)
+                })?;
+        }
+        Some(None) => {}
+        None => {
+            if let Some(position) = position {
+                session
+                    .seek(&guild_id, position)
+                    .await
+                    .map_err(|e| bad_request(e.to_string()))?;
+            }
+        }
+    }
+
+    let players = session.players.lock().await;
+    players
+        .get(&guild_id)
+        .map(|player| Json(player.into()))
+        .ok_or_else(|| RestError::new(StatusCode::NOT_FOUND, "Player not found", &uri))
+}
+
+async fn destroy_player(
+    State(state): State<AppState>,
+    OriginalUri(uri): OriginalUri,
+    Path((session_id, guild_id)): Path<(String, String)>,
+) -> Result<StatusCode, RestError> {
+    let session = find_session(&state, &session_id, &uri)?;
+    session.destroy_player(&guild_id).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn get_channel_users(
+    State(state): State<AppState>,
+    OriginalUri(uri): OriginalUri,
+    Path((session_id, guild_id)): Path<(String, String)>,
+) -> Result<Json<Vec<v4::ChannelUser>>, RestError> {
+    let session = find_session(&state, &session_id, &uri)?;
+    let players = session.players.lock().await;
+    players
+        .get(&guild_id)
+        .map(|player| Json(player.users().into_iter().map(Into::into).collect()))
+        .ok_or_else(|| RestError::new(StatusCode::NOT_FOUND, "Player not found", &uri))
+}
+
+async fn get_recording(
+    State(state): State<AppState>,
+    OriginalUri(uri): OriginalUri,
+    Path((session_id, guild_id)): Path<(String, String)>,
+) -> Result<Json<RecordingState>, RestError> {
+    let session = find_session(&state, &session_id, &uri)?;
+    let players = session.players.lock().await;
+    players
+        .get(&guild_id)
+        .and_then(|player| player.recording())
+        .map(|recording| {
+            Json(RecordingState {
+                id: recording.id().to_owned(),
+                started_at: recording.started_at(),
+            })
+        })
+        .ok_or_else(|| RestError::new(StatusCode::NOT_FOUND, "Not recording", &uri))
+}
+
+async fn start_recording(
+    State(state): State<AppState>,
+    OriginalUri(uri): OriginalUri,
+    Path((session_id, guild_id)): Path<(String, String)>,
+) -> Result<Json<RecordingState>, RestError> {
+    let session = find_session(&state, &session_id, &uri)?;
+    session
+        .start_recording(&guild_id)
+        .await
+        .map(Json)
+        .map_err(|e| RestError::new(StatusCode::BAD_REQUEST, e.to_string(), &uri))
+}
+
+async fn stop_recording(
+    State(state): State<AppState>,
+    OriginalUri(uri): OriginalUri,
+    Path((session_id, guild_id)): Path<(String, String)>,
+) -> Result<Json<RecordingFinished>, RestError> {
+    let session = find_session(&state, &session_id, &uri)?;
+    session
+        .stop_recording(&guild_id)
+        .await
+        .map(Json)
+        .ok_or_else(|| RestError::new(StatusCode::NOT_FOUND, "Not recording", &uri))
+}
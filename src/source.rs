@@ -0,0 +1,241 @@
+mod http;
+mod library;
+mod local;
+mod probe;
+mod stream;
+mod track;
+
+use std::{pin::Pin, sync::Arc, time::Duration};
+
+use futures_util::{future::BoxFuture, Stream, StreamExt};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
+use tracing::{debug, warn};
+
+use crate::{config::MediaConfiguration, opus_parse::OggStream, webm_parse::WebmStream};
+
+pub use http::HttpSourceManager;
+pub use library::{LibraryEntry, LibraryIndex};
+pub use local::{LocalSourceManager, SEARCH_PREFIX};
+pub use probe::{probe_file, Metadata};
+pub use stream::StreamSourceManager;
+pub use track::{decode_track, encode_track, AudioTrack, TrackInfo, STREAM_LENGTH};
+
+/// A stream of raw opus packets, ready to be handed to the voice connection. It
+/// ends at the first error.
+pub type PacketStream = Pin<Box<dyn Stream<Item = Result<Vec<u8>, SourceError>> + Send>>;
+
+#[derive(Error, Debug)]
+pub enum SourceError {
+    #[error("invalid track: {0}")]
+    InvalidTrack(String),
+    #[error("no source manager named {0} is registered")]
+    UnknownSource(String),
+    #[error("unsupported container format")]
+    UnsupportedFormat,
+    #[error("{0} is outside of the local roots")]
+    NotAllowed(String),
+    #[error("larger than {0} bytes")]
+    TooLarge(u64),
+    #[error("io error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("http error: {0}")]
+    HttpError(#[from] reqwest::Error),
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+#[derive(Debug)]
+pub enum LoadResult {
+    Track(AudioTrack),
+    Playlist {
+        name: String,
+        selected_track: Option<usize>,
+        tracks: Vec<AudioTrack>,
+    },
+    Search(Vec<AudioTrack>),
+    Empty,
+    Error(SourceError),
+}
+
+/// A source of tracks, in the spirit of lavaplayer's `AudioSourceManager`.
+///
+/// Managers are asked in registration order whether they [`identify`] an identifier,
+/// and the first one that returns something other than [`LoadResult::Empty`] from
+/// [`load_item`] wins.
+///
+/// [`identify`]: AudioSourceManager::identify
+/// [`load_item`]: AudioSourceManager::load_item
+pub trait AudioSourceManager: Send + Sync {
+    /// Name written into [`TrackInfo::source_name`], used to route encoded tracks back
+    /// to the manager that produced them
+    fn name(&self) -> &'static str;
+
+    /// Cheap check for whether this manager could possibly load the identifier
+    fn identify(&self, identifier: &str) -> bool;
+
+    fn load_item<'a>(
+        &'a self,
+        identifier: &'a str,
+    ) -> BoxFuture<'a, Result<LoadResult, SourceError>>;
+
+    /// Rebuilds a track from info decoded out of an encoded track. Managers that need
+    /// to validate or refresh the info can override this.
+    fn decode_track(&self, info: TrackInfo) -> Result<AudioTrack, SourceError> {
+        Ok(AudioTrack::new(info))
+    }
+
+    fn open_stream<'a>(
+        &'a self,
+        track: &'a AudioTrack,
+    ) -> BoxFuture<'a, Result<PacketStream, SourceError>>;
+}
+
+#[derive(Default)]
+pub struct SourceRegistry {
+    managers: Vec<Box<dyn AudioSourceManager>>,
+}
+
+impl SourceRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Builds the registry from the enabled sources. Must be called from within a
+    /// tokio runtime if library roots are configured, since the index refreshes in
+    /// the background.
+    pub fn from_config(config: &MediaConfiguration) -> Self {
+        let mut registry = Self::new();
+        if config.sources.local {
+            // whatever the library indexes has to be playable
+            let roots = [
+                config.sources.local_roots.as_slice(),
+                config.library.roots.as_slice(),
+            ]
+            .concat();
+            if config.library.roots.is_empty() {
+                registry.register(LocalSourceManager::new(&roots));
+            } else {
+                let library = Arc::new(LibraryIndex::new(&config.library));
+                library
+                    .clone()
+                    .spawn_refresh(Duration::from_secs(config.library.refresh_interval));
+                registry.register(LocalSourceManager::with_library(&roots, library));
+            }
+        }
+        // streams have to be ruled out before the http manager claims the url
+        if config.sources.stream {
+            registry.register(StreamSourceManager::new());
+        }
+        if config.sources.http {
+            registry.register(HttpSourceManager::new(config.sources.http_max_size));
+        }
+        registry
+    }
+
+    pub fn register(&mut self, manager: impl AudioSourceManager + 'static) {
+        debug!("Registered source manager {}", manager.name());
+        self.managers.push(Box::new(manager));
+    }
+
+    pub fn managers(&self) -> impl Iterator<Item = &dyn AudioSourceManager> {
+        self.managers.iter().map(|m| m.as_ref())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn AudioSourceManager> {
+        self.managers().find(|m| m.name() == name)
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub async fn load_item(&self, identifier: &str) -> LoadResult {
+        for manager in self.managers().filter(|m| m.identify(identifier)) {
+            match manager.load_item(identifier).await {
+                Ok(LoadResult::Empty) => continue,
+                Ok(result) => return result,
+                Err(e) => {
+                    warn!("{} failed to load {}: {}", manager.name(), identifier, e);
+                    return LoadResult::Error(e);
+                }
+            }
+        }
+        LoadResult::Empty
+    }
+
+    pub fn decode_track(&self, encoded: &str) -> Result<AudioTrack, SourceError> {
+        let info = decode_track(encoded)?;
+        self.get(&info.source_name)
+            .ok_or_else(|| SourceError::UnknownSource(info.source_name.clone()))?
+            .decode_track(info)
+    }
+
+    pub async fn open_stream(&self, track: &AudioTrack) -> Result<PacketStream, SourceError> {
+        self.get(&track.info.source_name)
+            .ok_or_else(|| SourceError::UnknownSource(track.info.source_name.clone()))?
+            .open_stream(track)
+            .await
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Container {
+    Webm,
+    Ogg,
+}
+
+impl Container {
+    /// Guesses the container from the first bytes of a file
+    pub fn sniff(magic: &[u8]) -> Option<Self> {
+        match magic {
+            [0x1A, 0x45, 0xDF, 0xA3, ..] => Some(Container::Webm),
+            [b'O', b'g', b'g', b'S', ..] => Some(Container::Ogg),
+            _ => None,
+        }
+    }
+
+    pub fn from_mime(mime: &str) -> Option<Self> {
+        match mime.split(';').next()?.trim() {
+            "audio/webm" | "video/webm" | "audio/x-matroska" => Some(Container::Webm),
+            "audio/ogg" | "application/ogg" | "audio/opus" => Some(Container::Ogg),
+            _ => None,
+        }
+    }
+}
+
+/// Reads the magic bytes of a seekable reader and rewinds it
+pub async fn sniff_container<R: AsyncRead + AsyncSeek + Unpin>(
+    reader: &mut R,
+) -> Result<Container, SourceError> {
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic).await?;
+    reader.rewind().await?;
+    Container::sniff(&magic).ok_or(SourceError::UnsupportedFormat)
+}
+
+/// Wraps a seekable reader in the demuxer for its container
+pub fn open_seekable<R>(reader: R, container: Container) -> PacketStream
+where
+    R: AsyncRead + AsyncSeek + Unpin + Send + 'static,
+{
+    match container {
+        Container::Webm => Box::pin(WebmStream::new(reader).map(Ok)),
+        Container::Ogg => open_ogg(reader),
+    }
+}
+
+/// Wraps a reader in the ogg demuxer, dropping the OpusHead and OpusTags header
+/// packets so only audio reaches the voice connection
+pub fn open_ogg<R>(reader: R) -> PacketStream
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    Box::pin(
+        OggStream::new(reader)
+            .filter(|packet| {
+                futures_util::future::ready(!matches!(
+                    packet,
+                    Ok(packet) if packet.starts_with(b"OpusHead") || packet.starts_with(b"OpusTags")
+                ))
+            })
+            .map(|packet| packet.map_err(SourceError::from)),
+    )
+}
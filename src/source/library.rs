@@ -0,0 +1,274 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{fs, time};
+use tracing::{debug, error, info, warn};
+
+use crate::config::LibraryConfiguration;
+
+use super::probe::probe_file;
+
+const EXTENSIONS: [&str; 5] = ["webm", "mka", "mkv", "ogg", "opus"];
+
+/// A probed file inside one of the library roots
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LibraryEntry {
+    pub path: String,
+    pub title: String,
+    pub artist: Option<String>,
+    pub length: Option<u64>,
+}
+
+impl LibraryEntry {
+    fn matches(&self, terms: &[String]) -> bool {
+        let haystack = format!(
+            "{} {} {}",
+            self.title,
+            self.artist.as_deref().unwrap_or_default(),
+            self.file_name()
+        )
+        .to_lowercase();
+        terms.iter().all(|term| haystack.contains(term.as_str()))
+    }
+
+    fn file_name(&self) -> &str {
+        Path::new(&self.path)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or(&self.path)
+    }
+}
+
+/// Searchable index over the configured music directories, used for `localsearch:`
+#[derive(Debug)]
+pub struct LibraryIndex {
+    roots: Vec<PathBuf>,
+    index_path: Option<PathBuf>,
+    entries: RwLock<Vec<LibraryEntry>>,
+}
+
+impl LibraryIndex {
+    pub fn new(config: &LibraryConfiguration) -> Self {
+        Self {
+            roots: config.roots.iter().map(PathBuf::from).collect(),
+            index_path: config.index_path.as_ref().map(PathBuf::from),
+            entries: RwLock::new(Vec::new()),
+        }
+    }
+
+    /// Loads the persisted index if there is one, then rescans the library roots
+    /// immediately and every `refresh_interval` after that. An interval of 0 only
+    /// scans them the once.
+    pub fn spawn_refresh(self: Arc<Self>, refresh_interval: Duration) {
+        tokio::spawn(async move {
+            if let Err(e) = self.load().await {
+                warn!("Could not load persisted library index: {}", e);
+            }
+            if refresh_interval.is_zero() {
+                self.refresh().await;
+                return;
+            }
+            let mut interval = time::interval(refresh_interval);
+            loop {
+                interval.tick().await;
+                self.refresh().await;
+            }
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns every entry whose title, artist or file name contains all the words
+    /// of `query`, entries matching on title first
+    pub fn search(&self, query: &str, limit: usize) -> Vec<LibraryEntry> {
+        let terms: Vec<String> = query.split_whitespace().map(str::to_lowercase).collect();
+        if terms.is_empty() {
+            return Vec::new();
+        }
+
+        let entries = self.entries.read().unwrap();
+        let mut results: Vec<&LibraryEntry> =
+            entries.iter().filter(|e| e.matches(&terms)).collect();
+        results.sort_by_key(|entry| {
+            let title = entry.title.to_lowercase();
+            !terms.iter().all(|term| title.contains(term.as_str()))
+        });
+        results.into_iter().take(limit).cloned().collect()
+    }
+
+    pub async fn refresh(&self) {
+        let mut entries = Vec::new();
+        for root in &self.roots {
+            if let Err(e) = Self::scan(root, &mut entries).await {
+                error!("Failed to scan library root {}: {}", root.display(), e);
+            }
+        }
+        info!("Indexed {} files in the local library", entries.len());
+        *self.entries.write().unwrap() = entries;
+
+        if let Err(e) = self.save().await {
+            error!("Failed to persist library index: {}", e);
+        }
+    }
+
+    async fn scan(root: &Path, entries: &mut Vec<LibraryEntry>) -> std::io::Result<()> {
+        let mut dirs = vec![root.to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            // an unreadable subdirectory only loses what's in it, not the whole root
+            let mut read_dir = match fs::read_dir(&dir).await {
+                Ok(read_dir) => read_dir,
+                Err(e) if dir != root => {
+                    warn!("Skipping {}: {}", dir.display(), e);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            while let Some(dir_entry) = read_dir.next_entry().await? {
+                let path = dir_entry.path();
+                let file_type = match dir_entry.file_type().await {
+                    Ok(file_type) => file_type,
+                    Err(e) => {
+                        warn!("Skipping {}: {}", path.display(), e);
+                        continue;
+                    }
+                };
+                if file_type.is_dir() {
+                    dirs.push(path);
+                    continue;
+                }
+
+                let supported = path
+                    .extension()
+                    .and_then(|ext| ext.to_str())
+                    .is_some_and(|ext| EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()));
+                if !supported {
+                    continue;
+                }
+
+                match probe_file(&path).await {
+                    Ok(Some((_, metadata))) => entries.push(LibraryEntry {
+                        title: metadata.title.unwrap_or_else(|| {
+                            path.file_stem()
+                                .map(|stem| stem.to_string_lossy().into_owned())
+                                .unwrap_or_default()
+                        }),
+                        path: path.to_string_lossy().into_owned(),
+                        artist: metadata.artist,
+                        length: metadata.length,
+                    }),
+                    Ok(None) => debug!("Skipping {}, not webm or ogg", path.display()),
+                    Err(e) => warn!("Failed to probe {}: {}", path.display(), e),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn load(&self) -> anyhow::Result<()> {
+        let Some(index_path) = &self.index_path else {
+            return Ok(());
+        };
+        if !fs::try_exists(index_path).await? {
+            return Ok(());
+        }
+        let data = fs::read(index_path).await?;
+        let entries: Vec<LibraryEntry> = serde_json::from_slice(&data)?;
+        debug!("Loaded {} persisted library entries", entries.len());
+        *self.entries.write().unwrap() = entries;
+        Ok(())
+    }
+
+    async fn save(&self) -> anyhow::Result<()> {
+        let Some(index_path) = &self.index_path else {
+            return Ok(());
+        };
+        let data = serde_json::to_vec(&*self.entries.read().unwrap())?;
+        fs::write(index_path, data).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opus_write::OggOpusWriter;
+
+    fn entry(path: &str, title: &str, artist: &str) -> LibraryEntry {
+        LibraryEntry {
+            path: path.to_owned(),
+            title: title.to_owned(),
+            artist: Some(artist.to_owned()),
+            length: None,
+        }
+    }
+
+    fn index(entries: Vec<LibraryEntry>) -> LibraryIndex {
+        let index = LibraryIndex::new(&LibraryConfiguration::default());
+        *index.entries.write().unwrap() = entries;
+        index
+    }
+
+    #[test]
+    fn search_ranks_title_matches_first() {
+        let by_file_name = entry("/music/Blue Monday.ogg", "Confusion", "New Order");
+        let by_title = entry("/music/01.ogg", "Blue Monday", "New Order");
+        let by_artist = entry("/music/02.ogg", "Monday", "Blue Hour");
+        let index = index(vec![
+            by_file_name.clone(),
+            entry("/music/03.ogg", "Blue Monday", "Someone Else"),
+            by_artist.clone(),
+            by_title.clone(),
+        ]);
+
+        let results = index.search("blue MONDAY", 10);
+        assert_eq!(results.len(), 4);
+        assert_eq!(results[0].path, "/music/03.ogg");
+        assert_eq!(results[1], by_title);
+        // the rest keep the order they were indexed in
+        assert_eq!(results[2..], [by_file_name.clone(), by_artist]);
+
+        assert_eq!(index.search("blue monday", 1).len(), 1);
+        // every word has to match somewhere
+        assert_eq!(index.search("new order confusion", 10), [by_file_name]);
+        assert!(index.search("blue tuesday", 10).is_empty());
+        assert!(index.search("  ", 10).is_empty());
+    }
+
+    #[tokio::test]
+    async fn scan_indexes_nested_files() {
+        let root = std::env::temp_dir().join(format!("jukebox-library-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(root.join("album")).unwrap();
+        let mut writer = OggOpusWriter::new(1);
+        let mut track = writer.headers(2);
+        for _ in 0..50 {
+            track.extend(writer.push(vec![0xfc, 0xff, 0xfe], 960).unwrap_or_default());
+        }
+        track.extend(writer.finish());
+        std::fs::write(root.join("album/Track One.opus"), &track).unwrap();
+        std::fs::write(root.join("notes.txt"), "not audio").unwrap();
+        std::fs::write(root.join("fake.ogg"), "not audio either").unwrap();
+
+        let mut entries = Vec::new();
+        let scanned = LibraryIndex::scan(&root, &mut entries).await;
+        std::fs::remove_dir_all(&root).unwrap();
+        scanned.unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].title, "Track One");
+        assert_eq!(entries[0].artist, None);
+        assert_eq!(entries[0].length, Some(1000));
+        assert!(entries[0].path.ends_with("Track One.opus"));
+
+        // a root that's gone is still an error
+        assert!(LibraryIndex::scan(&root, &mut entries).await.is_err());
+    }
+}
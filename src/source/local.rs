@@ -0,0 +1,165 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use futures_util::{future::BoxFuture, FutureExt};
+use tokio::fs::{self, File};
+use tracing::warn;
+
+use super::{
+    library::{LibraryEntry, LibraryIndex},
+    open_seekable,
+    probe::{probe_file, Metadata},
+    sniff_container, AudioSourceManager, AudioTrack, LoadResult, PacketStream, SourceError,
+    TrackInfo,
+};
+
+pub const SEARCH_PREFIX: &str = "localsearch:";
+const SEARCH_LIMIT: usize = 25;
+
+/// Plays webm and ogg opus files below the configured roots, and searches the
+/// library index for `localsearch:` identifiers if one is configured
+#[derive(Debug, Default)]
+pub struct LocalSourceManager {
+    // canonical, so that neither .. nor symlinks lead out of them
+    roots: Vec<PathBuf>,
+    library: Option<Arc<LibraryIndex>>,
+}
+
+impl LocalSourceManager {
+    /// Only files below `roots` can be played. Roots that don't exist are left out.
+    pub fn new(roots: &[String]) -> Self {
+        let roots = roots
+            .iter()
+            .filter_map(|root| match std::fs::canonicalize(root) {
+                Ok(root) => Some(root),
+                Err(e) => {
+                    warn!("Ignoring local root {}: {}", root, e);
+                    None
+                }
+            })
+            .collect();
+        Self {
+            roots,
+            library: None,
+        }
+    }
+
+    pub fn with_library(roots: &[String], library: Arc<LibraryIndex>) -> Self {
+        Self {
+            library: Some(library),
+            ..Self::new(roots)
+        }
+    }
+
+    /// The canonical path of `identifier`, if it is below one of the roots
+    async fn resolve(&self, identifier: &str) -> Option<PathBuf> {
+        let path = fs::canonicalize(identifier).await.ok()?;
+        self.roots
+            .iter()
+            .any(|root| path.starts_with(root))
+            .then_some(path)
+    }
+
+    fn track(&self, path: &str, metadata: Metadata) -> AudioTrack {
+        let title = metadata.title.unwrap_or_else(|| {
+            Path::new(path)
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_else(|| path.to_owned())
+        });
+
+        AudioTrack::new(TrackInfo {
+            identifier: path.to_owned(),
+            is_seekable: true,
+            author: metadata
+                .artist
+                .unwrap_or_else(|| "Unknown artist".to_owned()),
+            length: metadata.length.unwrap_or(0),
+            is_stream: false,
+            position: 0,
+            title,
+            uri: Some(path.to_owned()),
+            artwork_url: None,
+            isrc: None,
+            source_name: self.name().to_owned(),
+        })
+    }
+
+    fn search(&self, query: &str) -> LoadResult {
+        let Some(library) = &self.library else {
+            return LoadResult::Empty;
+        };
+        let tracks: Vec<AudioTrack> = library
+            .search(query, SEARCH_LIMIT)
+            .into_iter()
+            .map(|entry: LibraryEntry| {
+                self.track(
+                    &entry.path,
+                    Metadata {
+                        title: Some(entry.title),
+                        artist: entry.artist,
+                        length: entry.length,
+                    },
+                )
+            })
+            .collect();
+        if tracks.is_empty() {
+            LoadResult::Empty
+        } else {
+            LoadResult::Search(tracks)
+        }
+    }
+}
+
+impl AudioSourceManager for LocalSourceManager {
+    fn name(&self) -> &'static str {
+        "local"
+    }
+
+    fn identify(&self, identifier: &str) -> bool {
+        identifier.starts_with(SEARCH_PREFIX) || !identifier.contains("://")
+    }
+
+    fn load_item<'a>(
+        &'a self,
+        identifier: &'a str,
+    ) -> BoxFuture<'a, Result<LoadResult, SourceError>> {
+        async move {
+            if let Some(query) = identifier.strip_prefix(SEARCH_PREFIX) {
+                return Ok(self.search(query));
+            }
+
+            let Some(path) = self.resolve(identifier).await else {
+                return Ok(LoadResult::Empty);
+            };
+            match fs::metadata(&path).await {
+                Ok(metadata) if metadata.is_file() => {}
+                _ => return Ok(LoadResult::Empty),
+            }
+
+            match probe_file(&path).await? {
+                Some((_, metadata)) => Ok(LoadResult::Track(self.track(identifier, metadata))),
+                None => Err(SourceError::UnsupportedFormat),
+            }
+        }
+        .boxed()
+    }
+
+    fn open_stream<'a>(
+        &'a self,
+        track: &'a AudioTrack,
+    ) -> BoxFuture<'a, Result<PacketStream, SourceError>> {
+        async move {
+            // encoded tracks can name any file
+            let Some(path) = self.resolve(&track.info.identifier).await else {
+                return Err(SourceError::NotAllowed(track.info.identifier.clone()));
+            };
+            let mut file = File::open(path).await?;
+            let container = sniff_container(&mut file).await?;
+            Ok(open_seekable(file, container))
+        }
+        .boxed()
+    }
+}
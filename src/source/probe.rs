@@ -0,0 +1,363 @@
+use std::{io::SeekFrom, path::Path};
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+
+use super::Container;
+
+// enough for the headers and tags of any reasonably tagged file, cover art aside
+const HEAD_PROBE_SIZE: u64 = 256 * 1024;
+// a single ogg page is at most ~64KiB, so the last page header is always in here
+const TAIL_PROBE_SIZE: u64 = 80 * 1024;
+
+const OPUS_SAMPLE_RATE: u64 = 48_000;
+
+/// What could be learned about a file without demuxing all of it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    /// Length in milliseconds
+    pub length: Option<u64>,
+}
+
+/// Reads the tags and duration of a webm or ogg opus file. Returns `None` if the
+/// file is in neither container.
+pub async fn probe_file(path: impl AsRef<Path>) -> std::io::Result<Option<(Container, Metadata)>> {
+    let mut file = File::open(path).await?;
+    let file_len = file.metadata().await?.len();
+
+    let mut head = Vec::new();
+    (&mut file)
+        .take(HEAD_PROBE_SIZE)
+        .read_to_end(&mut head)
+        .await?;
+
+    let Some(container) = Container::sniff(&head) else {
+        return Ok(None);
+    };
+
+    let metadata = match container {
+        Container::Webm => probe_webm(&head),
+        Container::Ogg => {
+            let tail_start = file_len.saturating_sub(TAIL_PROBE_SIZE);
+            file.seek(SeekFrom::Start(tail_start)).await?;
+            let mut tail = Vec::new();
+            file.read_to_end(&mut tail).await?;
+            probe_ogg(&head, &tail)
+        }
+    };
+    Ok(Some((container, metadata)))
+}
+
+fn probe_ogg(head: &[u8], tail: &[u8]) -> Metadata {
+    let mut metadata = Metadata::default();
+
+    if let Some(comments) = find(head, b"OpusTags").map(|i| &head[i + 8..]) {
+        for (key, value) in read_vorbis_comments(comments) {
+            match key.to_ascii_uppercase().as_str() {
+                "TITLE" => metadata.title = Some(value),
+                "ARTIST" => metadata.artist = Some(value),
+                _ => {}
+            }
+        }
+    }
+
+    let pre_skip = find(head, b"OpusHead")
+        .and_then(|i| head.get(i + 10..i + 12))
+        .map(LittleEndian::read_u16)
+        .unwrap_or(0) as u64;
+
+    // granule position of the last page is the total sample count
+    metadata.length = rfind(tail, b"OggS")
+        .and_then(|i| tail.get(i + 6..i + 14))
+        .map(LittleEndian::read_u64)
+        .map(|granule| granule.saturating_sub(pre_skip) * 1000 / OPUS_SAMPLE_RATE);
+
+    metadata
+}
+
+fn read_vorbis_comments(mut data: &[u8]) -> Vec<(String, String)> {
+    fn take(data: &mut &[u8]) -> Option<Vec<u8>> {
+        let len = LittleEndian::read_u32(data.get(..4)?) as usize;
+        let bytes = data.get(4..4 + len)?.to_vec();
+        *data = &data[4 + len..];
+        Some(bytes)
+    }
+
+    let mut comments = Vec::new();
+
+    // vendor string
+    if take(&mut data).is_none() || data.len() < 4 {
+        return comments;
+    }
+    let count = LittleEndian::read_u32(data);
+    data = &data[4..];
+
+    for _ in 0..count {
+        let Some(comment) = take(&mut data) else {
+            break;
+        };
+        let comment = String::from_utf8_lossy(&comment);
+        if let Some((key, value)) = comment.split_once('=') {
+            comments.push((key.to_owned(), value.to_owned()));
+        }
+    }
+    comments
+}
+
+// ebml ids
+const SEGMENT: u32 = 0x18538067;
+const INFO: u32 = 0x1549A966;
+const TIMECODE_SCALE: u32 = 0x2AD7B1;
+const DURATION: u32 = 0x4489;
+const TITLE: u32 = 0x7BA9;
+const TAGS: u32 = 0x1254C367;
+const TAG: u32 = 0x7373;
+const SIMPLE_TAG: u32 = 0x67C8;
+const TAG_NAME: u32 = 0x45A3;
+const TAG_STRING: u32 = 0x4487;
+const CLUSTER: u32 = 0x1F43B675;
+
+fn probe_webm(head: &[u8]) -> Metadata {
+    let mut metadata = Metadata::default();
+    let mut timecode_scale = 1_000_000u64;
+    let mut duration = None;
+    let mut tag_name: Option<String> = None;
+    let mut data = head;
+
+    // masters are entered by simply not skipping their body, everything we need
+    // lives in the first few elements of the segment
+    while let Some((id, size, rest)) = read_element_header(data) {
+        let body_len = size.unwrap_or(rest.len()).min(rest.len());
+        let body = &rest[..body_len];
+        match id {
+            SEGMENT | INFO | TAGS | TAG | SIMPLE_TAG => {
+                data = rest;
+                continue;
+            }
+            CLUSTER => break,
+            TIMECODE_SCALE => timecode_scale = read_uint(body),
+            DURATION => {
+                duration = match body.len() {
+                    4 => Some(BigEndian::read_f32(body) as f64),
+                    8 => Some(BigEndian::read_f64(body)),
+                    _ => None,
+                }
+            }
+            TITLE => metadata.title = Some(String::from_utf8_lossy(body).into_owned()),
+            TAG_NAME => tag_name = Some(String::from_utf8_lossy(body).to_ascii_uppercase()),
+            TAG_STRING => {
+                let value = String::from_utf8_lossy(body).into_owned();
+                match tag_name.take().as_deref() {
+                    Some("TITLE") => metadata.title = Some(value),
+                    Some("ARTIST") => metadata.artist = Some(value),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+        data = &rest[body_len..];
+    }
+
+    metadata.length =
+        duration.map(|duration| (duration * timecode_scale as f64 / 1_000_000.0) as u64);
+    metadata
+}
+
+/// Returns the id, size (`None` if unknown) and everything after the header
+fn read_element_header(data: &[u8]) -> Option<(u32, Option<usize>, &[u8])> {
+    let id_len = data.first()?.leading_zeros() as usize + 1;
+    if id_len > 4 {
+        return None;
+    }
+    let id = data
+        .get(..id_len)?
+        .iter()
+        .fold(0u32, |acc, &byte| (acc << 8) | byte as u32);
+    let data = &data[id_len..];
+
+    let size_len = data.first()?.leading_zeros() as usize + 1;
+    if size_len > 8 {
+        return None;
+    }
+    let size_bytes = data.get(..size_len)?;
+    let mask = (0xFFu16 >> size_len) as u8;
+    let size = size_bytes[1..]
+        .iter()
+        .fold((size_bytes[0] & mask) as u64, |acc, &byte| {
+            (acc << 8) | byte as u64
+        });
+    let unknown = size == (1u64 << (7 * size_len)) - 1;
+
+    Some((id, (!unknown).then_some(size as usize), &data[size_len..]))
+}
+
+fn read_uint(data: &[u8]) -> u64 {
+    data.iter()
+        .fold(0u64, |acc, &byte| (acc << 8) | byte as u64)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+fn rfind(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).rposition(|w| w == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opus_write::OggOpusWriter;
+
+    fn vorbis_comments(comments: &[&str]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&6u32.to_le_bytes());
+        data.extend_from_slice(b"vendor");
+        data.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+        for comment in comments {
+            data.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+            data.extend_from_slice(comment.as_bytes());
+        }
+        data
+    }
+
+    // ids are written without their leading zero bytes, sizes always take 8 bytes
+    fn element(id: u32, body: &[u8]) -> Vec<u8> {
+        let mut data: Vec<u8> = id
+            .to_be_bytes()
+            .into_iter()
+            .skip_while(|&byte| byte == 0)
+            .collect();
+        data.push(0x01);
+        data.extend_from_slice(&(body.len() as u64).to_be_bytes()[1..]);
+        data.extend_from_slice(body);
+        data
+    }
+
+    #[test]
+    fn ogg_length_comes_from_the_last_page() {
+        let mut writer = OggOpusWriter::new(1);
+        let mut file = writer.headers(2);
+        for _ in 0..150 {
+            file.extend(writer.push(vec![0xfc, 0xff, 0xfe], 960).unwrap_or_default());
+        }
+        file.extend(writer.finish());
+
+        let tail = &file[file.len().saturating_sub(TAIL_PROBE_SIZE as usize)..];
+        let metadata = probe_ogg(&file, tail);
+        assert_eq!(metadata.length, Some(3000));
+        // jukebox doesn't write any comments
+        assert_eq!(metadata.title, None);
+        assert_eq!(metadata.artist, None);
+    }
+
+    #[test]
+    fn ogg_tags_and_pre_skip() {
+        let mut head = Vec::new();
+        head.extend_from_slice(b"OpusHead");
+        head.extend_from_slice(&[1, 2]);
+        head.extend_from_slice(&4800u16.to_le_bytes());
+        head.extend_from_slice(b"OpusTags");
+        head.extend(vorbis_comments(&[
+            "title=Hoppípolla",
+            "ARTIST=Sigur Rós",
+            "no equals sign",
+            "ALBUM=Takk...",
+        ]));
+        let mut tail = b"OggS\0\x04".to_vec();
+        tail.extend_from_slice(&(48_000u64 * 2 + 4800).to_le_bytes());
+
+        assert_eq!(
+            probe_ogg(&head, &tail),
+            Metadata {
+                title: Some("Hoppípolla".to_owned()),
+                artist: Some("Sigur Rós".to_owned()),
+                length: Some(2000),
+            }
+        );
+    }
+
+    #[test]
+    fn ogg_comments_cut_short() {
+        let mut head = b"OpusTags".to_vec();
+        let comments = vorbis_comments(&["TITLE=Complete", "ARTIST=Cut short"]);
+        head.extend_from_slice(&comments[..comments.len() - 4]);
+        let metadata = probe_ogg(&head, &[]);
+        assert_eq!(metadata.title.as_deref(), Some("Complete"));
+        assert_eq!(metadata.artist, None);
+        assert_eq!(metadata.length, None);
+    }
+
+    #[test]
+    fn webm_info_and_tags() {
+        let info = [
+            element(TIMECODE_SCALE, &[0x0F, 0x42, 0x40]),
+            element(DURATION, &183_500f64.to_be_bytes()),
+            element(TITLE, b"Segment title"),
+        ]
+        .concat();
+        let tags = element(
+            TAG,
+            &[
+                element(
+                    SIMPLE_TAG,
+                    &[
+                        element(TAG_NAME, b"title"),
+                        element(TAG_STRING, b"Blue Monday"),
+                    ]
+                    .concat(),
+                ),
+                element(
+                    SIMPLE_TAG,
+                    &[
+                        element(TAG_NAME, b"ARTIST"),
+                        element(TAG_STRING, b"New Order"),
+                    ]
+                    .concat(),
+                ),
+            ]
+            .concat(),
+        );
+        // nothing after the first cluster is looked at
+        let cluster = element(
+            CLUSTER,
+            &[element(TAG_NAME, b"ARTIST"), element(TAG_STRING, b"Nobody")].concat(),
+        );
+        let mut head = element(0x1A45DFA3, &[]);
+        // live streams don't know the segment's size
+        head.extend_from_slice(&SEGMENT.to_be_bytes());
+        head.extend_from_slice(&[0x01, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+        head.extend(element(INFO, &info));
+        head.extend(element(TAGS, &tags));
+        head.extend(cluster);
+
+        assert_eq!(
+            probe_webm(&head),
+            Metadata {
+                title: Some("Blue Monday".to_owned()),
+                artist: Some("New Order".to_owned()),
+                length: Some(183_500),
+            }
+        );
+    }
+
+    #[test]
+    fn webm_duration_scales_with_the_timecode_scale() {
+        let info = [
+            element(TIMECODE_SCALE, &500_000u32.to_be_bytes()),
+            element(DURATION, &1000f32.to_be_bytes()),
+        ]
+        .concat();
+        let metadata = probe_webm(&element(INFO, &info));
+        assert_eq!(metadata.length, Some(500));
+        assert_eq!(metadata.title, None);
+
+        assert_eq!(probe_webm(&[]), Metadata::default());
+        assert_eq!(probe_webm(&[0x00; 16]), Metadata::default());
+    }
+}
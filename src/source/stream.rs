@@ -0,0 +1,101 @@
+use futures_util::{future::BoxFuture, FutureExt, TryStreamExt};
+use reqwest::{
+    header::{HeaderMap, CONTENT_LENGTH, CONTENT_TYPE},
+    Client,
+};
+use tokio_util::io::StreamReader;
+
+use super::{
+    http::{is_http_url, title_from_url},
+    open_ogg, AudioSourceManager, AudioTrack, Container, LoadResult, PacketStream, SourceError,
+    TrackInfo, STREAM_LENGTH,
+};
+
+/// Plays live ogg opus streams, such as icecast mounts. Packets are demuxed as they
+/// arrive instead of buffering the whole body.
+#[derive(Debug, Default)]
+pub struct StreamSourceManager {
+    client: Client,
+}
+
+impl StreamSourceManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn is_stream(headers: &HeaderMap) -> bool {
+        let is_ogg = headers
+            .get(CONTENT_TYPE)
+            .and_then(|mime| mime.to_str().ok())
+            .and_then(Container::from_mime)
+            == Some(Container::Ogg);
+        let is_icy = headers.keys().any(|name| name.as_str().starts_with("icy-"));
+        is_ogg && (is_icy || !headers.contains_key(CONTENT_LENGTH))
+    }
+}
+
+impl AudioSourceManager for StreamSourceManager {
+    fn name(&self) -> &'static str {
+        "stream"
+    }
+
+    fn identify(&self, identifier: &str) -> bool {
+        is_http_url(identifier)
+    }
+
+    fn load_item<'a>(
+        &'a self,
+        identifier: &'a str,
+    ) -> BoxFuture<'a, Result<LoadResult, SourceError>> {
+        async move {
+            let response = self
+                .client
+                .get(identifier)
+                .send()
+                .await?
+                .error_for_status()?;
+            if !Self::is_stream(response.headers()) {
+                return Ok(LoadResult::Empty);
+            }
+
+            let title = response
+                .headers()
+                .get("icy-name")
+                .and_then(|name| name.to_str().ok())
+                .map(str::to_owned)
+                .unwrap_or_else(|| title_from_url(response.url()));
+
+            Ok(LoadResult::Track(AudioTrack::new(TrackInfo {
+                identifier: identifier.to_owned(),
+                is_seekable: false,
+                author: "Unknown artist".to_owned(),
+                length: STREAM_LENGTH,
+                is_stream: true,
+                position: 0,
+                title,
+                uri: Some(identifier.to_owned()),
+                artwork_url: None,
+                isrc: None,
+                source_name: self.name().to_owned(),
+            })))
+        }
+        .boxed()
+    }
+
+    fn open_stream<'a>(
+        &'a self,
+        track: &'a AudioTrack,
+    ) -> BoxFuture<'a, Result<PacketStream, SourceError>> {
+        async move {
+            let response = self
+                .client
+                .get(&track.info.identifier)
+                .send()
+                .await?
+                .error_for_status()?;
+            let body = response.bytes_stream().map_err(std::io::Error::other);
+            Ok(open_ogg(StreamReader::new(Box::pin(body))))
+        }
+        .boxed()
+    }
+}
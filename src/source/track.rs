@@ -0,0 +1,261 @@
+use std::io::{Cursor, Read};
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+use serde::{Deserialize, Serialize};
+
+use super::SourceError;
+
+// lavaplayer's MessageOutput flag for messages that carry a version byte
+const TRACK_INFO_VERSIONED: u32 = 1;
+const TRACK_INFO_VERSION: u8 = 3;
+
+/// Length reported for tracks that never end, same as lavaplayer's `Long.MAX_VALUE`
+pub const STREAM_LENGTH: u64 = i64::MAX as u64;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct TrackInfo {
+    pub identifier: String,
+    pub is_seekable: bool,
+    pub author: String,
+    pub length: u64,
+    pub is_stream: bool,
+    pub position: u64,
+    pub title: String,
+    pub uri: Option<String>,
+    pub artwork_url: Option<String>,
+    pub isrc: Option<String>,
+    pub source_name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AudioTrack {
+    pub encoded: String,
+    pub info: TrackInfo,
+}
+
+impl AudioTrack {
+    pub fn new(info: TrackInfo) -> Self {
+        Self {
+            encoded: encode_track(&info),
+            info,
+        }
+    }
+}
+
+/// Encodes the track info the same way lavaplayer does, so that encoded tracks
+/// can be passed between jukebox and Lavalink clients unchanged.
+pub fn encode_track(info: &TrackInfo) -> String {
+    let mut body = Vec::new();
+    body.push(TRACK_INFO_VERSION);
+    write_utf(&mut body, &info.title);
+    write_utf(&mut body, &info.author);
+    body.write_u64::<NetworkEndian>(info.length).unwrap();
+    write_utf(&mut body, &info.identifier);
+    body.push(info.is_stream as u8);
+    write_nullable_utf(&mut body, info.uri.as_deref());
+    write_nullable_utf(&mut body, info.artwork_url.as_deref());
+    write_nullable_utf(&mut body, info.isrc.as_deref());
+    write_utf(&mut body, &info.source_name);
+    body.write_u64::<NetworkEndian>(info.position).unwrap();
+
+    let mut message = Vec::with_capacity(body.len() + 4);
+    message
+        .write_u32::<NetworkEndian>((TRACK_INFO_VERSIONED << 30) | body.len() as u32)
+        .unwrap();
+    message.extend_from_slice(&body);
+    BASE64_STANDARD.encode(message)
+}
+
+pub fn decode_track(encoded: &str) -> Result<TrackInfo, SourceError> {
+    let message = BASE64_STANDARD
+        .decode(encoded)
+        .map_err(|e| SourceError::InvalidTrack(e.to_string()))?;
+    let mut reader = Cursor::new(message);
+
+    let header = reader.read_u32::<NetworkEndian>()?;
+    let version = if (header >> 30) & TRACK_INFO_VERSIONED != 0 {
+        reader.read_u8()?
+    } else {
+        1
+    };
+
+    let title = read_utf(&mut reader)?;
+    let author = read_utf(&mut reader)?;
+    let length = reader.read_u64::<NetworkEndian>()?;
+    let identifier = read_utf(&mut reader)?;
+    let is_stream = reader.read_u8()? != 0;
+    let uri = if version >= 2 {
+        read_nullable_utf(&mut reader)?
+    } else {
+        None
+    };
+    let (artwork_url, isrc) = if version >= 3 {
+        (
+            read_nullable_utf(&mut reader)?,
+            read_nullable_utf(&mut reader)?,
+        )
+    } else {
+        (None, None)
+    };
+    let source_name = read_utf(&mut reader)?;
+
+    // source specific fields sit between the source name and the position, none of
+    // the built in sources write any so the position is always the last 8 bytes
+    let message = reader.into_inner();
+    let position = match message.len().checked_sub(8) {
+        Some(start) => Cursor::new(&message[start..]).read_u64::<NetworkEndian>()?,
+        None => 0,
+    };
+
+    Ok(TrackInfo {
+        identifier,
+        is_seekable: !is_stream,
+        author,
+        length,
+        is_stream,
+        position,
+        title,
+        uri,
+        artwork_url,
+        isrc,
+        source_name,
+    })
+}
+
+// the length is a u16, longer strings are cut short at a char boundary rather
+// than wrapping the length
+fn write_utf(buf: &mut Vec<u8>, s: &str) {
+    let mut len = s.len().min(u16::MAX as usize);
+    while !s.is_char_boundary(len) {
+        len -= 1;
+    }
+    buf.write_u16::<NetworkEndian>(len as u16).unwrap();
+    buf.extend_from_slice(&s.as_bytes()[..len]);
+}
+
+fn write_nullable_utf(buf: &mut Vec<u8>, s: Option<&str>) {
+    match s {
+        Some(s) => {
+            buf.push(1);
+            write_utf(buf, s);
+        }
+        None => buf.push(0),
+    }
+}
+
+fn read_utf(reader: &mut impl Read) -> Result<String, SourceError> {
+    let len = reader.read_u16::<NetworkEndian>()?;
+    let mut buf = vec![0u8; len as usize];
+    reader.read_exact(&mut buf)?;
+    String::from_utf8(buf).map_err(|e| SourceError::InvalidTrack(e.to_string()))
+}
+
+fn read_nullable_utf(reader: &mut impl Read) -> Result<Option<String>, SourceError> {
+    match reader.read_u8()? {
+        0 => Ok(None),
+        _ => read_utf(reader).map(Some),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the example from Lavalink's docs, also in the golden files. version 2, so
+    // no artwork or isrc
+    const LAVALINK_TRACK: &str = "QAAAjQIAJVJpY2sgQXN0bGV5IC0gTmV2ZXIgR29ubmEgR2l2ZSBZb3UgVXAADlJpY2tBc3RsZXlWRVZPAAAAAAADPCAAC2RRdzR3OVdnWGNRAAEAK2h0dHBzOi8vd3d3LnlvdXR1YmUuY29tL3dhdGNoP3Y9ZFF3NHc5V2dYY1EAB3lvdXR1YmUAAAAAAAAAAA==";
+
+    fn info() -> TrackInfo {
+        TrackInfo {
+            identifier: "/music/sígur rós.ogg".to_owned(),
+            is_seekable: true,
+            author: "Sigur Rós".to_owned(),
+            length: 421_000,
+            is_stream: false,
+            position: 12_345,
+            title: "Hoppípolla 🎵".to_owned(),
+            uri: Some("file:///music/sígur rós.ogg".to_owned()),
+            artwork_url: None,
+            isrc: Some("GBAYE0601498".to_owned()),
+            source_name: "local".to_owned(),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let info = info();
+        assert_eq!(decode_track(&encode_track(&info)).unwrap(), info);
+
+        let stream = TrackInfo {
+            is_seekable: false,
+            is_stream: true,
+            length: STREAM_LENGTH,
+            position: 0,
+            uri: None,
+            isrc: None,
+            ..info
+        };
+        assert_eq!(decode_track(&encode_track(&stream)).unwrap(), stream);
+    }
+
+    #[test]
+    fn decodes_lavalink_tracks() {
+        let track = decode_track(LAVALINK_TRACK).unwrap();
+        assert_eq!(track.title, "Rick Astley - Never Gonna Give You Up");
+        assert_eq!(track.author, "RickAstleyVEVO");
+        assert_eq!(track.length, 212_000);
+        assert_eq!(track.identifier, "dQw4w9WgXcQ");
+        assert!(!track.is_stream);
+        assert!(track.is_seekable);
+        assert_eq!(
+            track.uri.as_deref(),
+            Some("https://www.youtube.com/watch?v=dQw4w9WgXcQ")
+        );
+        assert_eq!(track.artwork_url, None);
+        assert_eq!(track.isrc, None);
+        assert_eq!(track.source_name, "youtube");
+        assert_eq!(track.position, 0);
+
+        // re-encoded as version 3, which still holds everything
+        assert_eq!(decode_track(&encode_track(&track)).unwrap(), track);
+    }
+
+    // used to wrap the u16 length and write garbage
+    #[test]
+    fn long_strings_are_cut_short() {
+        let info = TrackInfo {
+            title: "é".repeat(u16::MAX as usize),
+            ..info()
+        };
+        let decoded = decode_track(&encode_track(&info)).unwrap();
+        assert_eq!(decoded.title, "é".repeat(u16::MAX as usize / 2));
+        assert_eq!(decoded.author, info.author);
+        assert_eq!(decoded.position, info.position);
+    }
+
+    #[test]
+    fn rejects_malformed_tracks() {
+        assert!(matches!(
+            decode_track("not base64!"),
+            Err(SourceError::InvalidTrack(_))
+        ));
+        assert!(decode_track("").is_err());
+
+        // everything up to the source name is needed, the position is optional
+        let message = BASE64_STANDARD.decode(encode_track(&info())).unwrap();
+        for len in 0..message.len() - 8 {
+            let truncated = BASE64_STANDARD.encode(&message[..len]);
+            assert!(decode_track(&truncated).is_err(), "decoded {} bytes", len);
+        }
+
+        let mut message = BASE64_STANDARD.decode(LAVALINK_TRACK).unwrap();
+        // first byte of the title
+        message[7] = 0xff;
+        assert!(matches!(
+            decode_track(&BASE64_STANDARD.encode(message)),
+            Err(SourceError::InvalidTrack(_))
+        ));
+    }
+}
@@ -3,7 +3,6 @@ use std::fmt::Debug;
 use bytes::Bytes;
 use futures_util::{stream::SplitStream, StreamExt};
 use thiserror::Error;
-use tracing::error;
 
 #[derive(Error, Debug)]
 pub enum ReadMessageError {
@@ -18,17 +17,17 @@ pub enum ReadMessageError {
 }
 
 pub trait IntoRawData {
-    fn as_bytes(self) -> Bytes;
+    fn into_bytes(self) -> Bytes;
 }
 
 impl IntoRawData for axum::extract::ws::Message {
-    fn as_bytes(self) -> Bytes {
+    fn into_bytes(self) -> Bytes {
         self.into_data()
     }
 }
 
 impl IntoRawData for tokio_tungstenite::tungstenite::Message {
-    fn as_bytes(self) -> Bytes {
+    fn into_bytes(self) -> Bytes {
         self.into_data()
     }
 }
@@ -42,7 +41,7 @@ pub async fn parse_msg<
 ) -> Result<R, ReadMessageError> {
     match msg {
         Some(Ok(msg)) => {
-            let bytes = msg.as_bytes();
+            let bytes = msg.into_bytes();
             let result = serde_json::from_slice(&bytes).map_err(|_| {
                 ReadMessageError::SerializationError(String::from_utf8_lossy(&bytes).into_owned())
             })?;
@@ -63,7 +62,7 @@ where
 {
     match rx.next().await {
         Some(Ok(msg)) => {
-            let bytes = msg.as_bytes();
+            let bytes = msg.into_bytes();
             let result = serde_json::from_slice(&bytes).map_err(|_| {
                 ReadMessageError::SerializationError(String::from_utf8_lossy(&bytes).into_owned())
             })?;
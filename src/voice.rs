@@ -1,34 +1,57 @@
+mod dave;
 mod gateway;
 mod payloads;
+mod receive;
+mod roster;
+mod rtcp;
+mod scheduler;
+mod sender;
+mod speaking;
 mod udp;
 
 use std::{
     io::ErrorKind,
-    net::SocketAddr,
-    sync::Arc,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::Duration,
 };
 
 use anyhow::Result;
-use crypto_secretbox::{KeyInit, XSalsa20Poly1305};
 use derivative::Derivative;
 use futures_util::StreamExt;
-use payloads::{DiscordPayload, SelectProtocol, SelectProtocolData, Speaking};
+use payloads::{DiscordPayload, SelectProtocol, SelectProtocolData};
 use thiserror::Error;
 use tracing::{error, info, trace};
 
 use tokio::{
-    fs::File,
-    sync::mpsc::{unbounded_channel, Sender, UnboundedReceiver, UnboundedSender},
-    time,
+    sync::{
+        broadcast,
+        mpsc::{channel, unbounded_channel, Sender, UnboundedSender},
+    },
+    task::JoinHandle,
 };
 
 use crate::{
-    client::payloads::VoiceUpdate,
-    webm_parse::WebmStream,
+    client::payloads::{SpeakingFlags, VoiceUpdate},
+    config::VoiceConfiguration,
+    crypto::{Cipher, EncryptionMode},
+    source::{PacketStream, SourceError},
 };
 
-use gateway::VoiceGateway;
-use udp::{UDPMessage, VoiceUDP};
+pub use receive::{VoicePacket, VoiceReceiver};
+pub use roster::{ChannelUser, VoiceRoster};
+pub use rtcp::RtcpStats;
+pub use udp::IpDiscoveryError;
+
+use gateway::{GatewayState, VoiceGateway};
+use rtcp::RtcpState;
+use scheduler::{Frame, FrameScheduler, FrameSource};
+use sender::SharedSender;
+use speaking::SpeakingState;
+use udp::{SendHandle, UDPMessage, VoiceUDP};
 
 #[derive(Error, Debug)]
 pub enum VoiceError {
@@ -42,11 +65,106 @@ pub enum VoiceError {
     SendError(#[from] tokio::sync::mpsc::error::SendError<DiscordPayload>),
     #[error("discord violated protocol: ")]
     UnexpectedProtocolError(String),
+    #[error("ip discovery failed: {0}")]
+    IpDiscoveryError(#[from] IpDiscoveryError),
+    #[error("none of the configured encryption modes are supported, discord offered {0:?}")]
+    NoEncryptionMode(Vec<EncryptionMode>),
 
     #[error("discord sent invalid payload: {0}")]
     InvalidPayloadError(#[from] crate::utils::ReadMessageError),
 }
 
+/// Things happening to a voice connection that the player needs to know about
+#[derive(Debug, Clone)]
+pub enum VoiceEvent {
+    /// The voice gateway closed and the session could not be resumed. With
+    /// `reconnect` the connection should be identified again from scratch,
+    /// otherwise discord doesn't want us back.
+    GatewayClosed {
+        code: u16,
+        reason: String,
+        by_remote: bool,
+        reconnect: bool,
+    },
+    /// Audio stopped making it to the voice server, the connection has to be
+    /// negotiated again
+    UdpTimeout,
+    /// The last other user left the voice channel
+    ChannelEmpty,
+    /// Someone joined the empty voice channel
+    ChannelOccupied,
+    /// The track ran out or reached its end time, rather than being stopped
+    TrackFinished,
+    /// The track's source has delivered nothing for `threshold`
+    TrackStuck { threshold: Duration },
+    /// The track's source broke off with an error, after the audio before it
+    TrackFailed(Arc<SourceError>),
+}
+
+const FRAME_DURATION: Duration = Duration::from_millis(20);
+
+/// Progress of the track currently being sent, shared with the playback task
+#[derive(Debug, Default)]
+pub struct PlaybackState {
+    // frames since the start of the track, including any that were skipped over
+    frames: AtomicU64,
+    paused: AtomicBool,
+    finished: AtomicBool,
+    sent: AtomicU64,
+    nulled: AtomicU64,
+    deficit: AtomicU64,
+    // what ended the source early, if anything
+    error: Mutex<Option<SourceError>>,
+}
+
+/// Frame counters of a track, mirroring lavalink's frame stats
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FrameStats {
+    /// Audio frames sent
+    pub sent: u64,
+    /// Slots where the source had no frame ready
+    pub nulled: u64,
+    /// Slots missed entirely because the playback task ran late
+    pub deficit: u64,
+}
+
+impl PlaybackState {
+    pub fn position(&self) -> Duration {
+        FRAME_DURATION * self.frames.load(Ordering::Relaxed) as u32
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::Relaxed)
+    }
+
+    pub fn frame_stats(&self) -> FrameStats {
+        FrameStats {
+            sent: self.sent.load(Ordering::Relaxed),
+            nulled: self.nulled.load(Ordering::Relaxed),
+            deficit: self.deficit.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Playback {
+    state: Arc<PlaybackState>,
+    task: JoinHandle<()>,
+}
+
+impl Drop for Playback {
+    fn drop(&mut self) {
+        // takes the track off the shared sender, if it's on there
+        self.state.finished.store(true, Ordering::Relaxed);
+        // the demux task exits on its own once the packet receiver is gone
+        self.task.abort();
+    }
+}
+
 // Essentially a handle to both the websocket-based voice gateway and the udp
 // socket for sending audio
 #[derive(Derivative)]
@@ -54,25 +172,48 @@ pub enum VoiceError {
 pub struct VoiceManager {
     ssrc: u32,
     #[derivative(Debug = "ignore")]
-    from_gateway_rx: UnboundedReceiver<DiscordPayload>,
+    udp_tx: Arc<Sender<UDPMessage>>,
+    gateway_state: Arc<GatewayState>,
+    receiver: Arc<VoiceReceiver>,
+    roster: Arc<VoiceRoster>,
+    rtcp: Arc<RtcpState>,
+    speaking: Arc<SpeakingState>,
+    prebuffer_frames: usize,
+    stuck_threshold: Duration,
     #[derivative(Debug = "ignore")]
-    to_gateway_tx: UnboundedSender<DiscordPayload>,
+    events_tx: UnboundedSender<VoiceEvent>,
+    // set when frames are sent by the shared sender thread
     #[derivative(Debug = "ignore")]
-    udp_tx: Arc<Sender<UDPMessage>>,
+    shared_sender: Option<SendHandle>,
+    playback: Option<Playback>,
+    paused: bool,
 }
 
 // i gotta clean this up
 impl VoiceManager {
     /// Initializes the voice gateway and UDP connection. The returned connection is fully
-    /// authenticated and ready to send and receive audio.
-    #[tracing::instrument]
+    /// authenticated and ready to send and receive audio. Anything that happens to
+    /// the connection afterwards is reported through `events_tx`.
+    #[tracing::instrument(skip(config, events_tx))]
     pub async fn new(
         user_id: impl Into<String> + std::fmt::Debug,
         voice_update_payload: VoiceUpdate,
+        config: &VoiceConfiguration,
+        events_tx: UnboundedSender<VoiceEvent>,
     ) -> Result<Self, VoiceError> {
         let (to_manager_tx, mut from_gateway_rx) = unbounded_channel();
-        let to_gateway_tx =
-            VoiceGateway::connect(user_id.into(), voice_update_payload, to_manager_tx).await?;
+        let receiver = Arc::new(VoiceReceiver::new());
+        let roster = Arc::new(VoiceRoster::new());
+        let (to_gateway_tx, gateway_state) = VoiceGateway::connect(
+            user_id.into(),
+            voice_update_payload,
+            config,
+            to_manager_tx,
+            events_tx.clone(),
+            receiver.clone(),
+            roster.clone(),
+        )
+        .await?;
 
         let ready_payload = match from_gateway_rx.recv().await {
             Some(ready_payload) => match ready_payload {
@@ -90,22 +231,29 @@ impl VoiceManager {
             }
         };
 
-        let dest_addr: SocketAddr = format!("{}:{}", ready_payload.ip, ready_payload.port)
-            .parse()
-            .map_err(|e| {
-                std::io::Error::new(
-                    ErrorKind::InvalidData,
-                    format!("discord did not provide valid udp address {}", e),
-                )
-            })?;
+        let dest_ip: IpAddr = ready_payload.ip.parse().map_err(|e| {
+            std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("discord did not provide valid udp address {}", e),
+            )
+        })?;
+        let dest_addr = SocketAddr::new(dest_ip, ready_payload.port);
 
-        let mode = ready_payload
-            .modes
-            .into_iter()
-            .min()
-            .expect("Modes should not be empty");
+        let mode = EncryptionMode::negotiate(&config.encryption_modes, &ready_payload.modes)
+            .ok_or_else(|| VoiceError::NoEncryptionMode(ready_payload.modes.clone()))?;
+        info!("Negotiated encryption mode {:?}", mode);
 
-        let (mut udp, udp_tx) = VoiceUDP::connect(ready_payload.ssrc, dest_addr, mode).await?;
+        let rtcp = Arc::new(RtcpState::new());
+        let (udp, udp_tx) = VoiceUDP::connect(
+            ready_payload.ssrc,
+            dest_addr,
+            mode,
+            receiver.clone(),
+            rtcp.clone(),
+            events_tx.clone(),
+            config,
+        )
+        .await?;
 
         let test_payload = DiscordPayload::SelectProtocol(SelectProtocol {
             protocol: "udp".to_string(),
@@ -136,82 +284,195 @@ impl VoiceManager {
             }
         };
 
-        *udp.cipher_mut() = Some(
-            XSalsa20Poly1305::new_from_slice(&sd.secret_key)
-                .expect("32 bytes should be correct key size"),
+        let send_handle = udp.start(
+            Cipher::new(sd.mode, &sd.secret_key).expect("32 bytes should be correct key size"),
         );
-        tokio::spawn(async move {
-            if let Err(e) = udp.run().await {
-                error!("{}", e);
-            }
-        });
 
+        // speaking is all we still tell the gateway from here on
+        let speaking = Arc::new(SpeakingState::new(ready_payload.ssrc, to_gateway_tx));
         Ok(Self {
             ssrc: ready_payload.ssrc,
-            from_gateway_rx,
-            to_gateway_tx,
             udp_tx: Arc::new(udp_tx),
+            gateway_state,
+            receiver,
+            roster,
+            rtcp,
+            speaking,
+            prebuffer_frames: config.prebuffer_frames,
+            stuck_threshold: Duration::from_millis(config.track_stuck_threshold),
+            events_tx,
+            shared_sender: config.shared_sender.then_some(send_handle),
+            playback: None,
+            paused: false,
         })
     }
 
-    #[tracing::instrument]
-    pub async fn play_audio(&self, path: impl Into<String> + std::fmt::Debug) -> Result<()> {
-        let path: String = path.into();
-        self.to_gateway_tx.send(DiscordPayload::Speaking(Speaking {
-            speaking: 1,
-            delay: Some(0),
-            user_id: None,
-            ssrc: self.ssrc,
-        }))?;
+    /// Starts sending the packets of `stream` to discord, one every 20ms, replacing
+    /// whatever was playing before. Packets before `start` are skipped, and playback
+    /// stops once `end` is reached. Discord is only told we're speaking once the
+    /// first few frames are buffered.
+    #[tracing::instrument(skip(self, stream))]
+    pub async fn play_stream(
+        &mut self,
+        stream: PacketStream,
+        start: Duration,
+        end: Option<Duration>,
+    ) -> Result<()> {
+        self.stop();
 
-        info!("started playing audio");
         let weak_udp_tx = Arc::downgrade(&self.udp_tx);
+        let prebuffer_frames = self.prebuffer_frames;
+
+        let skipped_frames = (start.as_millis() / FRAME_DURATION.as_millis()) as u64;
+        let end_frames = end.map(|end| (end.as_millis() / FRAME_DURATION.as_millis()) as u64);
+        let state = Arc::new(PlaybackState {
+            frames: AtomicU64::new(skipped_frames),
+            paused: AtomicBool::new(self.paused),
+            ..Default::default()
+        });
 
         // Create a bounded channel for buffering audio packets
-        let (packet_tx, mut packet_rx) = tokio::sync::mpsc::channel(32); // Buffer size of 32 packets
+        let (packet_tx, packet_rx) = channel(32.max(prebuffer_frames));
 
-        // Spawn a separate task for reading from OggStream
+        // Spawn a separate task for demuxing the source
+        let demux_state = state.clone();
         tokio::spawn(async move {
-            let f = File::open(path).await.unwrap();
-            //let mut stream = OggStream::new(f);
-            let mut stream = WebmStream::new(f);
-
+            let mut stream = stream;
+            let mut skipped = 0;
             while let Some(packet) = stream.next().await {
-                if packet_tx.send(packet).await.is_err() {
-                    // Channel closed, receiver dropped
-                    break;
+                match packet {
+                    // reported once the frames before it are played
+                    Err(e) => {
+                        *demux_state.error.lock().unwrap() = Some(e);
+                        break;
+                    }
+                    Ok(_) if skipped < skipped_frames => skipped += 1,
+                    Ok(packet) => {
+                        if packet_tx.send(packet).await.is_err() {
+                            // Channel closed, receiver dropped
+                            break;
+                        }
+                    }
                 }
             }
         });
 
         // Main playback loop
-        tokio::spawn(async move {
-            let mut interval = time::interval(time::Duration::from_millis(20));
-
-            loop {
-                interval.tick().await;
-
-                if let Some(udp_tx) = weak_udp_tx.upgrade() {
-                    match packet_rx.recv().await {
-                        Some(packet) => {
-                            if let Err(e) = udp_tx.send(UDPMessage::Audio(packet)).await {
-                                error!("error sending audio: {}", e);
-                                break;
-                            }
-                        }
-                        None => {
-                            // Channel closed, no more packets
-                            _ = udp_tx.send(UDPMessage::Silence).await;
-                            break;
-                        }
-                    }
-                } else {
-                    break;
-                }
+        let mut source = FrameSource::new(
+            state.clone(),
+            self.speaking.clone(),
+            packet_rx,
+            end_frames,
+            self.stuck_threshold,
+            self.events_tx.clone(),
+        );
+        let shared_sender = self.shared_sender.clone();
+        let task = tokio::spawn(async move {
+            source.prebuffer(prebuffer_frames).await;
+            info!("started playing audio");
+            match shared_sender {
+                Some(handle) => SharedSender::get().add(source, handle),
+                None => send_frames(source, weak_udp_tx).await,
             }
-            info!("finished playing audio");
         });
 
+        self.playback = Some(Playback { state, task });
         Ok(())
     }
+
+    /// Stops the current track, if any
+    pub fn stop(&mut self) {
+        self.playback = None;
+        self.speaking.stop();
+    }
+
+    pub fn speaking_flags(&self) -> SpeakingFlags {
+        self.speaking.flags()
+    }
+
+    /// Sets how discord shows and mixes our audio, takes effect immediately
+    pub fn set_speaking_flags(&self, flags: SpeakingFlags) {
+        self.speaking.set_flags(flags);
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        if let Some(playback) = &self.playback {
+            playback.state.paused.store(paused, Ordering::Relaxed);
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Whether a track is loaded and hasn't run out, paused or not
+    pub fn is_playing(&self) -> bool {
+        self.playback
+            .as_ref()
+            .is_some_and(|playback| !playback.state.is_finished())
+    }
+
+    /// Audio sent by everyone else in the channel. The stream ends when this
+    /// connection is dropped, e.g. when the player moves to another voice server.
+    pub fn subscribe(&self) -> broadcast::Receiver<VoicePacket> {
+        self.receiver.subscribe()
+    }
+
+    /// Everyone else in the voice channel
+    pub fn users(&self) -> Vec<ChannelUser> {
+        self.roster.users()
+    }
+
+    /// Round trip time of the last acknowledged gateway heartbeat
+    pub fn ping(&self) -> Option<Duration> {
+        self.gateway_state.ping()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.gateway_state.is_connected()
+    }
+
+    /// Frame counters of the current or last track
+    pub fn frame_stats(&self) -> Option<FrameStats> {
+        self.playback
+            .as_ref()
+            .map(|playback| playback.state.frame_stats())
+    }
+
+    /// Packet loss and jitter of our audio as reported by discord over RTCP
+    pub fn rtcp_stats(&self) -> Option<RtcpStats> {
+        self.rtcp.stats()
+    }
+
+    pub fn position(&self) -> Duration {
+        self.playback
+            .as_ref()
+            .map_or(Duration::ZERO, |playback| playback.state.position())
+    }
+}
+
+// the playback loop proper, runs until the track ends or the connection is gone
+async fn send_frames(mut source: FrameSource, udp_tx: Weak<Sender<UDPMessage>>) {
+    let mut scheduler = FrameScheduler::new();
+    loop {
+        let missed = scheduler.tick().await;
+        let Some(udp_tx) = udp_tx.upgrade() else {
+            break;
+        };
+        let msg = match source.next(missed) {
+            Frame::Audio(packet) => UDPMessage::Audio(packet),
+            Frame::Silence => UDPMessage::Silence,
+            Frame::Idle => continue,
+            Frame::End => {
+                _ = udp_tx.send(UDPMessage::Silence).await;
+                break;
+            }
+        };
+        if let Err(e) = udp_tx.send(msg).await {
+            error!("error sending audio: {}", e);
+            break;
+        }
+    }
+    source.finish();
 }
@@ -0,0 +1,15 @@
+//! DAVE, discord's end to end encryption for voice. Everyone in the call is in
+//! an MLS group whose membership the voice server proposes over the gateway,
+//! and every epoch of the group gives each user a key for their audio frames.
+
+// frames only ever pass through when built without DAVE
+#[cfg_attr(not(feature = "dave"), allow(dead_code))]
+mod frame;
+#[cfg(feature = "dave")]
+mod mls;
+#[cfg(feature = "dave")]
+mod session;
+
+pub use frame::FrameKeys;
+#[cfg(feature = "dave")]
+pub(super) use session::{DaveSession, PROTOCOL_VERSION};
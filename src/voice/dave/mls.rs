@@ -0,0 +1,511 @@
+//! The MLS (RFC 9420) side of DAVE, on top of openmls: one ciphersuite,
+//! handshake messages in the clear, add and remove proposals from the voice
+//! server, and the secrets every member's media keys start from
+
+use std::collections::HashMap;
+
+use derivative::Derivative;
+use openmls::{
+    framing::{MlsMessageBodyOut, MlsMessageIn, ProcessedMessageContent},
+    group::{
+        MlsGroup, MlsGroupCreateConfig, MlsGroupJoinConfig, ProcessMessageError, StageCommitError,
+        StagedWelcome, PURE_PLAINTEXT_WIRE_FORMAT_POLICY,
+    },
+    prelude::tls_codec::{DeserializeBytes, Serialize, VLBytes},
+    prelude::{
+        hash_ref::ProposalRef, BasicCredential, Capabilities, Ciphersuite, CredentialType,
+        CredentialWithKey, Extension, Extensions, ExternalSender, GroupId, KeyPackage, Lifetime,
+        OpenMlsProvider, ProtocolVersion, Welcome,
+    },
+};
+use openmls_basic_credential::SignatureKeyPair;
+use openmls_rust_crypto::OpenMlsRustCrypto;
+
+use super::session::DaveError;
+
+type Result<T> = std::result::Result<T, DaveError>;
+
+/// The only ciphersuite of DAVE protocol version 1
+const CIPHERSUITE: Ciphersuite = Ciphersuite::MLS_128_DHKEMP256_AES128GCM_SHA256_P256;
+const MEDIA_KEY_LABEL: &str = "Discord Secure Frames v0";
+const MEDIA_KEY_LEN: usize = 16;
+// proposals either add to the ones pending or take some of them back
+const PROPOSALS_APPEND: u8 = 0;
+const PROPOSALS_REVOKE: u8 = 1;
+
+fn malformed(e: impl std::fmt::Display) -> DaveError {
+    DaveError::Malformed(e.to_string())
+}
+
+fn rejected(e: impl std::fmt::Display) -> DaveError {
+    DaveError::Rejected(e.to_string())
+}
+
+fn capabilities() -> Capabilities {
+    Capabilities::builder()
+        .versions(vec![ProtocolVersion::Mls10])
+        .ciphersuites(vec![CIPHERSUITE])
+        .extensions(vec![])
+        .proposals(vec![])
+        .credentials(vec![CredentialType::Basic])
+        .build()
+}
+
+// credentials hold the user id, big endian
+fn user_id(credential: &[u8]) -> Option<u64> {
+    Some(u64::from_be_bytes(credential.try_into().ok()?))
+}
+
+/// A commit of ours, with the welcome for anyone it adds
+#[derive(Debug)]
+pub(super) struct Commit {
+    pub message: Vec<u8>,
+    pub welcome: Option<Vec<u8>>,
+}
+
+/// Us in a DAVE group: our keys, and the group once there is one. Starting over
+/// means making a new one, which throws away every key.
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub(super) struct Member {
+    user_id: u64,
+    #[derivative(Debug = "ignore")]
+    provider: OpenMlsRustCrypto,
+    #[derivative(Debug = "ignore")]
+    signer: SignatureKeyPair,
+    #[derivative(Debug = "ignore")]
+    credential: CredentialWithKey,
+    #[derivative(Debug = "ignore")]
+    external_sender: Option<ExternalSender>,
+    // the group we're in, or one with just us until someone adds us to theirs
+    #[derivative(Debug = "ignore")]
+    group: Option<MlsGroup>,
+    joined: bool,
+}
+
+impl Member {
+    pub fn new(user_id: u64) -> Self {
+        let signer = SignatureKeyPair::new(CIPHERSUITE.signature_algorithm())
+            .expect("p256 keys can always be generated");
+        let credential = CredentialWithKey {
+            credential: BasicCredential::new(user_id.to_be_bytes().to_vec()).into(),
+            signature_key: signer.public().into(),
+        };
+        Self {
+            user_id,
+            provider: OpenMlsRustCrypto::default(),
+            signer,
+            credential,
+            external_sender: None,
+            group: None,
+            joined: false,
+        }
+    }
+
+    pub fn user_id(&self) -> u64 {
+        self.user_id
+    }
+
+    pub fn joined(&self) -> bool {
+        self.joined
+    }
+
+    pub fn epoch(&self) -> Option<u64> {
+        self.group.as_ref().map(|group| group.epoch().as_u64())
+    }
+
+    /// A new key package, whoever adds us to their group needs it
+    pub fn key_package(&mut self) -> Result<Vec<u8>> {
+        let bundle = KeyPackage::builder()
+            .key_package_extensions(Extensions::empty())
+            .leaf_node_capabilities(capabilities())
+            .key_package_lifetime(Lifetime::init(0, u64::MAX))
+            .build(
+                CIPHERSUITE,
+                &self.provider,
+                &self.signer,
+                self.credential.clone(),
+            )
+            .map_err(rejected)?;
+        bundle
+            .key_package()
+            .tls_serialize_detached()
+            .map_err(malformed)
+    }
+
+    /// Starts a group with just us, which the voice server's proposals go to
+    /// until we join someone else's
+    pub fn create_group(&mut self, channel_id: u64, external_sender: &[u8]) -> Result<()> {
+        let external_sender =
+            ExternalSender::tls_deserialize_exact_bytes(external_sender).map_err(malformed)?;
+        let config = MlsGroupCreateConfig::builder()
+            .with_group_context_extensions(
+                Extensions::single(Extension::ExternalSenders(vec![external_sender.clone()]))
+                    .map_err(malformed)?,
+            )
+            .ciphersuite(CIPHERSUITE)
+            .capabilities(capabilities())
+            .use_ratchet_tree_extension(true)
+            .wire_format_policy(PURE_PLAINTEXT_WIRE_FORMAT_POLICY)
+            .build();
+        self.delete_group();
+        let group = MlsGroup::new_with_group_id(
+            &self.provider,
+            &self.signer,
+            &config,
+            GroupId::from_slice(&channel_id.to_be_bytes()),
+            self.credential.clone(),
+        )
+        .map_err(rejected)?;
+        self.external_sender = Some(external_sender);
+        self.group = Some(group);
+        self.joined = false;
+        Ok(())
+    }
+
+    /// Queues up or revokes the voice server's proposals and commits whatever
+    /// is left. `None` if nothing is.
+    pub fn handle_proposals(&mut self, payload: &[u8]) -> Result<Option<Commit>> {
+        let group = self
+            .group
+            .as_mut()
+            .ok_or(DaveError::Rejected("no group yet".to_owned()))?;
+        let (&operation, proposals) = payload
+            .split_first()
+            .ok_or(DaveError::Malformed("empty proposals".to_owned()))?;
+        let proposals: Vec<u8> = VLBytes::tls_deserialize_exact_bytes(proposals)
+            .map_err(malformed)?
+            .into();
+        let mut rest = proposals.as_slice();
+
+        match operation {
+            PROPOSALS_APPEND => {
+                while !rest.is_empty() {
+                    let (message, remaining) =
+                        MlsMessageIn::tls_deserialize_bytes(rest).map_err(malformed)?;
+                    rest = remaining;
+                    let message = message.try_into_protocol_message().map_err(malformed)?;
+                    let processed = group
+                        .process_message(&self.provider, message)
+                        .map_err(rejected)?;
+                    let ProcessedMessageContent::ProposalMessage(proposal) =
+                        processed.into_content()
+                    else {
+                        return Err(DaveError::Rejected("not a proposal".to_owned()));
+                    };
+                    group
+                        .store_pending_proposal(self.provider.storage(), *proposal)
+                        .map_err(rejected)?;
+                }
+            }
+            PROPOSALS_REVOKE => {
+                while !rest.is_empty() {
+                    let (reference, remaining) =
+                        ProposalRef::tls_deserialize_bytes(rest).map_err(malformed)?;
+                    rest = remaining;
+                    group
+                        .remove_pending_proposal(self.provider.storage(), &reference)
+                        .map_err(rejected)?;
+                }
+            }
+            _ => {
+                return Err(DaveError::Malformed(
+                    "unknown proposals operation".to_owned(),
+                ))
+            }
+        }
+
+        // an older commit of ours covers fewer proposals, it's replaced
+        group
+            .clear_pending_commit(self.provider.storage())
+            .map_err(rejected)?;
+        if group.pending_proposals().next().is_none() {
+            return Ok(None);
+        }
+        let (message, welcome, _) = group
+            .commit_to_pending_proposals(&self.provider, &self.signer)
+            .map_err(rejected)?;
+        let welcome = match welcome.as_ref().map(|welcome| welcome.body()) {
+            // the voice server wants the welcome without the message around it
+            Some(MlsMessageBodyOut::Welcome(welcome)) => {
+                Some(welcome.tls_serialize_detached().map_err(malformed)?)
+            }
+            _ => None,
+        };
+        Ok(Some(Commit {
+            message: message.tls_serialize_detached().map_err(malformed)?,
+            welcome,
+        }))
+    }
+
+    /// Moves to the epoch of the commit we made last
+    pub fn merge_own_commit(&mut self) -> Result<()> {
+        let group = self
+            .group
+            .as_mut()
+            .ok_or(DaveError::Rejected("no group yet".to_owned()))?;
+        group
+            .merge_pending_commit(&self.provider)
+            .map_err(rejected)?;
+        self.joined = true;
+        Ok(())
+    }
+
+    /// Moves to the epoch of someone else's commit
+    pub fn process_commit(&mut self, commit: &[u8]) -> Result<()> {
+        let group = self
+            .group
+            .as_mut()
+            .filter(|_| self.joined)
+            .ok_or(DaveError::Rejected("not in a group".to_owned()))?;
+        let message = MlsMessageIn::tls_deserialize_exact_bytes(commit)
+            .map_err(malformed)?
+            .try_into_protocol_message()
+            .map_err(malformed)?;
+        match group.process_message(&self.provider, message) {
+            Ok(processed) => match processed.into_content() {
+                ProcessedMessageContent::StagedCommitMessage(commit) => group
+                    .merge_staged_commit(&self.provider, *commit)
+                    .map_err(rejected),
+                _ => Err(DaveError::Rejected("not a commit".to_owned())),
+            },
+            // ours after all
+            Err(ProcessMessageError::InvalidCommit(StageCommitError::OwnCommit)) => {
+                group.merge_pending_commit(&self.provider).map_err(rejected)
+            }
+            Err(e) => Err(rejected(e)),
+        }
+    }
+
+    /// Joins the group a welcome is for, which has to be the voice server's
+    pub fn join(&mut self, welcome: &[u8]) -> Result<()> {
+        let welcome = Welcome::tls_deserialize_exact_bytes(welcome).map_err(malformed)?;
+        let config = MlsGroupJoinConfig::builder()
+            .use_ratchet_tree_extension(true)
+            .wire_format_policy(PURE_PLAINTEXT_WIRE_FORMAT_POLICY)
+            .build();
+        let staged = StagedWelcome::build_from_welcome(&self.provider, &config, welcome)
+            .map_err(rejected)?
+            .replace_old_group()
+            .build()
+            .map_err(rejected)?;
+        let external_senders = staged.group_context().extensions().external_senders();
+        let expected = self.external_sender.as_ref().map(std::slice::from_ref);
+        if external_senders.map(|senders| senders.as_slice()) != expected {
+            return Err(DaveError::Rejected(
+                "group of a different external sender".to_owned(),
+            ));
+        }
+        self.group = Some(staged.into_group(&self.provider).map_err(rejected)?);
+        self.joined = true;
+        Ok(())
+    }
+
+    /// The secret each member's media keys are ratcheted from in this epoch,
+    /// by user id
+    pub fn media_secrets(&self) -> Result<HashMap<u64, Vec<u8>>> {
+        let group = self
+            .group
+            .as_ref()
+            .filter(|_| self.joined)
+            .ok_or(DaveError::Rejected("not in a group".to_owned()))?;
+        group
+            .members()
+            .filter_map(|member| user_id(member.credential.serialized_content()))
+            .map(|user_id| {
+                let secret = group
+                    .export_secret(
+                        self.provider.crypto(),
+                        MEDIA_KEY_LABEL,
+                        &user_id.to_le_bytes(),
+                        MEDIA_KEY_LEN,
+                    )
+                    .map_err(rejected)?;
+                Ok((user_id, secret))
+            })
+            .collect()
+    }
+
+    fn delete_group(&mut self) {
+        if let Some(mut group) = self.group.take() {
+            _ = group.delete(self.provider.storage());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use openmls::prelude::{
+        ExternalProposal, GroupEpoch, KeyPackageIn, LeafNodeIndex, SenderExtensionIndex,
+    };
+
+    use super::*;
+    use crate::voice::dave::FrameKeys;
+
+    const CHANNEL_ID: u64 = 42;
+
+    // plays the voice server, which proposes everyone's adds and removes
+    struct ExternalSenderPeer {
+        provider: OpenMlsRustCrypto,
+        signer: SignatureKeyPair,
+    }
+
+    impl ExternalSenderPeer {
+        fn new() -> Self {
+            Self {
+                provider: OpenMlsRustCrypto::default(),
+                signer: SignatureKeyPair::new(CIPHERSUITE.signature_algorithm()).unwrap(),
+            }
+        }
+
+        fn external_sender(&self) -> Vec<u8> {
+            ExternalSender::new(
+                self.signer.public().into(),
+                BasicCredential::new(Vec::new()).into(),
+            )
+            .tls_serialize_detached()
+            .unwrap()
+        }
+
+        fn proposals(&self, messages: Vec<Vec<u8>>) -> Vec<u8> {
+            let mut payload = vec![PROPOSALS_APPEND];
+            payload.extend(
+                VLBytes::new(messages.concat())
+                    .tls_serialize_detached()
+                    .unwrap(),
+            );
+            payload
+        }
+
+        fn add(&self, epoch: u64, member: &mut Member) -> Vec<u8> {
+            let key_package =
+                KeyPackageIn::tls_deserialize_exact_bytes(&member.key_package().unwrap())
+                    .unwrap()
+                    .validate(self.provider.crypto(), ProtocolVersion::Mls10)
+                    .unwrap();
+            let message = ExternalProposal::new_add::<OpenMlsRustCrypto>(
+                key_package,
+                GroupId::from_slice(&CHANNEL_ID.to_be_bytes()),
+                GroupEpoch::from(epoch),
+                &self.signer,
+                SenderExtensionIndex::new(0),
+            )
+            .unwrap();
+            self.proposals(vec![message.tls_serialize_detached().unwrap()])
+        }
+
+        fn remove(&self, epoch: u64, leaf: u32) -> Vec<u8> {
+            let message = ExternalProposal::new_remove::<OpenMlsRustCrypto>(
+                LeafNodeIndex::new(leaf),
+                GroupId::from_slice(&CHANNEL_ID.to_be_bytes()),
+                GroupEpoch::from(epoch),
+                &self.signer,
+                SenderExtensionIndex::new(0),
+            )
+            .unwrap();
+            self.proposals(vec![message.tls_serialize_detached().unwrap()])
+        }
+    }
+
+    fn member(user_id: u64, server: &ExternalSenderPeer) -> Member {
+        let mut member = Member::new(user_id);
+        member
+            .create_group(CHANNEL_ID, &server.external_sender())
+            .unwrap();
+        member
+    }
+
+    fn frame_keys(member: &Member) -> FrameKeys {
+        let frames = FrameKeys::new();
+        frames.set_epoch(Some(member.media_secrets().unwrap()), member.user_id());
+        frames
+    }
+
+    #[test]
+    fn members_agree_on_every_epoch() {
+        let server = ExternalSenderPeer::new();
+        let (mut a, mut b, mut c) = (member(1, &server), member(2, &server), member(3, &server));
+
+        // a commits b's add to its group
+        let commit = a.handle_proposals(&server.add(0, &mut b)).unwrap().unwrap();
+        a.merge_own_commit().unwrap();
+        b.join(&commit.welcome.unwrap()).unwrap();
+        assert_eq!(a.epoch(), Some(1));
+        assert_eq!(b.epoch(), Some(1));
+        assert_eq!(a.media_secrets().unwrap(), b.media_secrets().unwrap());
+
+        let opus = b"not really opus but close enough";
+        let encrypted = frame_keys(&a).encrypt(opus).into_owned();
+        assert_ne!(&encrypted[..], &opus[..]);
+        let decrypted = frame_keys(&b).decrypt(Some("1"), encrypted);
+        assert_eq!(decrypted.as_deref(), Some(&opus[..]));
+
+        // both commit c's add, the voice server picks b's
+        let proposals = server.add(1, &mut c);
+        a.handle_proposals(&proposals).unwrap().unwrap();
+        let commit = b.handle_proposals(&proposals).unwrap().unwrap();
+        b.merge_own_commit().unwrap();
+        a.process_commit(&commit.message).unwrap();
+        c.join(&commit.welcome.unwrap()).unwrap();
+        assert_eq!(a.media_secrets().unwrap(), b.media_secrets().unwrap());
+        assert_eq!(c.media_secrets().unwrap(), b.media_secrets().unwrap());
+
+        // a leaves
+        let proposals = server.remove(2, 0);
+        b.handle_proposals(&proposals).unwrap().unwrap();
+        let commit = c.handle_proposals(&proposals).unwrap().unwrap();
+        assert!(commit.welcome.is_none());
+        c.merge_own_commit().unwrap();
+        b.process_commit(&commit.message).unwrap();
+        assert_eq!(b.epoch(), Some(3));
+        let secrets = b.media_secrets().unwrap();
+        assert_eq!(secrets, c.media_secrets().unwrap());
+        assert!(!secrets.contains_key(&1));
+    }
+
+    #[test]
+    fn revoked_proposals_are_not_committed() {
+        let server = ExternalSenderPeer::new();
+        let (mut a, mut b) = (member(1, &server), member(2, &server));
+        assert!(a
+            .handle_proposals(&server.add(0, &mut b))
+            .unwrap()
+            .is_some());
+
+        let reference = a
+            .group
+            .as_ref()
+            .unwrap()
+            .pending_proposals()
+            .next()
+            .unwrap()
+            .proposal_reference_ref()
+            .tls_serialize_detached()
+            .unwrap();
+        let mut payload = vec![PROPOSALS_REVOKE];
+        payload.extend(VLBytes::new(reference).tls_serialize_detached().unwrap());
+        assert!(a.handle_proposals(&payload).unwrap().is_none());
+    }
+
+    #[test]
+    fn proposals_must_be_signed_by_the_voice_server() {
+        let server = ExternalSenderPeer::new();
+        let impostor = ExternalSenderPeer::new();
+        let (mut a, mut b) = (member(1, &server), member(2, &server));
+        assert!(a.handle_proposals(&impostor.add(0, &mut b)).is_err());
+    }
+
+    #[test]
+    fn welcomes_must_come_from_the_voice_server() {
+        let server = ExternalSenderPeer::new();
+        let impostor = ExternalSenderPeer::new();
+        let mut a = member(1, &impostor);
+        let mut b = member(2, &server);
+        let commit = a
+            .handle_proposals(&impostor.add(0, &mut b))
+            .unwrap()
+            .unwrap();
+        assert!(b.join(&commit.welcome.unwrap()).is_err());
+        assert!(!b.joined());
+    }
+}
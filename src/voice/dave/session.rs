@@ -0,0 +1,269 @@
+//! The gateway's half of DAVE: the binary MLS messages and the transitions
+//! between epochs
+
+use std::{collections::HashMap, sync::Arc};
+
+use derivative::Derivative;
+use thiserror::Error;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{debug, info, warn};
+
+use super::{
+    super::payloads::{DaveEpoch, DaveTransition, DaveTransitionId, DiscordPayload},
+    mls::Member,
+    FrameKeys,
+};
+
+/// The DAVE protocol version we speak
+pub(in crate::voice) const PROTOCOL_VERSION: u16 = 1;
+
+// binary gateway messages, the rest are json
+const MLS_EXTERNAL_SENDER: u8 = 25;
+const MLS_KEY_PACKAGE: u8 = 26;
+const MLS_PROPOSALS: u8 = 27;
+const MLS_COMMIT_WELCOME: u8 = 28;
+const MLS_ANNOUNCE_COMMIT_TRANSITION: u8 = 29;
+const MLS_WELCOME: u8 = 30;
+
+#[derive(Error, Debug)]
+pub(in crate::voice) enum DaveError {
+    #[error("malformed mls message: {0}")]
+    Malformed(String),
+    #[error("mls message rejected: {0}")]
+    Rejected(String),
+}
+
+// what the media keys turn into once the transition executes
+#[derive(Debug)]
+struct Transition {
+    protocol_version: u16,
+    media_secrets: Option<HashMap<u64, Vec<u8>>>,
+}
+
+/// The gateway's side of DAVE: keeps our place in the group up to date and
+/// hands the keys to [`FrameKeys`] when the voice server says to switch
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub(in crate::voice) struct DaveSession {
+    channel_id: u64,
+    protocol_version: u16,
+    #[derivative(Debug = "ignore")]
+    frames: Arc<FrameKeys>,
+    member: Member,
+    #[derivative(Debug = "ignore")]
+    external_sender: Option<Vec<u8>>,
+    // the commit we sent last, until the voice server picks one
+    #[derivative(Debug = "ignore")]
+    pending_commit: Option<Vec<u8>>,
+    transitions: HashMap<u16, Transition>,
+}
+
+fn binary(opcode: u8, payload: &[u8]) -> Message {
+    Message::Binary([&[opcode], payload].concat().into())
+}
+
+impl DaveSession {
+    pub fn new(user_id: u64, channel_id: u64, frames: Arc<FrameKeys>) -> Self {
+        Self {
+            channel_id,
+            protocol_version: 0,
+            frames,
+            member: Member::new(user_id),
+            external_sender: None,
+            pending_commit: None,
+            transitions: HashMap::new(),
+        }
+    }
+
+    /// The protocol version from the session description, audio stays
+    /// unencrypted until we're in the group if it's not 0
+    pub fn start(&mut self, protocol_version: u16) -> Vec<Message> {
+        self.protocol_version = protocol_version;
+        if protocol_version == 0 {
+            self.frames.set_epoch(None, self.member.user_id());
+            return Vec::new();
+        }
+        self.frames.set_pending();
+        self.reset()
+    }
+
+    /// Handles a binary gateway message, `data` starts at the opcode
+    pub fn handle_binary(&mut self, opcode: u8, payload: &[u8]) -> Vec<Message> {
+        match opcode {
+            MLS_EXTERNAL_SENDER => {
+                self.external_sender = Some(payload.to_vec());
+                if !self.member.joined() {
+                    self.create_group();
+                }
+                Vec::new()
+            }
+            MLS_PROPOSALS => self.proposals(payload),
+            MLS_ANNOUNCE_COMMIT_TRANSITION | MLS_WELCOME if payload.len() < 2 => {
+                warn!("DAVE message {} without a transition id", opcode);
+                Vec::new()
+            }
+            MLS_ANNOUNCE_COMMIT_TRANSITION => {
+                let transition_id = u16::from_be_bytes([payload[0], payload[1]]);
+                self.announced_commit(transition_id, &payload[2..])
+            }
+            MLS_WELCOME => {
+                let transition_id = u16::from_be_bytes([payload[0], payload[1]]);
+                self.welcome(transition_id, &payload[2..])
+            }
+            _ => {
+                debug!("Ignoring binary gateway message {}", opcode);
+                Vec::new()
+            }
+        }
+    }
+
+    pub fn prepare_transition(&mut self, transition: DaveTransition) -> Vec<Message> {
+        let media_secrets = match transition.protocol_version {
+            0 => None,
+            _ => self.member.media_secrets().ok(),
+        };
+        self.transitions.insert(
+            transition.transition_id,
+            Transition {
+                protocol_version: transition.protocol_version,
+                media_secrets,
+            },
+        );
+        self.ready(transition.transition_id)
+    }
+
+    pub fn execute_transition(&mut self, transition_id: u16) {
+        let Some(transition) = self.transitions.remove(&transition_id) else {
+            debug!("Ignoring unknown DAVE transition {}", transition_id);
+            return;
+        };
+        info!(
+            "Executing DAVE transition {} to protocol version {}",
+            transition_id, transition.protocol_version
+        );
+        self.protocol_version = transition.protocol_version;
+        self.frames
+            .set_epoch(transition.media_secrets, self.member.user_id());
+    }
+
+    /// A new group is about to be made when the epoch is 1, which we need a
+    /// fresh key package for
+    pub fn prepare_epoch(&mut self, epoch: DaveEpoch) -> Vec<Message> {
+        if epoch.epoch != 1 {
+            return Vec::new();
+        }
+        self.protocol_version = epoch.protocol_version;
+        self.reset()
+    }
+
+    // forgets the group and starts over with new keys
+    fn reset(&mut self) -> Vec<Message> {
+        self.member = Member::new(self.member.user_id());
+        self.pending_commit = None;
+        self.create_group();
+        match self.member.key_package() {
+            Ok(key_package) => vec![binary(MLS_KEY_PACKAGE, &key_package)],
+            Err(e) => {
+                warn!("Could not create a DAVE key package: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    fn create_group(&mut self) {
+        let Some(external_sender) = &self.external_sender else {
+            return;
+        };
+        if let Err(e) = self.member.create_group(self.channel_id, external_sender) {
+            warn!("Could not create DAVE group: {}", e);
+        }
+    }
+
+    fn proposals(&mut self, payload: &[u8]) -> Vec<Message> {
+        match self.member.handle_proposals(payload) {
+            Ok(Some(commit)) => {
+                let mut payload = commit.message.clone();
+                payload.extend(commit.welcome.iter().flatten());
+                self.pending_commit = Some(commit.message);
+                vec![binary(MLS_COMMIT_WELCOME, &payload)]
+            }
+            // everything was revoked
+            Ok(None) => {
+                self.pending_commit = None;
+                Vec::new()
+            }
+            Err(e) => {
+                warn!("Invalid DAVE proposals: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    fn announced_commit(&mut self, transition_id: u16, commit: &[u8]) -> Vec<Message> {
+        let result = match self.pending_commit.take() {
+            Some(pending) if pending == commit => self.member.merge_own_commit(),
+            _ if self.member.joined() => self.member.process_commit(commit),
+            // someone else's commit adding us, the welcome comes next
+            _ => return Vec::new(),
+        };
+        match result {
+            Ok(()) => self.entered_epoch(transition_id),
+            Err(e) => {
+                warn!("Invalid DAVE commit: {}", e);
+                self.invalid(transition_id)
+            }
+        }
+    }
+
+    fn welcome(&mut self, transition_id: u16, welcome: &[u8]) -> Vec<Message> {
+        if self.member.joined() {
+            debug!("Ignoring DAVE welcome, we're already in the group");
+            return Vec::new();
+        }
+        match self.member.join(welcome) {
+            Ok(()) => self.entered_epoch(transition_id),
+            Err(e) => {
+                warn!("Invalid DAVE welcome: {}", e);
+                self.invalid(transition_id)
+            }
+        }
+    }
+
+    fn entered_epoch(&mut self, transition_id: u16) -> Vec<Message> {
+        debug!("Entered DAVE epoch {:?}", self.member.epoch());
+        let media_secrets = match self.member.media_secrets() {
+            Ok(secrets) => secrets,
+            Err(e) => {
+                warn!("Could not export DAVE media secrets: {}", e);
+                return self.invalid(transition_id);
+            }
+        };
+        self.transitions.insert(
+            transition_id,
+            Transition {
+                protocol_version: self.protocol_version,
+                media_secrets: Some(media_secrets),
+            },
+        );
+        self.pending_commit = None;
+        self.ready(transition_id)
+    }
+
+    // transition 0 happens right away, anything else once everyone is ready
+    fn ready(&mut self, transition_id: u16) -> Vec<Message> {
+        if transition_id == 0 {
+            self.execute_transition(transition_id);
+            return Vec::new();
+        }
+        vec![DiscordPayload::DaveTransitionReady(DaveTransitionId { transition_id }).into()]
+    }
+
+    // tells the voice server we couldn't follow, which makes it add us again
+    fn invalid(&mut self, transition_id: u16) -> Vec<Message> {
+        let mut messages = vec![
+            DiscordPayload::DaveMlsInvalidCommitWelcome(DaveTransitionId { transition_id }).into(),
+        ];
+        messages.extend(self.reset());
+        messages
+    }
+}
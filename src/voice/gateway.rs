@@ -1,4 +1,10 @@
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicI64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::Result;
 use derivative::Derivative;
@@ -11,22 +17,130 @@ use tokio::{
     sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
     time,
 };
-use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream};
-use tracing::{debug, error, Instrument};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{protocol::CloseFrame, Message},
+    MaybeTlsStream,
+};
+use tracing::{debug, error, info, trace, warn, Instrument};
 
 use crate::{
     client::payloads::VoiceUpdate,
-    utils::{handle_message, ReadMessageError},
+    config::VoiceConfiguration,
+    utils::{handle_message, parse_msg, ReadMessageError},
 };
 
+#[cfg(feature = "dave")]
+use super::dave::{self, DaveSession};
 use super::{
-    payloads::{self, DiscordPayload, Identify},
-    VoiceError,
+    payloads::{self, DiscordPayload, Heartbeat, Identify, Resume, Sequenced},
+    receive::VoiceReceiver,
+    roster::VoiceRoster,
+    VoiceError, VoiceEvent,
 };
 
 type WebSocketStream = tokio_tungstenite::WebSocketStream<MaybeTlsStream<TcpStream>>;
 
-const WEBSOCET_VERSION: u8 = 7;
+/// Close codes after which discord won't accept a resume, the session has to be
+/// identified from scratch
+const NON_RESUMABLE_CLOSE_CODES: [u16; 8] = [4004, 4006, 4009, 4011, 4012, 4014, 4016, 4022];
+/// Non resumable close codes after which identifying again is worth a try
+const RECONNECT_CLOSE_CODES: [u16; 2] = [4006, 4009];
+const MAX_RESUME_ATTEMPTS: u32 = 5;
+/// Heartbeats in a row that may go unacknowledged before the connection is
+/// considered dead
+const MAX_MISSED_HEARTBEATS: u32 = 2;
+/// How long after the handshake discord gets to tell us who's already in the
+/// channel before it counts as empty
+const EMPTY_CHANNEL_GRACE: Duration = Duration::from_secs(2);
+
+/// Health of the gateway connection, shared with the [`super::VoiceManager`]
+#[derive(Debug)]
+pub struct GatewayState {
+    // round trip time of the last acknowledged heartbeat in ms, -1 until the first ack
+    ping: AtomicI64,
+    connected: AtomicBool,
+}
+
+impl Default for GatewayState {
+    fn default() -> Self {
+        Self {
+            ping: AtomicI64::new(-1),
+            connected: AtomicBool::new(false),
+        }
+    }
+}
+
+impl GatewayState {
+    pub fn ping(&self) -> Option<Duration> {
+        let ping = self.ping.load(Ordering::Relaxed);
+        (ping >= 0).then(|| Duration::from_millis(ping as u64))
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+}
+
+/// Why a single websocket connection ended
+#[derive(Debug)]
+enum Disconnect {
+    /// [`super::VoiceManager`] was dropped, nobody needs the gateway anymore
+    ManagerDropped,
+    /// Discord closed the websocket, without a close frame if the connection dropped
+    Closed(Option<CloseFrame>),
+    /// Discord stopped acknowledging heartbeats
+    Zombie,
+}
+
+impl Disconnect {
+    fn is_resumable(&self) -> bool {
+        match self {
+            Disconnect::ManagerDropped => false,
+            Disconnect::Closed(Some(frame)) => {
+                !NON_RESUMABLE_CLOSE_CODES.contains(&u16::from(frame.code))
+            }
+            Disconnect::Closed(None) | Disconnect::Zombie => true,
+        }
+    }
+
+    fn into_event(self, reconnect: bool) -> Option<VoiceEvent> {
+        match self {
+            Disconnect::ManagerDropped => None,
+            Disconnect::Closed(Some(frame)) => Some(VoiceEvent::GatewayClosed {
+                code: frame.code.into(),
+                reason: frame.reason.to_string(),
+                by_remote: true,
+                reconnect,
+            }),
+            // 1006 is what the websocket spec reports for a connection that dropped
+            // without a close frame
+            Disconnect::Closed(None) => Some(VoiceEvent::GatewayClosed {
+                code: 1006,
+                reason: "connection dropped".to_owned(),
+                by_remote: false,
+                reconnect,
+            }),
+            Disconnect::Zombie => Some(VoiceEvent::GatewayClosed {
+                code: 1006,
+                reason: "heartbeats were not acknowledged".to_owned(),
+                by_remote: false,
+                reconnect,
+            }),
+        }
+    }
+}
+
+/// Discord sends endpoints without a scheme, which means `wss://`. Endpoints
+/// that come with one, like a local `ws://` server, are used as they are.
+fn gateway_url(endpoint: &str, version: u8) -> Result<url::Url, url::ParseError> {
+    let endpoint = if endpoint.starts_with("ws://") || endpoint.starts_with("wss://") {
+        endpoint.to_owned()
+    } else {
+        format!("wss://{}", endpoint)
+    };
+    url::Url::parse(&format!("{}?v={}", endpoint, version))
+}
 
 #[derive(Derivative)]
 #[derivative(Debug)]
@@ -35,6 +149,8 @@ pub struct VoiceGateway {
     guild_id: String,
     endpoint: String,
     session_id: String,
+    #[derivative(Debug = "ignore")]
+    token: String,
 
     #[derivative(Debug = "ignore")]
     write: SplitSink<WebSocketStream, Message>,
@@ -46,50 +162,89 @@ pub struct VoiceGateway {
     heartbeat_interval: Duration,
     #[derivative(Debug = "ignore")]
     to_manager_tx: UnboundedSender<DiscordPayload>,
+    #[derivative(Debug = "ignore")]
+    events_tx: UnboundedSender<VoiceEvent>,
+    #[derivative(Debug = "ignore")]
+    state: Arc<GatewayState>,
+    #[derivative(Debug = "ignore")]
+    receiver: Arc<VoiceReceiver>,
+    #[derivative(Debug = "ignore")]
+    roster: Arc<VoiceRoster>,
+    // None when DAVE is turned off, or the channel id is unknown
+    #[cfg(feature = "dave")]
+    dave: Option<DaveSession>,
+    version: u8,
+    // last sequence number received, v8 only. survives resumes so discord can
+    // replay what was missed
+    seq_ack: Option<i64>,
+    // nonce and send time of the heartbeat waiting for an ack
+    #[derivative(Debug = "ignore")]
+    pending_heartbeat: Option<(u64, Instant)>,
+    #[derivative(Debug = "ignore")]
+    missed_heartbeats: u32,
+    // when to check whether anyone was in the channel we joined
+    #[derivative(Debug = "ignore")]
+    empty_check: Option<time::Instant>,
 }
 
 type Error = super::VoiceError;
 
 impl VoiceGateway {
-    /// Connects to the voice gateway, sends identify payload, and returns a [`VoiceGateway`]
-    /// as well as a [`UnboundedSender`] to send payloads to the gateway.
-    #[tracing::instrument(skip(to_manager_tx))]
+    /// Connects to the voice gateway, sends identify payload, and returns a [`UnboundedSender`]
+    /// to send payloads to the gateway as well as the [`GatewayState`] it keeps updated.
+    #[tracing::instrument(skip(config, to_manager_tx, events_tx, receiver, roster))]
     pub async fn connect(
         user_id: impl Into<String> + std::fmt::Debug,
         voice_update_payload: VoiceUpdate,
+        config: &VoiceConfiguration,
         to_manager_tx: UnboundedSender<DiscordPayload>,
-    ) -> Result<UnboundedSender<DiscordPayload>, Error> {
-        let url = url::Url::parse(&format!(
-            "wss://{}?v={}",
-            voice_update_payload.event.endpoint.clone(),
-            WEBSOCET_VERSION
-        ))?;
-        let (ws_stream, _) = connect_async(url.as_str()).await?;
-        let (write, mut read) = ws_stream.split();
+        events_tx: UnboundedSender<VoiceEvent>,
+        receiver: Arc<VoiceReceiver>,
+        roster: Arc<VoiceRoster>,
+    ) -> Result<(UnboundedSender<DiscordPayload>, Arc<GatewayState>), Error> {
+        let user_id = user_id.into();
+        let version = config.gateway_version;
+        #[cfg(feature = "dave")]
+        let dave = config
+            .dave
+            .then(|| Self::dave_session(&user_id, &voice_update_payload, &receiver))
+            .flatten();
+        #[cfg(not(feature = "dave"))]
+        if config.dave {
+            warn!("DAVE is turned on, but jukebox was built without the dave feature");
+        }
+        let (write, mut read) = Self::open(&voice_update_payload.event.endpoint, version).await?;
         let (to_gateway_tx, from_manager_rx) = unbounded_channel();
 
-        // await hello to determine heartbeat interval
-        let payload: payloads::Hello = match handle_message(&mut read).await? {
-            DiscordPayload::Hello(payload) => Ok(payload),
-            _ => Err(VoiceError::UnexpectedProtocolError(
-                "first message was not Hello".to_owned(),
-            )),
-        }?;
+        let payload = Self::await_hello(&mut read).await?;
 
         let mut gateway = Self {
-            user_id: user_id.into(),
+            user_id,
             guild_id: voice_update_payload.event.guild_id,
             endpoint: voice_update_payload.event.endpoint,
             session_id: voice_update_payload.session_id,
+            token: voice_update_payload.event.token,
 
             write,
             read,
-            heartbeat_interval: Duration::from_millis(payload.heartbeat_interval),
+            heartbeat_interval: Duration::from_secs_f64(payload.heartbeat_interval / 1000.0),
             from_manager_rx,
             to_manager_tx,
+            events_tx,
+            state: Arc::new(GatewayState::default()),
+            receiver,
+            roster,
+            #[cfg(feature = "dave")]
+            dave,
+            version,
+            seq_ack: None,
+            pending_heartbeat: None,
+            missed_heartbeats: 0,
+            empty_check: None,
         };
 
-        gateway.identify(voice_update_payload.event.token).await?;
+        gateway.identify().await?;
+        let state = gateway.state.clone();
 
         let gateway_info = format!(
             "user_id: {}, guild_id: {}, endpoint: {}, session_id: {}",
@@ -108,7 +263,26 @@ impl VoiceGateway {
             .instrument(tracing::info_span!("voice_gateway", "gateway" = %gateway_info)),
         );
 
-        Ok(to_gateway_tx)
+        Ok((to_gateway_tx, state))
+    }
+
+    #[cfg(feature = "dave")]
+    fn dave_session(
+        user_id: &str,
+        voice_update: &VoiceUpdate,
+        receiver: &VoiceReceiver,
+    ) -> Option<DaveSession> {
+        let ids = user_id.parse().ok().zip(
+            voice_update
+                .channel_id
+                .as_deref()
+                .and_then(|id| id.parse().ok()),
+        );
+        let Some((user_id, channel_id)) = ids else {
+            warn!("No channel id in the voice update, DAVE is disabled");
+            return None;
+        };
+        Some(DaveSession::new(user_id, channel_id, receiver.frames()))
     }
 
     #[tracing::instrument(level = "trace")]
@@ -117,11 +291,94 @@ impl VoiceGateway {
         Ok(())
     }
 
+    #[cfg(feature = "dave")]
+    async fn send_all(&mut self, messages: Vec<Message>) -> Result<(), Error> {
+        for message in messages {
+            self.write.send(message).await?;
+        }
+        Ok(())
+    }
+
+    async fn open(
+        endpoint: &str,
+        version: u8,
+    ) -> Result<
+        (
+            SplitSink<WebSocketStream, Message>,
+            SplitStream<WebSocketStream>,
+        ),
+        Error,
+    > {
+        let (ws_stream, _) = connect_async(gateway_url(endpoint, version)?.as_str()).await?;
+        Ok(ws_stream.split())
+    }
+
+    // await hello to determine heartbeat interval
+    async fn await_hello(
+        read: &mut SplitStream<WebSocketStream>,
+    ) -> Result<payloads::Hello, Error> {
+        match handle_message(read).await? {
+            DiscordPayload::Hello(payload) => Ok(payload),
+            _ => Err(VoiceError::UnexpectedProtocolError(
+                "first message was not Hello".to_owned(),
+            )),
+        }
+    }
+
     /// Runs the event loop for the voice gateway. It responds to gateway events
-    /// and messages sent from the [`super::VoiceManager`].
+    /// and messages sent from the [`super::VoiceManager`], and resumes the session
+    /// on a new websocket if discord closes it with a resumable code. The udp
+    /// socket is left alone while resuming, so audio keeps flowing.
     #[tracing::instrument]
     pub async fn run(&mut self) -> Result<()> {
+        loop {
+            let disconnect = self.run_connection().await?;
+            if !disconnect.is_resumable() {
+                info!("Voice gateway closed: {:?}", disconnect);
+                let reconnect = matches!(
+                    &disconnect,
+                    Disconnect::Closed(Some(frame))
+                        if RECONNECT_CLOSE_CODES.contains(&u16::from(frame.code))
+                );
+                self.report(disconnect, reconnect);
+                return Ok(());
+            }
+
+            warn!("Voice gateway closed with {:?}, resuming", disconnect);
+            if let Err(e) = self.resume_with_retries().await {
+                // the session is most likely gone on discord's side too
+                error!("Could not resume voice session: {}", e);
+                self.report(disconnect, true);
+                return Ok(());
+            }
+        }
+    }
+
+    // everything that mentions a user means they're in the channel
+    fn joined(&self, user_id: &str) {
+        if user_id != self.user_id && self.roster.join(user_id) {
+            _ = self.events_tx.send(VoiceEvent::ChannelOccupied);
+        }
+    }
+
+    fn report(&self, disconnect: Disconnect, reconnect: bool) {
+        if let Some(event) = disconnect.into_event(reconnect) {
+            // nobody to tell if the player is already gone
+            _ = self.events_tx.send(event);
+        }
+    }
+
+    async fn run_connection(&mut self) -> Result<Disconnect> {
+        self.state.connected.store(true, Ordering::Relaxed);
+        let disconnect = self.handle_connection().await;
+        self.state.connected.store(false, Ordering::Relaxed);
+        disconnect
+    }
+
+    async fn handle_connection(&mut self) -> Result<Disconnect> {
         let mut interval = time::interval(self.heartbeat_interval);
+        self.pending_heartbeat = None;
+        self.missed_heartbeats = 0;
 
         loop {
             tokio::select! {
@@ -131,64 +388,257 @@ impl VoiceGateway {
                     match sent_payload {
                         Some(payload) => self.send(payload).await?,
                         // VoiceManager is dropped
-                        None => break,
+                        None => return Ok(Disconnect::ManagerDropped),
                     }
                 },
 
+                _ = time::sleep_until(self.empty_check.unwrap_or_else(time::Instant::now)),
+                    if self.empty_check.is_some() =>
+                {
+                    // the roster starts out empty, so nobody showing up by now
+                    // means there's nobody to show up
+                    self.empty_check = None;
+                    if self.roster.is_empty() {
+                        _ = self.events_tx.send(VoiceEvent::ChannelEmpty);
+                    }
+                }
+
                 _ = interval.tick() => {
-                    self.send(Self::heartbeat()).await?;
+                    if self.pending_heartbeat.is_some() {
+                        self.missed_heartbeats += 1;
+                        if self.missed_heartbeats >= MAX_MISSED_HEARTBEATS {
+                            return Ok(Disconnect::Zombie);
+                        }
+                    }
+                    // a failed heartbeat means the connection is gone, which the
+                    // read half will notice
+                    if let Err(e) = self.heartbeat().await {
+                        debug!("Failed to send heartbeat: {}", e);
+                    }
                 }
 
-                response = handle_message(&mut self.read) => {
+                msg = self.read.next() => {
+                    let response = match msg {
+                        Some(Ok(Message::Close(frame))) => return Ok(Disconnect::Closed(frame)),
+                        Some(Ok(Message::Binary(data))) => {
+                            self.handle_binary(&data).await?;
+                            continue;
+                        }
+                        Some(Ok(msg)) => {
+                            self.track_sequence(&msg);
+                            parse_msg(Some(Ok::<_, ReadMessageError>(msg))).await
+                        }
+                        msg => parse_msg(msg).await,
+                    };
                     match response {
                         Ok(payload) => {
                             match payload {
                                 DiscordPayload::Ready(_) => {
-                                    self.to_manager_tx
-                                        .send(payload)
-                                        .expect("Receiver should not be dropped");
+                                    // the manager stops listening after the handshake
+                                    let _ = self.to_manager_tx.send(payload);
+                                }
+                                DiscordPayload::SessionDescription(ref _description) => {
+                                    #[cfg(feature = "dave")]
+                                    let dave_version = _description.dave_protocol_version;
+                                    // the manager stops listening after the handshake
+                                    let _ = self.to_manager_tx.send(payload);
+                                    self.empty_check = Some(time::Instant::now() + EMPTY_CHANNEL_GRACE);
+                                    #[cfg(feature = "dave")]
+                                    if let Some(dave) = &mut self.dave {
+                                        let messages = dave.start(dave_version);
+                                        self.send_all(messages).await?;
+                                    }
+                                }
+                                DiscordPayload::HeartbeatACK(heartbeat) => self.acknowledge(heartbeat),
+                                DiscordPayload::Speaking(speaking) => {
+                                    if let Some(user_id) = speaking.user_id {
+                                        self.joined(&user_id);
+                                        self.receiver.set_user(speaking.ssrc, user_id);
+                                    }
+                                }
+                                DiscordPayload::ClientSsrc(client) => {
+                                    self.joined(&client.user_id);
+                                    self.receiver.set_user(client.audio_ssrc, client.user_id);
+                                }
+                                DiscordPayload::Resumed => info!("Voice session resumed"),
+                                DiscordPayload::ClientConnect(clients) => {
+                                    for user_id in &clients.user_ids {
+                                        self.joined(user_id);
+                                    }
+                                }
+                                DiscordPayload::ClientDisconnect(client) => {
+                                    self.receiver.remove_user(&client.user_id);
+                                    if self.roster.leave(&client.user_id) {
+                                        _ = self.events_tx.send(VoiceEvent::ChannelEmpty);
+                                    }
                                 }
-                                DiscordPayload::SessionDescription(_) => {
-                                    self.to_manager_tx
-                                        .send(payload)
-                                        .expect("Receiver should not be dropped");
+                                DiscordPayload::ClientFlags(client) => {
+                                    self.joined(&client.user_id);
+                                    self.roster.set_flags(&client.user_id, client.flags);
+                                }
+                                DiscordPayload::ClientPlatform(client) => {
+                                    self.joined(&client.user_id);
+                                    self.roster.set_platform(&client.user_id, client.platform);
+                                }
+                                #[cfg(feature = "dave")]
+                                DiscordPayload::DavePrepareTransition(transition) => {
+                                    if let Some(dave) = &mut self.dave {
+                                        let messages = dave.prepare_transition(transition);
+                                        self.send_all(messages).await?;
+                                    }
+                                }
+                                #[cfg(feature = "dave")]
+                                DiscordPayload::DaveExecuteTransition(transition) => {
+                                    if let Some(dave) = &mut self.dave {
+                                        dave.execute_transition(transition.transition_id);
+                                    }
+                                }
+                                #[cfg(feature = "dave")]
+                                DiscordPayload::DavePrepareEpoch(epoch) => {
+                                    if let Some(dave) = &mut self.dave {
+                                        let messages = dave.prepare_epoch(epoch);
+                                        self.send_all(messages).await?;
+                                    }
                                 }
-                                DiscordPayload::Speaking(_) => {}
-                                DiscordPayload::Resumed => {}
-                                DiscordPayload::ClientDisconnect(_) => {}
                                 _ => {}
                             }
                         },
                         Err(e) => {
                             match e {
-                                ReadMessageError::WebsocketClosed => break,
+                                ReadMessageError::WebsocketClosed => {
+                                    return Ok(Disconnect::Closed(None))
+                                }
                                 ReadMessageError::SerializationError(e) => error!(e),
-                                ReadMessageError::WebsocketStreamError(e) => error!(e),
+                                ReadMessageError::WebsocketStreamError(e) => {
+                                    // the stream is unusable after an error
+                                    error!(e);
+                                    return Ok(Disconnect::Closed(None));
+                                }
                             }
                         }
                     }
                 },
             }
         }
-        Ok(())
     }
 
-    async fn identify(&mut self, token: String) -> Result<(), Error> {
+    async fn resume_with_retries(&mut self) -> Result<(), Error> {
+        let mut attempt = 0;
+        loop {
+            match self.resume().await {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt < MAX_RESUME_ATTEMPTS => {
+                    attempt += 1;
+                    let backoff = Duration::from_secs(1 << attempt.min(4));
+                    warn!(
+                        "Resume attempt {} failed: {}, retrying in {:?}",
+                        attempt, e, backoff
+                    );
+                    time::sleep(backoff).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Reconnects to the same endpoint and resumes the session. Discord answers
+    /// with Resumed, which is picked up by the event loop.
+    async fn resume(&mut self) -> Result<(), Error> {
+        let (write, mut read) = Self::open(&self.endpoint, self.version).await?;
+        let hello = Self::await_hello(&mut read).await?;
+        self.write = write;
+        self.read = read;
+        self.heartbeat_interval = Duration::from_secs_f64(hello.heartbeat_interval / 1000.0);
+
+        self.send(DiscordPayload::Resume(Resume {
+            server_id: self.guild_id.clone(),
+            session_id: self.session_id.clone(),
+            token: self.token.clone(),
+            seq_ack: self.seq_ack,
+        }))
+        .await
+    }
+
+    async fn identify(&mut self) -> Result<(), Error> {
         let inner_payload = Identify {
             server_id: self.guild_id.clone(),
             user_id: self.user_id.clone(),
             session_id: self.session_id.clone(),
-            token,
+            token: self.token.clone(),
+            #[cfg(feature = "dave")]
+            max_dave_protocol_version: self.dave.as_ref().map(|_| dave::PROTOCOL_VERSION),
+            #[cfg(not(feature = "dave"))]
+            max_dave_protocol_version: None,
         };
         self.send(DiscordPayload::Identify(inner_payload)).await?;
         Ok(())
     }
 
-    fn heartbeat() -> DiscordPayload {
+    async fn heartbeat(&mut self) -> Result<(), Error> {
         let nonce = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
-            .as_millis();
-        DiscordPayload::Heartbeat(nonce)
+            .as_millis() as u64;
+        self.pending_heartbeat = Some((nonce, Instant::now()));
+        let heartbeat = if self.version >= 8 {
+            Heartbeat::V8 {
+                t: nonce,
+                seq_ack: self.seq_ack,
+            }
+        } else {
+            Heartbeat::V7(nonce)
+        };
+        self.send(DiscordPayload::Heartbeat(heartbeat)).await
+    }
+
+    fn track_sequence(&mut self, msg: &Message) {
+        if self.version < 8 {
+            return;
+        }
+        let Ok(text) = msg.to_text() else {
+            return;
+        };
+        if let Ok(Sequenced { seq: Some(seq) }) = serde_json::from_str(text) {
+            self.seq_ack = Some(seq);
+        }
+    }
+
+    // binary messages are DAVE's, prefixed with a sequence number and the opcode
+    async fn handle_binary(&mut self, data: &[u8]) -> Result<(), Error> {
+        let [seq_high, seq_low, opcode, payload @ ..] = data else {
+            debug!("Ignoring binary gateway message of {} bytes", data.len());
+            return Ok(());
+        };
+        if self.version >= 8 {
+            self.seq_ack = Some(u16::from_be_bytes([*seq_high, *seq_low]).into());
+        }
+        #[cfg(feature = "dave")]
+        if let Some(dave) = &mut self.dave {
+            let messages = dave.handle_binary(*opcode, payload);
+            self.send_all(messages).await?;
+        }
+        #[cfg(not(feature = "dave"))]
+        debug!(
+            "Ignoring DAVE message {} of {} bytes",
+            opcode,
+            payload.len()
+        );
+        Ok(())
+    }
+
+    fn acknowledge(&mut self, heartbeat: Heartbeat) {
+        let nonce = heartbeat.nonce();
+        match self.pending_heartbeat {
+            Some((pending, sent_at)) if pending == nonce => {
+                let ping = sent_at.elapsed();
+                trace!("Heartbeat acknowledged after {:?}", ping);
+                self.state
+                    .ping
+                    .store(ping.as_millis() as i64, Ordering::Relaxed);
+                self.pending_heartbeat = None;
+                self.missed_heartbeats = 0;
+            }
+            _ => debug!("Ignoring ack for unknown heartbeat {}", nonce),
+        }
     }
 }
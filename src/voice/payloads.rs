@@ -1,59 +1,112 @@
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
 use serde_with::{serde_as, VecSkipError};
 use tokio_tungstenite::tungstenite::Message;
 
 use crate::crypto::EncryptionMode;
 
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(tag = "op", content = "d")]
+/// A gateway message, `op` picks the variant and `d` holds its data
+#[derive(Debug)]
 pub enum DiscordPayload {
-    #[serde(rename = 0)]
     Identify(Identify),
-
-    #[serde(rename = 1)]
     SelectProtocol(SelectProtocol),
-
-    #[serde(rename = 2)]
     Ready(Ready),
-
-    #[serde(rename = 3)]
-    Heartbeat(u128),
-
-    #[serde(rename = 4)]
+    Heartbeat(Heartbeat),
     SessionDescription(SessionDescription),
-
-    #[serde(rename = 5)]
     Speaking(Speaking),
-
-    #[serde(rename = 6)]
-    HeartbeatACK(u128),
-
-    #[serde(rename = 7)]
+    HeartbeatACK(Heartbeat),
     Resume(Resume),
-
-    #[serde(rename = 8)]
     Hello(Hello),
-
-    #[serde(rename = 9)]
     Resumed,
-
-    #[serde(rename = 10)]
+    ClientConnect(ClientConnect),
+    ClientSsrc(ClientSsrc),
     ClientDisconnect(ClientDisconnect),
+    ClientFlags(ClientFlags),
+    ClientPlatform(ClientPlatform),
+    DavePrepareTransition(DaveTransition),
+    DaveExecuteTransition(DaveTransitionId),
+    DaveTransitionReady(DaveTransitionId),
+    DavePrepareEpoch(DaveEpoch),
+    DaveMlsInvalidCommitWelcome(DaveTransitionId),
+    /// Anything with an op we don't know, or data we can't parse
+    Unknown(Value),
+}
 
-    #[serde(rename = 11)]
-    ClientConnect(serde_json::Value),
+#[derive(Serialize)]
+struct Tagged<'a, T> {
+    op: u8,
+    d: &'a T,
+}
 
-    #[serde(rename = 13)]
-    OtherClientDisconnect(serde_json::Value),
+impl Serialize for DiscordPayload {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        fn tagged<S: Serializer, T: Serialize>(
+            serializer: S,
+            op: u8,
+            d: &T,
+        ) -> Result<S::Ok, S::Error> {
+            Tagged { op, d }.serialize(serializer)
+        }
 
-    #[serde(rename = 18)]
-    ClientFlags(serde_json::Value),
+        match self {
+            DiscordPayload::Identify(d) => tagged(serializer, 0, d),
+            DiscordPayload::SelectProtocol(d) => tagged(serializer, 1, d),
+            DiscordPayload::Ready(d) => tagged(serializer, 2, d),
+            DiscordPayload::Heartbeat(d) => tagged(serializer, 3, d),
+            DiscordPayload::SessionDescription(d) => tagged(serializer, 4, d),
+            DiscordPayload::Speaking(d) => tagged(serializer, 5, d),
+            DiscordPayload::HeartbeatACK(d) => tagged(serializer, 6, d),
+            DiscordPayload::Resume(d) => tagged(serializer, 7, d),
+            DiscordPayload::Hello(d) => tagged(serializer, 8, d),
+            DiscordPayload::Resumed => tagged(serializer, 9, &()),
+            DiscordPayload::ClientConnect(d) => tagged(serializer, 11, d),
+            DiscordPayload::ClientSsrc(d) => tagged(serializer, 12, d),
+            DiscordPayload::ClientDisconnect(d) => tagged(serializer, 13, d),
+            DiscordPayload::ClientFlags(d) => tagged(serializer, 18, d),
+            DiscordPayload::ClientPlatform(d) => tagged(serializer, 20, d),
+            DiscordPayload::DavePrepareTransition(d) => tagged(serializer, 21, d),
+            DiscordPayload::DaveExecuteTransition(d) => tagged(serializer, 22, d),
+            DiscordPayload::DaveTransitionReady(d) => tagged(serializer, 23, d),
+            DiscordPayload::DavePrepareEpoch(d) => tagged(serializer, 24, d),
+            DiscordPayload::DaveMlsInvalidCommitWelcome(d) => tagged(serializer, 31, d),
+            DiscordPayload::Unknown(value) => value.serialize(serializer),
+        }
+    }
+}
 
-    #[serde(rename = 20)]
-    ClientPlatform(serde_json::Value),
+impl<'de> Deserialize<'de> for DiscordPayload {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        fn data<T: DeserializeOwned>(d: Option<&Value>) -> Option<T> {
+            T::deserialize(d?).ok()
+        }
 
-    #[serde(untagged)]
-    Unknown(serde_json::Value),
+        let value = Value::deserialize(deserializer)?;
+        let d = value.get("d");
+        let payload = match value.get("op").and_then(Value::as_u64) {
+            Some(0) => data(d).map(DiscordPayload::Identify),
+            Some(1) => data(d).map(DiscordPayload::SelectProtocol),
+            Some(2) => data(d).map(DiscordPayload::Ready),
+            Some(3) => data(d).map(DiscordPayload::Heartbeat),
+            Some(4) => data(d).map(DiscordPayload::SessionDescription),
+            Some(5) => data(d).map(DiscordPayload::Speaking),
+            Some(6) => data(d).map(DiscordPayload::HeartbeatACK),
+            Some(7) => data(d).map(DiscordPayload::Resume),
+            Some(8) => data(d).map(DiscordPayload::Hello),
+            Some(9) => Some(DiscordPayload::Resumed),
+            Some(11) => data(d).map(DiscordPayload::ClientConnect),
+            Some(12) => data(d).map(DiscordPayload::ClientSsrc),
+            Some(13) => data(d).map(DiscordPayload::ClientDisconnect),
+            Some(18) => data(d).map(DiscordPayload::ClientFlags),
+            Some(20) => data(d).map(DiscordPayload::ClientPlatform),
+            Some(21) => data(d).map(DiscordPayload::DavePrepareTransition),
+            Some(22) => data(d).map(DiscordPayload::DaveExecuteTransition),
+            Some(23) => data(d).map(DiscordPayload::DaveTransitionReady),
+            Some(24) => data(d).map(DiscordPayload::DavePrepareEpoch),
+            Some(31) => data(d).map(DiscordPayload::DaveMlsInvalidCommitWelcome),
+            _ => None,
+        };
+        Ok(payload.unwrap_or(DiscordPayload::Unknown(value)))
+    }
 }
 
 impl From<DiscordPayload> for Message {
@@ -69,6 +122,8 @@ pub struct Identify {
     pub user_id: String,
     pub session_id: String,
     pub token: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_dave_protocol_version: Option<u16>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -98,6 +153,8 @@ pub struct Ready {
 pub struct SessionDescription {
     pub mode: EncryptionMode,
     pub secret_key: [u8; 32],
+    #[serde(default)]
+    pub dave_protocol_version: u16,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -109,9 +166,26 @@ pub struct Speaking {
     pub ssrc: u32,
 }
 
+/// Heartbeat nonce, bare in gateway v7. v8 wraps it together with the sequence
+/// number of the last message received, and acks with just the nonce.
 #[derive(Serialize, Deserialize, Debug)]
-pub struct HeartbeatACK {
-    pub d: u64,
+#[serde(untagged)]
+pub enum Heartbeat {
+    V8 {
+        t: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        seq_ack: Option<i64>,
+    },
+    V7(u64),
+}
+
+impl Heartbeat {
+    pub fn nonce(&self) -> u64 {
+        match self {
+            Heartbeat::V8 { t, .. } => *t,
+            Heartbeat::V7(nonce) => *nonce,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -119,18 +193,69 @@ pub struct Resume {
     pub server_id: String,
     pub session_id: String,
     pub token: String,
+    /// v8 only, discord replays everything after it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seq_ack: Option<i64>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Hello {
     pub v: u8,
-    pub heartbeat_interval: u64,
+    /// Milliseconds, fractional in v8
+    pub heartbeat_interval: f64,
+}
+
+/// The sequence number v8 puts next to `op` and `d` on messages it can replay
+#[derive(Deserialize, Debug)]
+pub struct Sequenced {
+    pub seq: Option<i64>,
 }
 
 // Resumed has no data
 
-// i am not sure what fields opcode 13 actually has
+/// Undocumented, announces the audio ssrc of a user in the channel
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClientSsrc {
+    pub user_id: String,
+    pub audio_ssrc: u32,
+}
+
+/// Users already in the channel or joining it
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClientConnect {
+    pub user_ids: Vec<String>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ClientDisconnect {
     pub user_id: String,
 }
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClientFlags {
+    pub user_id: String,
+    pub flags: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClientPlatform {
+    pub user_id: String,
+    pub platform: Option<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DaveTransition {
+    pub protocol_version: u16,
+    pub transition_id: u16,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DaveTransitionId {
+    pub transition_id: u16,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DaveEpoch {
+    pub protocol_version: u16,
+    pub epoch: u64,
+}
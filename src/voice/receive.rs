@@ -0,0 +1,180 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use byteorder::{ByteOrder, NetworkEndian};
+use tokio::sync::broadcast;
+
+use crate::crypto::{Cipher, EncryptionMode};
+
+use super::dave::FrameKeys;
+
+// packets pile up here for consumers that fall behind, after which they lag
+const PACKET_BUFFER: usize = 256;
+
+/// An opus frame sent by someone else in the voice channel
+#[derive(Debug, Clone)]
+pub struct VoicePacket {
+    pub ssrc: u32,
+    /// `None` until discord tells us who the ssrc belongs to
+    pub user_id: Option<String>,
+    pub sequence: u16,
+    pub timestamp: u32,
+    pub opus: Vec<u8>,
+}
+
+/// Incoming audio of a voice connection. The gateway learns which user owns which
+/// ssrc, the udp socket decrypts the packets and hands them to every subscriber.
+#[derive(Debug)]
+pub struct VoiceReceiver {
+    users: RwLock<HashMap<u32, String>>,
+    packets_tx: broadcast::Sender<VoicePacket>,
+    // DAVE keys, for both directions since the gateway only knows about us
+    frames: Arc<FrameKeys>,
+}
+
+impl Default for VoiceReceiver {
+    fn default() -> Self {
+        Self {
+            users: RwLock::new(HashMap::new()),
+            packets_tx: broadcast::channel(PACKET_BUFFER).0,
+            frames: Arc::new(FrameKeys::new()),
+        }
+    }
+}
+
+impl VoiceReceiver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Every packet received from now on, filter by `user_id` for a single user
+    pub fn subscribe(&self) -> broadcast::Receiver<VoicePacket> {
+        self.packets_tx.subscribe()
+    }
+
+    pub fn has_subscribers(&self) -> bool {
+        self.packets_tx.receiver_count() > 0
+    }
+
+    pub(super) fn frames(&self) -> Arc<FrameKeys> {
+        self.frames.clone()
+    }
+
+    /// Whether audio is end to end encrypted right now
+    pub fn is_e2ee(&self) -> bool {
+        self.frames.is_encrypted()
+    }
+
+    pub fn user(&self, ssrc: u32) -> Option<String> {
+        self.users.read().unwrap().get(&ssrc).cloned()
+    }
+
+    pub fn set_user(&self, ssrc: u32, user_id: String) {
+        self.users.write().unwrap().insert(ssrc, user_id);
+    }
+
+    pub fn remove_user(&self, user_id: &str) {
+        self.users
+            .write()
+            .unwrap()
+            .retain(|_, user| user != user_id);
+    }
+
+    /// Decrypts an incoming udp packet and hands the opus frame inside to the
+    /// subscribers. Anything that isn't a valid voice packet is dropped.
+    pub(super) fn receive(&self, packet: &[u8], mode: &EncryptionMode, cipher: &Cipher) {
+        if !self.has_subscribers() || is_rtcp(packet) {
+            return;
+        }
+        let Some(header) = RtpHeader::parse(packet) else {
+            return;
+        };
+        let Some(opus) = header.decrypt_payload(packet, mode, cipher) else {
+            return;
+        };
+        let user_id = self.user(header.ssrc);
+        let Some(opus) = self.frames.decrypt(user_id.as_deref(), opus) else {
+            return;
+        };
+        // nobody listening anymore is fine
+        _ = self.packets_tx.send(VoicePacket {
+            ssrc: header.ssrc,
+            user_id,
+            sequence: header.sequence,
+            timestamp: header.timestamp,
+            opus,
+        });
+    }
+}
+
+/// RTCP shares the socket with RTP, its packet types 200-204 sit where the RTP
+/// marker bit and payload type would be
+pub(super) fn is_rtcp(packet: &[u8]) -> bool {
+    packet.len() >= 2 && (200..=204).contains(&packet[1])
+}
+
+#[derive(Debug)]
+struct RtpHeader {
+    sequence: u16,
+    timestamp: u32,
+    ssrc: u32,
+    padding: bool,
+    extension: bool,
+    // fixed header and csrcs
+    len: usize,
+}
+
+impl RtpHeader {
+    fn parse(packet: &[u8]) -> Option<Self> {
+        if packet.len() < 12 || packet[0] >> 6 != 2 {
+            return None;
+        }
+        let csrc_count = (packet[0] & 0x0f) as usize;
+        let header = Self {
+            sequence: NetworkEndian::read_u16(&packet[2..4]),
+            timestamp: NetworkEndian::read_u32(&packet[4..8]),
+            ssrc: NetworkEndian::read_u32(&packet[8..12]),
+            padding: packet[0] & 0x20 != 0,
+            extension: packet[0] & 0x10 != 0,
+            len: 12 + 4 * csrc_count,
+        };
+        (packet.len() >= header.len).then_some(header)
+    }
+
+    /// Decrypts the payload and strips the header extension and padding off it
+    fn decrypt_payload(
+        &self,
+        packet: &[u8],
+        mode: &EncryptionMode,
+        cipher: &Cipher,
+    ) -> Option<Vec<u8>> {
+        // the rtpsize modes keep the extension's profile and length in the clear,
+        // the older ones encrypt the extension whole
+        let rtpsize_extension = mode.is_rtpsize() && self.extension;
+        let unencrypted = if rtpsize_extension {
+            self.len + 4
+        } else {
+            self.len
+        };
+        let mut payload = mode.decrypt(packet, unencrypted, cipher).ok()?;
+
+        if self.padding {
+            let padding = *payload.last()? as usize;
+            payload.truncate(payload.len().checked_sub(padding)?);
+        }
+        if self.extension {
+            let extension_len = if rtpsize_extension {
+                4 * NetworkEndian::read_u16(packet.get(self.len + 2..self.len + 4)?) as usize
+            } else {
+                4 + 4 * NetworkEndian::read_u16(payload.get(2..4)?) as usize
+            };
+            if payload.len() < extension_len {
+                return None;
+            }
+            payload.drain(..extension_len);
+        }
+        Some(payload)
+    }
+}
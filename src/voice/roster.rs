@@ -0,0 +1,71 @@
+use std::{collections::HashMap, sync::RwLock};
+
+/// Someone else in the voice channel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelUser {
+    pub user_id: String,
+    /// Discord's client flags, `None` until they are sent
+    pub flags: Option<u32>,
+    /// Discord's platform id (desktop, mobile...), `None` until it is sent
+    pub platform: Option<u8>,
+}
+
+impl ChannelUser {
+    fn new(user_id: String) -> Self {
+        Self {
+            user_id,
+            flags: None,
+            platform: None,
+        }
+    }
+}
+
+/// Who is in the voice channel, as far as the gateway told us
+#[derive(Debug, Default)]
+pub struct VoiceRoster {
+    users: RwLock<HashMap<String, ChannelUser>>,
+}
+
+impl VoiceRoster {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn users(&self) -> Vec<ChannelUser> {
+        let mut users: Vec<_> = self.users.read().unwrap().values().cloned().collect();
+        users.sort_by(|a, b| a.user_id.cmp(&b.user_id));
+        users
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.read().unwrap().is_empty()
+    }
+
+    /// Adds `user_id` if it's not there yet, true if the channel was empty until now
+    pub(super) fn join(&self, user_id: &str) -> bool {
+        let mut users = self.users.write().unwrap();
+        if users.contains_key(user_id) {
+            return false;
+        }
+        users.insert(user_id.to_owned(), ChannelUser::new(user_id.to_owned()));
+        users.len() == 1
+    }
+
+    /// Removes `user_id`, true if that left the channel empty
+    pub(super) fn leave(&self, user_id: &str) -> bool {
+        let mut users = self.users.write().unwrap();
+        users.remove(user_id).is_some() && users.is_empty()
+    }
+
+    pub(super) fn set_flags(&self, user_id: &str, flags: Option<u32>) {
+        if let Some(user) = self.users.write().unwrap().get_mut(user_id) {
+            user.flags = flags;
+        }
+    }
+
+    pub(super) fn set_platform(&self, user_id: &str, platform: Option<u8>) {
+        if let Some(user) = self.users.write().unwrap().get_mut(user_id) {
+            user.platform = platform;
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use std::{
+    sync::atomic::{AtomicBool, AtomicI32, AtomicU32, AtomicU8, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use byteorder::{ByteOrder, NetworkEndian};
+
+use crate::crypto::{Cipher, EncryptionMode};
+
+pub(super) const SENDER_REPORT: u8 = 200;
+pub(super) const RECEIVER_REPORT: u8 = 201;
+// everything in front of the report blocks is sent in the clear
+pub(super) const HEADER_LEN: usize = 8;
+const SENDER_INFO_LEN: usize = 20;
+const REPORT_BLOCK_LEN: usize = 24;
+// seconds between the ntp epoch (1900) and the unix epoch
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+const SAMPLE_RATE: u64 = 48_000;
+
+/// What discord's last receiver report said about the audio we are sending
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RtcpStats {
+    /// Packets lost since the previous report, from 0 to 1
+    pub fraction_lost: f32,
+    /// Packets lost over the whole connection
+    pub packets_lost: i32,
+    /// Interarrival jitter
+    pub jitter: Duration,
+}
+
+/// Receiver report numbers shared between the udp task and the player
+#[derive(Debug, Default)]
+pub struct RtcpState {
+    received: AtomicBool,
+    fraction_lost: AtomicU8,
+    packets_lost: AtomicI32,
+    // in rtp timestamp units
+    jitter: AtomicU32,
+}
+
+impl RtcpState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `None` until the first receiver report about our ssrc arrives
+    pub fn stats(&self) -> Option<RtcpStats> {
+        if !self.received.load(Ordering::Relaxed) {
+            return None;
+        }
+        Some(RtcpStats {
+            fraction_lost: self.fraction_lost.load(Ordering::Relaxed) as f32 / 256.0,
+            packets_lost: self.packets_lost.load(Ordering::Relaxed),
+            jitter: Duration::from_micros(
+                self.jitter.load(Ordering::Relaxed) as u64 * 1_000_000 / SAMPLE_RATE,
+            ),
+        })
+    }
+
+    fn update(&self, block: &ReportBlock) {
+        self.fraction_lost
+            .store(block.fraction_lost, Ordering::Relaxed);
+        self.packets_lost
+            .store(block.packets_lost, Ordering::Relaxed);
+        self.jitter.store(block.jitter, Ordering::Relaxed);
+        self.received.store(true, Ordering::Relaxed);
+    }
+
+    /// Decrypts an incoming rtcp packet and records the report block about `ssrc`,
+    /// if there is one. Anything else is ignored.
+    pub(super) fn receive(&self, packet: &[u8], ssrc: u32, mode: &EncryptionMode, cipher: &Cipher) {
+        let Ok(body) = mode.decrypt(packet, HEADER_LEN, cipher) else {
+            return;
+        };
+        let block = report_blocks(&packet[..HEADER_LEN], &body).find(|b| b.ssrc == ssrc);
+        if let Some(block) = block {
+            self.update(&block);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ReportBlock {
+    ssrc: u32,
+    fraction_lost: u8,
+    packets_lost: i32,
+    jitter: u32,
+}
+
+/// The report blocks of a sender or receiver report. `body` is everything after
+/// the 8 byte header, since that is the encrypted part.
+fn report_blocks<'a>(header: &[u8], body: &'a [u8]) -> impl Iterator<Item = ReportBlock> + 'a {
+    let count = (header[0] & 0x1f) as usize;
+    let offset = match header[1] {
+        SENDER_REPORT => SENDER_INFO_LEN,
+        RECEIVER_REPORT => 0,
+        _ => body.len(),
+    };
+    body.get(offset..)
+        .unwrap_or_default()
+        .chunks_exact(REPORT_BLOCK_LEN)
+        .take(count)
+        .map(|block| ReportBlock {
+            ssrc: NetworkEndian::read_u32(&block[0..4]),
+            fraction_lost: block[4],
+            // 24 bit signed, shift it up and back down to keep the sign
+            packets_lost: NetworkEndian::read_i32(&block[4..8]) << 8 >> 8,
+            jitter: NetworkEndian::read_u32(&block[12..16]),
+        })
+}
+
+/// Builds an unencrypted sender report without report blocks, we don't
+/// report on what we receive
+pub(super) fn sender_report(
+    ssrc: u32,
+    now: SystemTime,
+    rtp_timestamp: u32,
+    packets: u32,
+    octets: u32,
+) -> [u8; HEADER_LEN + SENDER_INFO_LEN] {
+    let mut report = [0u8; HEADER_LEN + SENDER_INFO_LEN];
+    report[0] = 0x80;
+    report[1] = SENDER_REPORT;
+    // length in 32 bit words minus one
+    NetworkEndian::write_u16(
+        &mut report[2..4],
+        ((HEADER_LEN + SENDER_INFO_LEN) / 4 - 1) as u16,
+    );
+    NetworkEndian::write_u32(&mut report[4..8], ssrc);
+
+    let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let fraction = ((since_epoch.subsec_nanos() as u64) << 32) / 1_000_000_000;
+    NetworkEndian::write_u32(
+        &mut report[8..12],
+        (since_epoch.as_secs() + NTP_UNIX_OFFSET) as u32,
+    );
+    NetworkEndian::write_u32(&mut report[12..16], fraction as u32);
+    NetworkEndian::write_u32(&mut report[16..20], rtp_timestamp);
+    NetworkEndian::write_u32(&mut report[20..24], packets);
+    NetworkEndian::write_u32(&mut report[24..28], octets);
+    report
+}
@@ -0,0 +1,212 @@
+use std::{
+    collections::VecDeque,
+    sync::{atomic::Ordering, Arc},
+    thread,
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    sync::mpsc::{error::TryRecvError, Receiver, UnboundedSender},
+    time,
+};
+use tracing::{info, warn};
+
+use super::{speaking::SpeakingState, PlaybackState, VoiceEvent, FRAME_DURATION};
+use crate::source::SourceError;
+
+// frames of silence sent whenever audio stops, enough for the other end's
+// decoder to not interpolate the gap
+const SILENCE_TAIL_FRAMES: u32 = 5;
+
+/// Hands out 20ms send slots on absolute deadlines, so time spent waiting on the
+/// demuxer or the socket doesn't pile up into drift
+#[derive(Debug)]
+pub(super) struct FrameScheduler {
+    start: Instant,
+    // index of the next slot
+    slot: u64,
+}
+
+impl FrameScheduler {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            slot: 0,
+        }
+    }
+
+    /// Sleeps until the next slot. Slots that already passed while we were busy are
+    /// skipped instead of being sent in a burst, their number is returned.
+    pub async fn tick(&mut self) -> u64 {
+        time::sleep_until(self.deadline().into()).await;
+        self.advance()
+    }
+
+    /// [`Self::tick`] for threads outside of tokio
+    pub fn blocking_tick(&mut self) -> u64 {
+        thread::sleep(self.deadline().saturating_duration_since(Instant::now()));
+        self.advance()
+    }
+
+    fn deadline(&self) -> Instant {
+        self.start + FRAME_DURATION * self.slot as u32
+    }
+
+    fn advance(&mut self) -> u64 {
+        let current = (self.start.elapsed().as_nanos() / FRAME_DURATION.as_nanos()) as u64;
+        let missed = current.saturating_sub(self.slot);
+        self.slot = current.max(self.slot) + 1;
+        missed
+    }
+}
+
+/// What to send in a slot
+#[derive(Debug)]
+pub(super) enum Frame {
+    Audio(Vec<u8>),
+    Silence,
+    /// Nothing to send, and the silence after the last audio is sent
+    Idle,
+    /// The track is over, send a last frame of silence
+    End,
+}
+
+/// Decides what goes out in each slot of a track
+#[derive(Debug)]
+pub(super) struct FrameSource {
+    state: Arc<PlaybackState>,
+    speaking: Arc<SpeakingState>,
+    buffered: VecDeque<Vec<u8>>,
+    packet_rx: Receiver<Vec<u8>>,
+    end_frames: Option<u64>,
+    silent_frames: u32,
+    stuck_threshold: Duration,
+    // slots in a row the source had nothing for
+    starved_frames: u64,
+    // ended on its own, rather than being stopped
+    ended: bool,
+    // why the source ended, if it broke off
+    error: Option<Arc<SourceError>>,
+    events_tx: UnboundedSender<VoiceEvent>,
+}
+
+impl FrameSource {
+    pub fn new(
+        state: Arc<PlaybackState>,
+        speaking: Arc<SpeakingState>,
+        packet_rx: Receiver<Vec<u8>>,
+        end_frames: Option<u64>,
+        stuck_threshold: Duration,
+        events_tx: UnboundedSender<VoiceEvent>,
+    ) -> Self {
+        Self {
+            state,
+            speaking,
+            buffered: VecDeque::new(),
+            packet_rx,
+            end_frames,
+            // nothing was said yet, so there's nothing to trail off
+            silent_frames: SILENCE_TAIL_FRAMES,
+            stuck_threshold,
+            starved_frames: 0,
+            ended: false,
+            error: None,
+            events_tx,
+        }
+    }
+
+    /// Reads ahead until `frames` packets are buffered or the source ends, then
+    /// lets discord know audio is coming
+    pub async fn prebuffer(&mut self, frames: usize) {
+        while self.buffered.len() < frames {
+            match self.packet_rx.recv().await {
+                Some(packet) => self.buffered.push_back(packet),
+                None => break,
+            }
+        }
+        if !self.state.is_paused() {
+            self.speaking.start();
+        }
+    }
+
+    /// The frame for the next slot, after `missed` slots went by unused
+    pub fn next(&mut self, missed: u64) -> Frame {
+        let state = &self.state;
+        state.deficit.fetch_add(missed, Ordering::Relaxed);
+
+        if state.is_finished() {
+            return Frame::End;
+        }
+        if state.is_paused() {
+            return self.silence();
+        }
+        if self
+            .end_frames
+            .is_some_and(|end| state.frames.load(Ordering::Relaxed) >= end)
+        {
+            self.ended = true;
+            return Frame::End;
+        }
+
+        let packet = match self.buffered.pop_front() {
+            Some(packet) => Ok(packet),
+            None => self.packet_rx.try_recv(),
+        };
+        match packet {
+            Ok(packet) => {
+                state.frames.fetch_add(1, Ordering::Relaxed);
+                state.sent.fetch_add(1, Ordering::Relaxed);
+                self.silent_frames = 0;
+                self.starved_frames = 0;
+                self.speaking.start();
+                Frame::Audio(packet)
+            }
+            Err(TryRecvError::Empty) => {
+                // the source fell behind, wait for it instead of bursting later
+                state.nulled.fetch_add(1, Ordering::Relaxed);
+                self.starved_frames += 1;
+                // only once, until audio comes through again
+                if self.starved_frames == self.stuck_frames() {
+                    warn!("No audio from the source for {:?}", self.stuck_threshold);
+                    _ = self.events_tx.send(VoiceEvent::TrackStuck {
+                        threshold: self.stuck_threshold,
+                    });
+                }
+                self.silence()
+            }
+            // Channel closed, no more packets
+            Err(TryRecvError::Disconnected) => {
+                self.ended = true;
+                self.error = state.error.lock().unwrap().take().map(Arc::new);
+                Frame::End
+            }
+        }
+    }
+
+    fn stuck_frames(&self) -> u64 {
+        (self.stuck_threshold.as_millis() / FRAME_DURATION.as_millis()).max(1) as u64
+    }
+
+    // the silence tail after audio stops, then nothing
+    fn silence(&mut self) -> Frame {
+        if self.silent_frames < SILENCE_TAIL_FRAMES {
+            self.silent_frames += 1;
+            Frame::Silence
+        } else {
+            self.speaking.stop();
+            Frame::Idle
+        }
+    }
+
+    pub fn finish(&self) {
+        self.speaking.stop();
+        self.state.finished.store(true, Ordering::Relaxed);
+        info!(stats = ?self.state.frame_stats(), "finished playing audio");
+        // sent after the playback is marked finished, so the player sees it done
+        if let Some(error) = &self.error {
+            _ = self.events_tx.send(VoiceEvent::TrackFailed(error.clone()));
+        } else if self.ended {
+            _ = self.events_tx.send(VoiceEvent::TrackFinished);
+        }
+    }
+}
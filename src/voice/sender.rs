@@ -0,0 +1,181 @@
+use std::{
+    net::UdpSocket,
+    sync::{Arc, Mutex, OnceLock},
+    thread::{self, Thread},
+};
+
+use tracing::{debug, error};
+
+use super::{
+    scheduler::{Frame, FrameScheduler, FrameSource},
+    udp::SendHandle,
+};
+
+static SHARED_SENDER: OnceLock<SharedSender> = OnceLock::new();
+
+/// One thread sending the audio of every player, instead of a timer task and a
+/// udp task each. The frames due in a tick are batched by socket, and each batch
+/// goes out with a single `sendmmsg` on linux.
+pub(super) struct SharedSender {
+    lanes: Mutex<Vec<Lane>>,
+    thread: Thread,
+}
+
+struct Lane {
+    source: FrameSource,
+    handle: SendHandle,
+}
+
+impl SharedSender {
+    /// The sender thread, started on first use
+    pub fn get() -> &'static Self {
+        SHARED_SENDER.get_or_init(|| {
+            // the thread waits for this initialization to finish before it runs
+            let thread = thread::Builder::new()
+                .name("voice-sender".to_string())
+                .spawn(|| Self::get().run())
+                .expect("failed to spawn the voice sender thread");
+            Self {
+                lanes: Mutex::new(Vec::new()),
+                thread: thread.thread().clone(),
+            }
+        })
+    }
+
+    /// Sends `source` on the connection of `handle` until the track ends or the
+    /// connection goes away
+    pub fn add(&self, source: FrameSource, handle: SendHandle) {
+        self.lanes.lock().unwrap().push(Lane { source, handle });
+        self.thread.unpark();
+    }
+
+    fn run(&self) {
+        loop {
+            // sleep until there's something to send, spurious wakeups just loop
+            if self.lanes.lock().unwrap().is_empty() {
+                thread::park();
+                continue;
+            }
+            let mut scheduler = FrameScheduler::new();
+            loop {
+                let missed = scheduler.blocking_tick();
+                let mut batches = Batches::default();
+                let mut lanes = self.lanes.lock().unwrap();
+                lanes.retain_mut(|lane| lane.next(missed, &mut batches));
+                let done = lanes.is_empty();
+                // sending doesn't need the lanes, players can come and go meanwhile
+                drop(lanes);
+                batches.send();
+                if done {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+impl Lane {
+    // queues the lane's frame for this tick, false once the lane is done and
+    // should be dropped
+    fn next(&mut self, missed: u64, batches: &mut Batches) -> bool {
+        let Some(rtp) = self.handle.rtp.upgrade() else {
+            self.source.finish();
+            return false;
+        };
+        let (packet, done) = match self.source.next(missed) {
+            Frame::Audio(packet) => (rtp.lock().unwrap().packet(&packet), false),
+            Frame::Silence => (rtp.lock().unwrap().silence(), false),
+            Frame::Idle => return true,
+            Frame::End => (rtp.lock().unwrap().silence(), true),
+        };
+        match packet {
+            Ok(packet) => batches.push(&self.handle.socket, packet),
+            Err(e) => {
+                error!("error encrypting audio: {}", e);
+                self.source.finish();
+                return false;
+            }
+        }
+        if done {
+            self.source.finish();
+        }
+        !done
+    }
+}
+
+/// The packets of a tick, by the socket they go out on
+#[derive(Default)]
+struct Batches(Vec<(Arc<UdpSocket>, Vec<Vec<u8>>)>);
+
+impl Batches {
+    fn push(&mut self, socket: &Arc<UdpSocket>, packet: Vec<u8>) {
+        match self.0.iter_mut().find(|(s, _)| Arc::ptr_eq(s, socket)) {
+            Some((_, packets)) => packets.push(packet),
+            None => self.0.push((socket.clone(), vec![packet])),
+        }
+    }
+
+    fn send(self) {
+        for (socket, packets) in self.0 {
+            send_batch(&socket, &packets);
+        }
+    }
+}
+
+// the socket is non-blocking, a full buffer drops the frame
+#[cfg(target_os = "linux")]
+fn send_batch(socket: &UdpSocket, packets: &[Vec<u8>]) {
+    use std::{io, os::fd::AsRawFd};
+
+    let mut iovecs: Vec<libc::iovec> = packets
+        .iter()
+        .map(|packet| libc::iovec {
+            iov_base: packet.as_ptr() as *mut libc::c_void,
+            iov_len: packet.len(),
+        })
+        .collect();
+    // the socket is connected, so the messages need no address
+    let mut messages: Vec<libc::mmsghdr> = iovecs
+        .iter_mut()
+        .map(|iovec| {
+            // SAFETY: mmsghdr is plain old data, all zeroes is an empty message
+            let mut message: libc::mmsghdr = unsafe { std::mem::zeroed() };
+            message.msg_hdr.msg_iov = iovec;
+            message.msg_hdr.msg_iovlen = 1;
+            message
+        })
+        .collect();
+
+    let mut sent = 0;
+    while sent < messages.len() {
+        let remaining = &mut messages[sent..];
+        // SAFETY: every message points at an iovec in `iovecs`, which point
+        // into `packets`, and all of them outlive the call
+        let result = unsafe {
+            libc::sendmmsg(
+                socket.as_raw_fd(),
+                remaining.as_mut_ptr(),
+                remaining.len() as libc::c_uint,
+                0,
+            )
+        };
+        match result {
+            // the error is about the first message that wasn't sent, skip it. a
+            // congested socket does this every frame, too often for an error
+            -1 => {
+                debug!("Packet dropped? {:?}", io::Error::last_os_error());
+                sent += 1;
+            }
+            n => sent += n as usize,
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_batch(socket: &UdpSocket, packets: &[Vec<u8>]) {
+    for packet in packets {
+        if let Err(e) = socket.send(packet) {
+            debug!("Packet dropped? {:?}", e);
+        }
+    }
+}
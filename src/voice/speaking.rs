@@ -0,0 +1,89 @@
+use std::sync::Mutex;
+
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::client::payloads::SpeakingFlags;
+
+use super::payloads::{DiscordPayload, Speaking};
+
+const MICROPHONE: u8 = 1 << 0;
+const SOUNDSHARE: u8 = 1 << 1;
+const PRIORITY: u8 = 1 << 2;
+
+impl SpeakingFlags {
+    /// The bits of the speaking field, with the microphone bit discord needs
+    /// for audio to go through at all
+    fn bits(&self) -> u8 {
+        let mut bits = MICROPHONE;
+        if self.soundshare {
+            bits |= SOUNDSHARE;
+        }
+        if self.priority {
+            bits |= PRIORITY;
+        }
+        bits
+    }
+}
+
+#[derive(Debug, Default)]
+struct Flags {
+    configured: SpeakingFlags,
+    // what discord was last told, 0 when not speaking
+    sent: u8,
+}
+
+/// Tells discord when we start and stop sending audio, and how. Speaking is set
+/// when frames start going out and cleared once the silence after them is sent.
+#[derive(Debug)]
+pub(super) struct SpeakingState {
+    ssrc: u32,
+    to_gateway_tx: UnboundedSender<DiscordPayload>,
+    flags: Mutex<Flags>,
+}
+
+impl SpeakingState {
+    pub fn new(ssrc: u32, to_gateway_tx: UnboundedSender<DiscordPayload>) -> Self {
+        Self {
+            ssrc,
+            to_gateway_tx,
+            flags: Mutex::new(Flags::default()),
+        }
+    }
+
+    pub fn flags(&self) -> SpeakingFlags {
+        self.flags.lock().unwrap().configured
+    }
+
+    /// Changes how we speak, right away if we are speaking
+    pub fn set_flags(&self, configured: SpeakingFlags) {
+        let mut flags = self.flags.lock().unwrap();
+        flags.configured = configured;
+        if flags.sent != 0 {
+            self.update(&mut flags, configured.bits());
+        }
+    }
+
+    pub fn start(&self) {
+        let mut flags = self.flags.lock().unwrap();
+        let bits = flags.configured.bits();
+        self.update(&mut flags, bits);
+    }
+
+    pub fn stop(&self) {
+        self.update(&mut self.flags.lock().unwrap(), 0);
+    }
+
+    fn update(&self, flags: &mut Flags, bits: u8) {
+        if flags.sent == bits {
+            return;
+        }
+        flags.sent = bits;
+        // the gateway being gone takes the whole connection with it
+        _ = self.to_gateway_tx.send(DiscordPayload::Speaking(Speaking {
+            speaking: bits,
+            delay: Some(0),
+            user_id: None,
+            ssrc: self.ssrc,
+        }));
+    }
+}
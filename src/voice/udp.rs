@@ -1,24 +1,55 @@
 use std::{
-    io::{Error, ErrorKind},
-    net::SocketAddr,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     str::FromStr,
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, SystemTime},
 };
 
 use anyhow::Result;
-use byteorder::{ByteOrder, NetworkEndian};
-
-use crypto_secretbox::XSalsa20Poly1305;
+use byteorder::{ByteOrder, LittleEndian, NetworkEndian};
+use socket2::{Domain, Protocol, Socket, Type};
+use thiserror::Error;
 use tokio::{
     net::UdpSocket,
-    sync::mpsc::{channel, Receiver, Sender},
+    sync::mpsc::{channel, Receiver, Sender, UnboundedSender},
+    time::{self, interval, Instant, MissedTickBehavior},
 };
-use tracing::error;
+use tracing::{debug, error, warn};
 
-use crate::crypto::EncryptionMode;
+use crate::{
+    config::VoiceConfiguration,
+    crypto::{Cipher, EncryptionMode},
+};
 
-use super::VoiceError;
+use super::{
+    dave::FrameKeys,
+    receive::{is_rtcp, VoiceReceiver},
+    rtcp::{self, RtcpState},
+    VoiceError, VoiceEvent,
+};
 
 const SILENCE_FRAME: [u8; 3] = [0xf8, 0xff, 0xfe];
+// comfortably larger than any voice packet discord sends
+const RECEIVE_BUFFER_SIZE: usize = 2048;
+const SENDER_REPORT_INTERVAL: Duration = Duration::from_secs(5);
+// keepalives hold the nat mapping open while nothing plays, discord echoes them
+// back, so they double as a check that the path still works
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(5);
+const KEEPALIVE_LEN: usize = 8;
+// a minute without an echo
+const MAX_MISSED_KEEPALIVES: u32 = 12;
+const DISCOVERY_LEN: usize = 74;
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(2);
+const DISCOVERY_ATTEMPTS: u32 = 3;
+
+#[derive(Error, Debug)]
+pub enum IpDiscoveryError {
+    #[error("no response after {0} attempts")]
+    TimedOut(u32),
+    #[error("malformed response: {0}")]
+    MalformedResponse(&'static str),
+}
 
 #[derive(Debug)]
 pub enum UDPMessage {
@@ -32,9 +63,73 @@ pub struct VoiceUDP {
     mode: EncryptionMode,
     player_rx: Receiver<UDPMessage>,
     socket: UdpSocket,
+    // the same socket, for sending from outside of tokio
+    std_socket: Arc<std::net::UdpSocket>,
+    receiver: Arc<VoiceReceiver>,
+    rtcp: Arc<RtcpState>,
+    events_tx: UnboundedSender<VoiceEvent>,
+}
+
+/// Everything needed to send audio on a connection: the rtp fields, the sender
+/// report counters and the cipher, which carries the nonce
+pub struct RtpSender {
+    ssrc: u32,
     sequence: u16,
     timestamp: u32,
-    cipher: Option<XSalsa20Poly1305>,
+    mode: EncryptionMode,
+    cipher: Cipher,
+    // end to end encryption of the opus frames, under the transport encryption
+    frames: Arc<FrameKeys>,
+    // sender report counters, wrap around like the rtp fields do
+    packets_sent: u32,
+    octets_sent: u32,
+}
+
+impl RtpSender {
+    /// Builds the next encrypted rtp packet carrying `payload`
+    pub fn packet(&mut self, payload: &[u8]) -> Result<Vec<u8>> {
+        let mut header = [0u8; 12];
+        header[0] = 0x80;
+        header[1] = 0x78;
+        NetworkEndian::write_u16(&mut header[2..4], self.sequence);
+        NetworkEndian::write_u32(&mut header[4..8], self.timestamp);
+        NetworkEndian::write_u32(&mut header[8..12], self.ssrc);
+
+        let payload = self.frames.encrypt(payload);
+        let encrypted = self.mode.encrypt(&payload, &header, &mut self.cipher)?;
+        self.packets_sent = self.packets_sent.wrapping_add(1);
+        self.octets_sent = self.octets_sent.wrapping_add(payload.len() as u32);
+        self.sequence = self.sequence.wrapping_add(1);
+        self.timestamp = self.timestamp.wrapping_add(960);
+        Ok(encrypted)
+    }
+
+    pub fn silence(&mut self) -> Result<Vec<u8>> {
+        self.packet(&SILENCE_FRAME)
+    }
+
+    /// An encrypted rtcp sender report, `None` before any audio was sent
+    fn sender_report(&mut self) -> Option<Result<Vec<u8>>> {
+        if self.packets_sent == 0 {
+            return None;
+        }
+        let report = rtcp::sender_report(
+            self.ssrc,
+            SystemTime::now(),
+            self.timestamp,
+            self.packets_sent,
+            self.octets_sent,
+        );
+        let (header, body) = report.split_at(rtcp::HEADER_LEN);
+        Some(self.mode.encrypt(body, header, &mut self.cipher))
+    }
+}
+
+/// Sending side of a running connection, for the shared sender thread
+#[derive(Clone)]
+pub struct SendHandle {
+    pub rtp: Weak<Mutex<RtpSender>>,
+    pub socket: Arc<std::net::UdpSocket>,
 }
 
 impl VoiceUDP {
@@ -44,9 +139,19 @@ impl VoiceUDP {
         ssrc: u32,
         dest_ip: SocketAddr,
         mode: EncryptionMode,
+        receiver: Arc<VoiceReceiver>,
+        rtcp: Arc<RtcpState>,
+        events_tx: UnboundedSender<VoiceEvent>,
+        config: &VoiceConfiguration,
     ) -> Result<(Self, Sender<UDPMessage>), VoiceError> {
         let (udp_tx, player_rx) = channel(1);
-        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        // the clone shares the socket and its non-blocking mode
+        let std_socket = Arc::new(bind(
+            dest_ip,
+            config.bind_address,
+            config.interface.as_deref(),
+        )?);
+        let socket = UdpSocket::from_std(std_socket.try_clone()?)?;
         socket.connect(dest_ip).await?;
         let src_ip = Self::ip_discovery(&socket, ssrc).await?;
 
@@ -57,88 +162,212 @@ impl VoiceUDP {
                 mode,
                 player_rx,
                 socket,
-                sequence: 0,
-                timestamp: 0,
-                cipher: None,
+                std_socket,
+                receiver,
+                rtcp,
+                events_tx,
             },
             udp_tx,
         ))
     }
 
-    pub async fn run(&mut self) -> Result<()> {
-        let mut cipher = self.cipher.take().ok_or(Error::new(
-            ErrorKind::Other,
-            "Cannot run UDP connection without a secret key",
-        ))?;
+    /// Spawns the task sending what arrives on the player channel and receiving
+    /// everything discord sends
+    pub fn start(self, cipher: Cipher) -> SendHandle {
+        let rtp = Arc::new(Mutex::new(RtpSender {
+            ssrc: self.ssrc,
+            sequence: 0,
+            timestamp: 0,
+            mode: self.mode,
+            cipher,
+            frames: self.receiver.frames(),
+            packets_sent: 0,
+            octets_sent: 0,
+        }));
+        let handle = SendHandle {
+            rtp: Arc::downgrade(&rtp),
+            socket: self.std_socket.clone(),
+        };
+        tokio::spawn(async move {
+            if let Err(e) = self.run(rtp).await {
+                error!("{}", e);
+            }
+        });
+        handle
+    }
 
+    async fn run(mut self, rtp: Arc<Mutex<RtpSender>>) -> Result<()> {
+        let mut receive_buf = vec![0u8; RECEIVE_BUFFER_SIZE];
+        let mut sender_reports = interval(SENDER_REPORT_INTERVAL);
+        sender_reports.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut keepalives = interval(KEEPALIVE_INTERVAL);
+        keepalives.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut keepalive_counter = 0u32;
+        let mut missed_keepalives = 0u32;
         loop {
-            let msg = match self.player_rx.recv().await {
-                Some(msg) => msg,
-                None => {
-                    // Player and VoiceManager are dropped
-                    return Ok(());
+            let msg = tokio::select! {
+                msg = self.player_rx.recv() => match msg {
+                    Some(msg) => msg,
+                    None => {
+                        // Player and VoiceManager are dropped
+                        return Ok(());
+                    }
+                },
+                received = self.socket.recv(&mut receive_buf) => {
+                    let packet = match received {
+                        Ok(len) => &receive_buf[..len],
+                        Err(e) => {
+                            error!("Failed to receive packet: {:?}", e);
+                            continue;
+                        }
+                    };
+                    if packet.len() == KEEPALIVE_LEN {
+                        missed_keepalives = 0;
+                        continue;
+                    }
+                    let sender = rtp.lock().unwrap();
+                    if is_rtcp(packet) {
+                        self.rtcp.receive(packet, self.ssrc, &sender.mode, &sender.cipher);
+                    } else {
+                        self.receiver.receive(packet, &sender.mode, &sender.cipher);
+                    }
+                    continue;
                 }
-            };
-
-            let mut packet = vec![0u8; 12];
-            packet[0] = 0x80;
-            packet[1] = 0x78;
-            NetworkEndian::write_u16(&mut packet[2..4], self.sequence);
-            NetworkEndian::write_u32(&mut packet[4..8], self.timestamp);
-            NetworkEndian::write_u32(&mut packet[8..12], self.ssrc);
-
-            match msg {
-                UDPMessage::Silence => {
-                    let encrypted = self.mode.encrypt(&SILENCE_FRAME, &packet, &mut cipher)?;
-                    if let Err(e) = self.socket.send(&encrypted).await {
-                        error!("Packet dropped? {:?}", e);
+                _ = sender_reports.tick() => {
+                    let report = rtp.lock().unwrap().sender_report();
+                    if let Some(report) = report {
+                        if let Err(e) = self.socket.send(&report?).await {
+                            error!("Sender report dropped? {:?}", e);
+                        }
                     }
+                    continue;
                 }
-                UDPMessage::Audio(audio) => {
-                    let encrypted = self.mode.encrypt(&audio, &packet, &mut cipher)?;
-                    if let Err(e) = self.socket.send(&encrypted).await {
-                        error!("Packet dropped? {:?}", e);
+                _ = keepalives.tick() => {
+                    if missed_keepalives == MAX_MISSED_KEEPALIVES {
+                        warn!("Voice server stopped answering keepalives");
+                        // nobody listening means the connection is on its way out anyway
+                        _ = self.events_tx.send(VoiceEvent::UdpTimeout);
+                    }
+                    missed_keepalives = missed_keepalives.saturating_add(1);
+
+                    let mut keepalive = [0u8; KEEPALIVE_LEN];
+                    LittleEndian::write_u32(&mut keepalive[..4], keepalive_counter);
+                    keepalive_counter = keepalive_counter.wrapping_add(1);
+                    if let Err(e) = self.socket.send(&keepalive).await {
+                        error!("Keepalive dropped? {:?}", e);
                     }
+                    continue;
                 }
+            };
+
+            let encrypted = match msg {
+                UDPMessage::Silence => rtp.lock().unwrap().silence()?,
+                UDPMessage::Audio(audio) => rtp.lock().unwrap().packet(&audio)?,
+            };
+            if let Err(e) = self.socket.send(&encrypted).await {
+                debug!("Packet dropped? {:?}", e);
             }
-            self.sequence += 1;
-            self.timestamp += 960;
         }
     }
 
-    pub fn cipher_mut(&mut self) -> &mut Option<XSalsa20Poly1305> {
-        &mut self.cipher
-    }
-
     pub fn local_addr(&self) -> SocketAddr {
         self.local_addr
     }
 
     async fn ip_discovery(socket: &UdpSocket, ssrc: u32) -> Result<SocketAddr, VoiceError> {
-        let mut discovery_buf = vec![0u8; 74];
-        NetworkEndian::write_u16(&mut discovery_buf[0..2], 0x1);
-        NetworkEndian::write_u16(&mut discovery_buf[2..4], 70);
-        NetworkEndian::write_u32(&mut discovery_buf[4..8], ssrc);
-
-        socket.send(&discovery_buf).await?;
-        socket.recv(&mut discovery_buf).await?;
-        if NetworkEndian::read_u16(&discovery_buf[0..2]) == 0x2 {
-            if NetworkEndian::read_u16(&discovery_buf[2..4]) == 70 {
-                if NetworkEndian::read_u32(&discovery_buf[4..8]) == ssrc {
-                    let null_byte_index = &discovery_buf
-                        .iter()
-                        .skip(8)
-                        .position(|&x| x == 0)
-                        .expect("null byte should exist");
-                    let ip = std::str::from_utf8(&discovery_buf[8..8 + null_byte_index])
-                        .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
-                    let port = NetworkEndian::read_u16(&discovery_buf[discovery_buf.len() - 2..]);
-                    let addr = SocketAddr::from_str(&format!("{}:{}", ip, port))
-                        .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
-                    return Ok(addr);
+        let mut request = [0u8; DISCOVERY_LEN];
+        NetworkEndian::write_u16(&mut request[0..2], 0x1);
+        NetworkEndian::write_u16(&mut request[2..4], 70);
+        NetworkEndian::write_u32(&mut request[4..8], ssrc);
+
+        let mut response = [0u8; DISCOVERY_LEN];
+        for attempt in 1..=DISCOVERY_ATTEMPTS {
+            socket.send(&request).await?;
+            let deadline = Instant::now() + DISCOVERY_TIMEOUT;
+            // anything that isn't our response is skipped until the attempt times out
+            loop {
+                match time::timeout_at(deadline, socket.recv(&mut response)).await {
+                    Ok(Ok(received)) => {
+                        if let Some(addr) = parse_discovery_response(&response[..received], ssrc)? {
+                            return Ok(addr);
+                        }
+                    }
+                    // e.g. an icmp port unreachable, wait the attempt out and try again
+                    Ok(Err(e)) => {
+                        debug!("IP discovery attempt {} failed: {:?}", attempt, e);
+                        time::sleep_until(deadline).await;
+                        break;
+                    }
+                    Err(_) => {
+                        debug!("IP discovery attempt {} timed out", attempt);
+                        break;
+                    }
                 }
             }
         }
-        Err(Error::new(ErrorKind::Other, "Failed to discover IP"))?
+        Err(IpDiscoveryError::TimedOut(DISCOVERY_ATTEMPTS).into())
     }
 }
+
+/// `None` if the packet isn't a discovery response for `ssrc` at all
+fn parse_discovery_response(
+    response: &[u8],
+    ssrc: u32,
+) -> Result<Option<SocketAddr>, IpDiscoveryError> {
+    if response.len() < 8
+        || NetworkEndian::read_u16(&response[0..2]) != 0x2
+        || NetworkEndian::read_u32(&response[4..8]) != ssrc
+    {
+        return Ok(None);
+    }
+    if response.len() != DISCOVERY_LEN || NetworkEndian::read_u16(&response[2..4]) != 70 {
+        return Err(IpDiscoveryError::MalformedResponse("wrong length"));
+    }
+    let address = &response[8..DISCOVERY_LEN - 2];
+    let address_len =
+        address
+            .iter()
+            .position(|&x| x == 0)
+            .ok_or(IpDiscoveryError::MalformedResponse(
+                "address is not null terminated",
+            ))?;
+    let ip = std::str::from_utf8(&address[..address_len])
+        .ok()
+        .and_then(|ip| IpAddr::from_str(ip).ok())
+        .ok_or(IpDiscoveryError::MalformedResponse("invalid address"))?;
+    let port = NetworkEndian::read_u16(&response[DISCOVERY_LEN - 2..]);
+    Ok(Some(SocketAddr::new(ip, port)))
+}
+
+/// A non-blocking socket of the same family as `dest`, on `address` or any
+/// address if unset
+fn bind(
+    dest: SocketAddr,
+    address: Option<IpAddr>,
+    interface: Option<&str>,
+) -> io::Result<std::net::UdpSocket> {
+    let address = address.unwrap_or(match dest {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    });
+    let socket = Socket::new(Domain::for_address(dest), Type::DGRAM, Some(Protocol::UDP))?;
+    if let Some(interface) = interface {
+        bind_device(&socket, interface)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::new(address, 0).into())?;
+    Ok(socket.into())
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn bind_device(socket: &Socket, interface: &str) -> io::Result<()> {
+    socket.bind_device(Some(interface.as_bytes()))
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn bind_device(_socket: &Socket, _interface: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "binding to an interface is only supported on linux",
+    ))
+}
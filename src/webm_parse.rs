@@ -19,13 +19,13 @@ macro_rules! ready_next {
 
 #[derive(Debug, Clone)]
 enum ParserStateMachine {
-    ReadElementIdLength,
+    IdLength,
     // u32 is the size of id, u32 is the id so far
-    ReadElementId(usize, u32),
-    ReadElementSizeLength(),
-    ReadElementSize(usize, u64),
+    Id(usize, u32),
+    SizeLength(),
+    Size(usize, u64),
     // u64 is the size of the element data
-    ReadElementData(usize),
+    Data(usize),
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -130,16 +130,16 @@ impl<T: AsyncRead + AsyncSeek + Unpin + Send> Stream for WebmStream<T> {
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Vec<u8>>> {
         match self.parser_state {
-            ParserStateMachine::ReadElementIdLength => {
+            ParserStateMachine::IdLength => {
                 let buf = ready_next!(self.read_exact_bytes(cx, 1));
 
                 let first_byte = buf[0];
 
                 let size = first_byte.leading_zeros() as usize + 1;
-                self.parser_state = ParserStateMachine::ReadElementId(size, first_byte as u32);
-                return self.poll_next(cx);
+                self.parser_state = ParserStateMachine::Id(size, first_byte as u32);
+                self.poll_next(cx)
             }
-            ParserStateMachine::ReadElementId(size, mut id) => {
+            ParserStateMachine::Id(size, mut id) => {
                 let id_bytes = ready_next!(self.read_exact_bytes(cx, size - 1));
 
                 id = id_bytes
@@ -147,35 +147,35 @@ impl<T: AsyncRead + AsyncSeek + Unpin + Send> Stream for WebmStream<T> {
                     .fold(id, |acc, byte| (acc << 8) | byte as u32);
 
                 self.current_element = Some(id.into());
-                self.parser_state = ParserStateMachine::ReadElementSizeLength();
-                return self.poll_next(cx);
+                self.parser_state = ParserStateMachine::SizeLength();
+                self.poll_next(cx)
             }
-            ParserStateMachine::ReadElementSizeLength() => {
+            ParserStateMachine::SizeLength() => {
                 let first_byte = ready_next!(self.read_exact_bytes(cx, 1))[0];
 
                 let size = first_byte.leading_zeros() as usize + 1;
-                self.parser_state = ParserStateMachine::ReadElementSize(size, first_byte as u64);
-                return self.poll_next(cx);
+                self.parser_state = ParserStateMachine::Size(size, first_byte as u64);
+                self.poll_next(cx)
             }
-            ParserStateMachine::ReadElementSize(size_of_vint, mut element_size) => {
+            ParserStateMachine::Size(size_of_vint, mut element_size) => {
                 let size_bytes = ready_next!(self.read_exact_bytes(cx, size_of_vint - 1));
 
-                let mask = (1 << 8 - size_of_vint) - 1;
+                let mask = (1 << (8 - size_of_vint)) - 1;
                 element_size = size_bytes
                     .into_iter()
                     .fold(element_size & mask, |acc, byte| (acc << 8) | byte as u64);
 
-                self.parser_state = ParserStateMachine::ReadElementData(element_size as usize);
-                return self.poll_next(cx);
+                self.parser_state = ParserStateMachine::Data(element_size as usize);
+                self.poll_next(cx)
             }
-            ParserStateMachine::ReadElementData(element_size) => {
-                let current_element = self.current_element.expect(
-                    "ParserStateMachine should always enter ReadElementId before ReadElementData",
-                );
+            ParserStateMachine::Data(element_size) => {
+                let current_element = self
+                    .current_element
+                    .expect("ParserStateMachine should always enter Id before Data");
 
                 match current_element {
                     EbmlElementId::Header => {
-                        self.parser_state = ParserStateMachine::ReadElementIdLength;
+                        self.parser_state = ParserStateMachine::IdLength;
                     }
                     EbmlElementId::DocType => {
                         let data = ready_next!(self.read_exact_bytes(cx, element_size));
@@ -189,18 +189,18 @@ impl<T: AsyncRead + AsyncSeek + Unpin + Send> Stream for WebmStream<T> {
                         if webm_string != "webm" {
                             error!("Expected DocType webm, got: {}", webm_string);
                         }
-                        self.parser_state = ParserStateMachine::ReadElementIdLength;
+                        self.parser_state = ParserStateMachine::IdLength;
                     }
                     EbmlElementId::Segment => {
-                        self.parser_state = ParserStateMachine::ReadElementIdLength;
+                        self.parser_state = ParserStateMachine::IdLength;
                     }
                     EbmlElementId::Cluster => {
-                        self.parser_state = ParserStateMachine::ReadElementIdLength;
+                        self.parser_state = ParserStateMachine::IdLength;
                     }
                     EbmlElementId::SimpleBlock => {
                         let mut data = ready_next!(self.read_exact_bytes(cx, element_size));
                         // TODO: parse vint, make sure block corresponds to opus track
-                        self.parser_state = ParserStateMachine::ReadElementIdLength;
+                        self.parser_state = ParserStateMachine::IdLength;
                         self.simple_blocks += 1;
                         return Poll::Ready(Some(data.split_off(4)));
                     }
@@ -220,10 +220,10 @@ impl<T: AsyncRead + AsyncSeek + Unpin + Send> Stream for WebmStream<T> {
                             return Poll::Pending;
                         }
                         self.seek_in_progress = false;
-                        self.parser_state = ParserStateMachine::ReadElementIdLength;
+                        self.parser_state = ParserStateMachine::IdLength;
                     }
                 }
-                return self.poll_next(cx);
+                self.poll_next(cx)
             }
         }
     }
@@ -234,7 +234,7 @@ impl<R: AsyncRead + AsyncSeek + Unpin> WebmStream<BufReader<R>> {
         WebmStream {
             stream: BufReader::new(stream),
             current_element: None,
-            parser_state: ParserStateMachine::ReadElementIdLength,
+            parser_state: ParserStateMachine::IdLength,
             buf: None,
             cursor: 0,
             seek_in_progress: false,
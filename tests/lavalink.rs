@@ -0,0 +1,679 @@
+mod support;
+
+use std::time::Duration;
+
+use jukebox::{config::VoiceConfiguration, crypto::EncryptionMode};
+use reqwest::{Method, StatusCode};
+use serde_json::{json, Value};
+use tokio::time;
+
+use support::{
+    lavalink::{
+        assert_compatible, corrupt_stream, golden, stalling_stream, LavalinkClient, Protocol,
+        TestServer,
+    },
+    voice_server::{MockOptions, MockVoiceServer, GUILD_ID, SESSION_ID, TOKEN},
+};
+
+/// A v3 voiceUpdate pointing at the mock voice server
+fn voice_update(voice: &MockVoiceServer) -> Value {
+    let mut payload = serde_json::to_value(voice.voice_update()).unwrap();
+    payload["op"] = json!("voiceUpdate");
+    payload["guildId"] = json!(GUILD_ID);
+    payload
+}
+
+/// The same for a v4 player update
+fn voice_state(voice: &MockVoiceServer) -> Value {
+    json!({
+        "token": TOKEN,
+        "endpoint": voice.endpoint(),
+        "sessionId": SESSION_ID,
+    })
+}
+
+fn player_path(client: &LavalinkClient) -> String {
+    format!("/v4/sessions/{}/players/{}", client.session_id(), GUILD_ID)
+}
+
+/// v3 ops have no response, so this polls the player until `f` is happy with it
+async fn wait_for_player(
+    server: &TestServer,
+    client: &LavalinkClient,
+    f: impl Fn(&Value) -> bool,
+) -> Value {
+    let path = player_path(client);
+    for _ in 0..100 {
+        let (status, player) = server.get(&path).await;
+        if status == StatusCode::OK && f(&player) {
+            return player;
+        }
+        time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!(
+        "player never got there, last seen {:#}",
+        server.get(&path).await.1
+    );
+}
+
+async fn load_track(server: &TestServer) -> Value {
+    let (status, result) = server
+        .get(&format!("/v4/loadtracks?identifier={}", server.track()))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    result["data"].clone()
+}
+
+/// A v3 client with a connected player in the mock's guild
+async fn connected_player(server: &TestServer, voice: &mut MockVoiceServer) -> LavalinkClient {
+    let mut client = server.connect(Protocol::V3).await;
+    client.send(voice_update(voice)).await;
+    voice.identified().await;
+    wait_for_player(server, &client, |player| {
+        player["state"]["connected"] == true
+    })
+    .await;
+    client
+}
+
+#[tokio::test]
+async fn rejects_the_wrong_password() {
+    let server = TestServer::start().await;
+    for protocol in [Protocol::V3, Protocol::V4] {
+        let result = server
+            .connect_with(protocol, &[("Authorization", Some("wrong"))])
+            .await;
+        assert_eq!(result.err(), Some(StatusCode::UNAUTHORIZED));
+    }
+}
+
+#[tokio::test]
+async fn requires_the_client_headers() {
+    let server = TestServer::start().await;
+    for header in ["Authorization", "User-Id", "Client-Name"] {
+        let result = server.connect_with(Protocol::V3, &[(header, None)]).await;
+        assert_eq!(
+            result.err(),
+            Some(StatusCode::BAD_REQUEST),
+            "without {}",
+            header
+        );
+    }
+}
+
+#[tokio::test]
+async fn v3_ready() {
+    let server = TestServer::start().await;
+    let client = server.connect(Protocol::V3).await;
+    assert_compatible(&client.ready, "v3/ready");
+    assert_eq!(client.ready["resumed"], false);
+}
+
+#[tokio::test]
+async fn v3_load_tracks() {
+    let server = TestServer::start().await;
+
+    let (status, loaded) = server
+        .get(&format!("/loadtracks?identifier={}", server.track()))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_compatible(&loaded, "v3/load_tracks");
+    let track = &loaded["tracks"][0];
+    assert_eq!(track["info"]["title"], "fixture");
+    assert_eq!(track["info"]["length"], 5000);
+
+    let (_, no_matches) = server
+        .get("/loadtracks?identifier=/does/not/exist.ogg")
+        .await;
+    assert_compatible(&no_matches, "v3/no_matches");
+
+    let (_, failed) = server
+        .get(&format!("/loadtracks?identifier={}", server.broken_track()))
+        .await;
+    assert_compatible(&failed, "v3/load_failed");
+
+    // older clients use track rather than encodedTrack
+    let encoded = track["encoded"].as_str().unwrap();
+    for param in ["track", "encodedTrack"] {
+        let (status, decoded) = server
+            .get(&format!("/decodetrack?{}={}", param, urlencode(encoded)))
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_compatible(&decoded, "v3/decode_track");
+        assert_eq!(decoded["info"], track["info"]);
+    }
+}
+
+#[tokio::test]
+async fn v3_voice_update_creates_a_player() {
+    let server = TestServer::start().await;
+    let mut voice = MockVoiceServer::start(MockOptions::default()).await;
+    let mut client = server.connect(Protocol::V3).await;
+
+    client.send(voice_update(&voice)).await;
+    let identify = voice.identified().await;
+    assert_eq!(identify["user_id"], support::lavalink::USER_ID);
+
+    // the first update comes as soon as the player exists, which is before its
+    // connection is done
+    let update = client.wait_for_op("playerUpdate").await;
+    assert_compatible(&update, "v3/player_update");
+    assert_eq!(update["guildId"], GUILD_ID);
+    wait_for_player(&server, &client, |player| {
+        player["state"]["connected"] == true
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn v3_player_ops() {
+    let server = TestServer::start().await;
+    let mut voice = MockVoiceServer::start(MockOptions::default()).await;
+    let mut client = connected_player(&server, &mut voice).await;
+    let track = load_track(&server).await;
+
+    client
+        .send(json!({
+            "op": "play",
+            "guildId": GUILD_ID,
+            "track": track["encoded"],
+            "volume": 80,
+        }))
+        .await;
+    let audio = voice.next_audio().await;
+    assert_eq!(audio.opus, [0xfc, 0xff, 0xfe]);
+    let start = client.wait_for_event("TrackStartEvent").await;
+    assert_compatible(&start, "v3/track_start_event");
+    assert_eq!(start["track"], track["encoded"]);
+    let player = wait_for_player(&server, &client, |player| !player["track"].is_null()).await;
+    assert_eq!(player["track"]["encoded"], track["encoded"]);
+    assert_eq!(player["volume"], 80);
+
+    client
+        .send(json!({ "op": "pause", "guildId": GUILD_ID, "pause": true }))
+        .await;
+    wait_for_player(&server, &client, |player| player["paused"] == true).await;
+    client
+        .send(json!({ "op": "pause", "guildId": GUILD_ID, "pause": false }))
+        .await;
+    wait_for_player(&server, &client, |player| player["paused"] == false).await;
+
+    client
+        .send(json!({ "op": "volume", "guildId": GUILD_ID, "volume": 50 }))
+        .await;
+    wait_for_player(&server, &client, |player| player["volume"] == 50).await;
+
+    // filters sit next to the op rather than in an object of their own
+    client
+        .send(json!({
+            "op": "filters",
+            "guildId": GUILD_ID,
+            "volume": 0.5,
+            "timescale": { "speed": 1.0, "pitch": 1.0, "rate": 1.0 },
+        }))
+        .await;
+    wait_for_player(&server, &client, |player| {
+        player["filters"]["volume"] == 0.5
+    })
+    .await;
+
+    client
+        .send(json!({ "op": "seek", "guildId": GUILD_ID, "position": 3000 }))
+        .await;
+    wait_for_player(&server, &client, |player| {
+        player["state"]["position"].as_u64() >= Some(3000)
+    })
+    .await;
+
+    client
+        .send(json!({ "op": "stop", "guildId": GUILD_ID }))
+        .await;
+    let end = client.wait_for_event("TrackEndEvent").await;
+    assert_compatible(&end, "v3/track_end_event");
+    assert_eq!(end["reason"], "STOPPED");
+    wait_for_player(&server, &client, |player| player["track"].is_null()).await;
+
+    client
+        .send(json!({ "op": "destroy", "guildId": GUILD_ID }))
+        .await;
+    let path = player_path(&client);
+    for _ in 0..100 {
+        let (status, error) = server.get(&path).await;
+        if status == StatusCode::NOT_FOUND {
+            assert_compatible(&error, "v4/error");
+            assert_eq!(error["path"], path);
+            return;
+        }
+        time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("player was never destroyed");
+}
+
+/// A track that loads but whose file is gone by the time it's played
+async fn vanishing_track(server: &TestServer) -> Value {
+    let path = server.directory().join("vanishing.ogg");
+    std::fs::copy(server.track(), &path).unwrap();
+    let (_, loaded) = server
+        .get(&format!("/v4/loadtracks?identifier={}", path.display()))
+        .await;
+    std::fs::remove_file(&path).unwrap();
+    loaded["data"].clone()
+}
+
+#[tokio::test]
+async fn v3_track_failures() {
+    let server = TestServer::with_voice(VoiceConfiguration {
+        track_stuck_threshold: 200,
+        ..Default::default()
+    })
+    .await;
+    let mut voice = MockVoiceServer::start(MockOptions::default()).await;
+    let mut client = connected_player(&server, &mut voice).await;
+
+    let track = vanishing_track(&server).await;
+    client
+        .send(json!({ "op": "play", "guildId": GUILD_ID, "track": track["encoded"] }))
+        .await;
+    let exception = client.wait_for_event("TrackExceptionEvent").await;
+    assert_compatible(&exception, "v3/track_exception_event");
+    assert_eq!(exception["track"], track["encoded"]);
+    let end = client.wait_for_event("TrackEndEvent").await;
+    assert_compatible(&end, "v3/track_end_event");
+    assert_eq!(end["reason"], "LOAD_FAILED");
+
+    let (_, loaded) = server
+        .get(&format!(
+            "/v4/loadtracks?identifier={}",
+            stalling_stream().await
+        ))
+        .await;
+    let track = &loaded["data"];
+    client
+        .send(json!({ "op": "play", "guildId": GUILD_ID, "track": track["encoded"] }))
+        .await;
+    let stuck = client.wait_for_event("TrackStuckEvent").await;
+    assert_compatible(&stuck, "v3/track_stuck_event");
+    assert_eq!(stuck["track"], track["encoded"]);
+    assert_eq!(stuck["thresholdMs"], 200);
+}
+
+#[tokio::test]
+async fn v3_auto_pause_in_an_empty_channel() {
+    let server = TestServer::with_voice(VoiceConfiguration {
+        auto_pause: true,
+        ..Default::default()
+    })
+    .await;
+    // the mock never reports anyone else in the channel
+    let mut voice = MockVoiceServer::start(MockOptions::default()).await;
+    let mut client = connected_player(&server, &mut voice).await;
+    let track = load_track(&server).await;
+
+    client
+        .send(json!({ "op": "play", "guildId": GUILD_ID, "track": track["encoded"] }))
+        .await;
+    client.wait_for_event("TrackStartEvent").await;
+    let player = wait_for_player(&server, &client, |player| player["paused"] == true).await;
+    assert_eq!(player["track"]["encoded"], track["encoded"]);
+}
+
+#[tokio::test]
+async fn v3_resuming() {
+    let server = TestServer::start().await;
+    let mut client = server.connect(Protocol::V3).await;
+    let session_id = client.session_id().to_owned();
+    client
+        .send(json!({ "op": "configureResuming", "key": "resume-me", "timeout": 60 }))
+        .await;
+    client.close().await;
+
+    // the session is only up for resuming once the server saw the close
+    for _ in 0..100 {
+        let client = server
+            .connect_with(Protocol::V3, &[("Resume-Key", Some("resume-me"))])
+            .await
+            .unwrap();
+        if client.ready["resumed"] == true {
+            assert_compatible(&client.ready, "v3/ready");
+            assert_eq!(client.session_id(), session_id);
+            return;
+        }
+        client.close().await;
+        time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("session was never resumed");
+}
+
+#[tokio::test]
+async fn v3_recording() {
+    let server = TestServer::start().await;
+    let mut voice = MockVoiceServer::start(MockOptions::default()).await;
+    let mut client = connected_player(&server, &mut voice).await;
+
+    client
+        .send(json!({ "op": "startRecording", "guildId": GUILD_ID }))
+        .await;
+    let path = format!("{}/recording", player_path(&client));
+    for _ in 0..100 {
+        if server.get(&path).await.0 == StatusCode::OK {
+            break;
+        }
+        time::sleep(Duration::from_millis(50)).await;
+    }
+    let (status, recording) = server.get(&path).await;
+    assert_eq!(status, StatusCode::OK);
+
+    client
+        .send(json!({ "op": "stopRecording", "guildId": GUILD_ID }))
+        .await;
+    let finished = client.wait_for_event("RecordingFinishedEvent").await;
+    assert_eq!(finished["guildId"], GUILD_ID);
+    assert_eq!(finished["id"], recording["id"]);
+    for file in finished["files"].as_array().unwrap() {
+        assert!(file
+            .as_str()
+            .unwrap()
+            .starts_with(server.directory().to_str().unwrap()));
+    }
+}
+
+#[tokio::test]
+async fn v3_websocket_closed_event() {
+    let server = TestServer::with_voice(VoiceConfiguration {
+        encryption_modes: vec![EncryptionMode::Aes256GcmRtpSize(0)],
+        ..Default::default()
+    })
+    .await;
+    let voice = MockVoiceServer::start(MockOptions {
+        modes: vec![EncryptionMode::XSalsa20Poly1305],
+        ..Default::default()
+    })
+    .await;
+    let mut client = server.connect(Protocol::V3).await;
+
+    client.send(voice_update(&voice)).await;
+    let closed = client.wait_for_event("WebSocketClosedEvent").await;
+    assert_compatible(&closed, "v3/websocket_closed_event");
+    assert_eq!(closed["guildId"], GUILD_ID);
+    assert_eq!(closed["code"], 4016);
+}
+
+#[tokio::test]
+async fn v4_ready_and_info() {
+    let server = TestServer::start().await;
+    let client = server.connect(Protocol::V4).await;
+    assert_compatible(&client.ready, "v4/ready");
+
+    let (status, mut info) = server.get("/v4/info").await;
+    assert_eq!(status, StatusCode::OK);
+    // filters are only stored, none of them change the audio yet
+    assert_eq!(info["filters"], json!([]));
+    info["filters"] = golden("v4/info")["filters"].clone();
+    assert_compatible(&info, "v4/info");
+    #[cfg(feature = "dave")]
+    assert!(info["plugins"].as_array().unwrap().contains(&json!({
+        "name": "dave",
+        "version": info["version"]["semver"],
+    })));
+}
+
+#[tokio::test]
+async fn v4_load_and_decode_tracks() {
+    let server = TestServer::start().await;
+
+    let (_, loaded) = server
+        .get(&format!("/v4/loadtracks?identifier={}", server.track()))
+        .await;
+    assert_compatible(&loaded, "v4/load_track");
+    let (_, empty) = server
+        .get("/v4/loadtracks?identifier=/does/not/exist.ogg")
+        .await;
+    assert_compatible(&empty, "v4/load_empty");
+    let outside = server.outside_track();
+    let name = outside.rsplit('/').next().unwrap();
+    for identifier in [
+        outside.clone(),
+        format!("{}/../{}", server.directory().display(), name),
+    ] {
+        let (_, empty) = server
+            .get(&format!("/v4/loadtracks?identifier={}", identifier))
+            .await;
+        assert_eq!(empty["loadType"], "empty", "{}", identifier);
+    }
+    let (_, error) = server
+        .get(&format!(
+            "/v4/loadtracks?identifier={}",
+            server.broken_track()
+        ))
+        .await;
+    assert_compatible(&error, "v4/load_error");
+
+    let track = &loaded["data"];
+    let (status, decoded) = server
+        .get(&format!(
+            "/v4/decodetrack?encodedTrack={}",
+            urlencode(track["encoded"].as_str().unwrap())
+        ))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_compatible(&decoded, "v4/decode_track");
+    assert_eq!(decoded["info"], track["info"]);
+
+    let (status, decoded) = server
+        .request(
+            Method::POST,
+            "/v4/decodetracks",
+            Some(json!([track["encoded"], track["encoded"]])),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_compatible(&decoded, "v4/decode_tracks");
+    assert_eq!(decoded.as_array().unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn v4_session_and_players() {
+    let server = TestServer::start().await;
+    let mut voice = MockVoiceServer::start(MockOptions::default()).await;
+    let mut client = server.connect(Protocol::V4).await;
+    let session_path = format!("/v4/sessions/{}", client.session_id());
+
+    let (status, session) = server
+        .request(
+            Method::PATCH,
+            &session_path,
+            Some(json!({ "resuming": true, "timeout": 30 })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_compatible(&session, "v4/session");
+    assert_eq!(session, json!({ "resuming": true, "timeout": 30 }));
+
+    let path = player_path(&client);
+    let (status, player) = server
+        .request(
+            Method::PATCH,
+            &path,
+            Some(json!({
+                "voice": voice_state(&voice),
+                "track": { "identifier": server.track() },
+                "volume": 70,
+            })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{:#}", player);
+    assert_compatible(&player, "v4/player");
+    assert_eq!(player["track"]["info"]["title"], "fixture");
+    assert_eq!(player["volume"], 70);
+    assert_eq!(player["voice"]["endpoint"], voice.endpoint());
+    voice.next_audio().await;
+
+    let update = client.wait_for_op("playerUpdate").await;
+    assert_compatible(&update, "v4/player_update");
+
+    let (status, players) = server.get(&format!("{}/players", session_path)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_compatible(&players, "v4/players");
+    assert_eq!(players.as_array().unwrap().len(), 1);
+
+    let (status, _) = server.request(Method::DELETE, &path, None).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, players) = server.get(&format!("{}/players", session_path)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(players, json!([]));
+
+    let (status, error) = server.get("/v4/sessions/nope/players").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_compatible(&error, "v4/error");
+}
+
+#[tokio::test]
+async fn v4_player_before_voice() {
+    let server = TestServer::start().await;
+    let mut voice = MockVoiceServer::start(MockOptions::default()).await;
+    let client = server.connect(Protocol::V4).await;
+    let path = player_path(&client);
+
+    // like Lavalink, the player is created without a connection
+    let (status, player) = server
+        .request(
+            Method::PATCH,
+            &path,
+            Some(json!({ "track": { "identifier": server.track() }, "paused": false })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{:#}", player);
+    assert_compatible(&player, "v4/player");
+    assert_eq!(player["track"]["info"]["title"], "fixture");
+    assert_eq!(player["state"]["connected"], false);
+    assert_eq!(player["voice"]["endpoint"], "");
+
+    // and starts playing once it gets one
+    let (status, player) = server
+        .request(
+            Method::PATCH,
+            &path,
+            Some(json!({ "voice": voice_state(&voice) })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{:#}", player);
+    assert_eq!(player["voice"]["endpoint"], voice.endpoint());
+    assert_eq!(player["track"]["info"]["title"], "fixture");
+    voice.next_audio().await;
+}
+
+#[tokio::test]
+async fn v4_track_events() {
+    let server = TestServer::with_voice(VoiceConfiguration {
+        track_stuck_threshold: 200,
+        ..Default::default()
+    })
+    .await;
+    let voice = MockVoiceServer::start(MockOptions::default()).await;
+    let mut client = server.connect(Protocol::V4).await;
+    let path = player_path(&client);
+    let track = load_track(&server).await;
+
+    let (status, player) = server
+        .request(
+            Method::PATCH,
+            &path,
+            Some(json!({
+                "voice": voice_state(&voice),
+                "track": { "encoded": track["encoded"] },
+                "endTime": 500,
+            })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{:#}", player);
+    let start = client.wait_for_event("TrackStartEvent").await;
+    assert_compatible(&start, "v4/track_start_event");
+    assert_eq!(start["track"]["encoded"], track["encoded"]);
+    // played to its end time
+    let end = client.wait_for_event("TrackEndEvent").await;
+    assert_compatible(&end, "v4/track_end_event");
+    assert_eq!(end["reason"], "finished");
+    assert_eq!(end["track"]["encoded"], track["encoded"]);
+
+    let track = vanishing_track(&server).await;
+    let (status, error) = server
+        .request(
+            Method::PATCH,
+            &path,
+            Some(json!({ "track": { "encoded": track["encoded"] } })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_compatible(&error, "v4/error");
+    let exception = client.wait_for_event("TrackExceptionEvent").await;
+    assert_compatible(&exception, "v4/track_exception_event");
+    assert_eq!(exception["track"]["encoded"], track["encoded"]);
+    let end = client.wait_for_event("TrackEndEvent").await;
+    assert_compatible(&end, "v4/track_end_event");
+    assert_eq!(end["reason"], "loadFailed");
+
+    let (status, player) = server
+        .request(
+            Method::PATCH,
+            &path,
+            Some(json!({ "track": { "identifier": stalling_stream().await } })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{:#}", player);
+    let stuck = client.wait_for_event("TrackStuckEvent").await;
+    assert_compatible(&stuck, "v4/track_stuck_event");
+    assert_eq!(stuck["track"]["encoded"], player["track"]["encoded"]);
+    assert_eq!(stuck["thresholdMs"], 200);
+
+    let (status, player) = server
+        .request(
+            Method::PATCH,
+            &path,
+            Some(json!({ "track": { "identifier": corrupt_stream().await } })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{:#}", player);
+    // the audio before the bad page still plays
+    let exception = client.wait_for_event("TrackExceptionEvent").await;
+    assert_compatible(&exception, "v4/track_exception_event");
+    assert_eq!(exception["track"]["encoded"], player["track"]["encoded"]);
+    let end = client.wait_for_event("TrackEndEvent").await;
+    assert_eq!(end["reason"], "loadFailed");
+    assert_eq!(end["track"]["encoded"], player["track"]["encoded"]);
+}
+
+#[tokio::test]
+async fn v4_websocket_closed_event() {
+    let server = TestServer::with_voice(VoiceConfiguration {
+        encryption_modes: vec![EncryptionMode::Aes256GcmRtpSize(0)],
+        ..Default::default()
+    })
+    .await;
+    let voice = MockVoiceServer::start(MockOptions {
+        modes: vec![EncryptionMode::XSalsa20Poly1305],
+        ..Default::default()
+    })
+    .await;
+    let mut client = server.connect(Protocol::V4).await;
+
+    let (status, error) = server
+        .request(
+            Method::PATCH,
+            &player_path(&client),
+            Some(json!({ "voice": voice_state(&voice) })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_compatible(&error, "v4/error");
+    let closed = client.wait_for_event("WebSocketClosedEvent").await;
+    assert_compatible(&closed, "v4/websocket_closed_event");
+    assert_eq!(closed["code"], 4016);
+}
+
+// encoded tracks are base64, which has characters that mean something in a query
+fn urlencode(value: &str) -> String {
+    value
+        .replace('+', "%2B")
+        .replace('/', "%2F")
+        .replace('=', "%3D")
+}
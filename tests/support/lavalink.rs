@@ -0,0 +1,405 @@
+//! A fake Lavalink client: runs a jukebox [`Server`] on an ephemeral port,
+//! talks to it over the websocket and the REST api like a client library would,
+//! and checks what comes back against the example payloads of Lavalink's docs.
+
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use futures_util::{SinkExt, StreamExt};
+use jukebox::{
+    config::{MediaConfiguration, VoiceConfiguration},
+    opus_write::OggOpusWriter,
+    server::Server,
+    source::SourceRegistry,
+};
+use reqwest::{Method, StatusCode};
+use serde_json::Value;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    time,
+};
+use tokio_tungstenite::{
+    tungstenite::{self, client::IntoClientRequest, Message},
+    MaybeTlsStream, WebSocketStream,
+};
+
+pub const PASSWORD: &str = "youshallnotpass";
+pub const USER_ID: &str = "1234";
+pub const CLIENT_NAME: &str = "jukebox-tests/1.0";
+// how long a test waits for a message before failing
+const MESSAGE_TIMEOUT: Duration = Duration::from_secs(10);
+// keys that tell payloads apart, whose values have to match the golden ones
+const DISCRIMINATORS: &[&str] = &["op", "type", "loadType", "severity"];
+
+/// The websocket of each protocol version
+#[derive(Debug, Clone, Copy)]
+pub enum Protocol {
+    V3,
+    V4,
+}
+
+impl Protocol {
+    fn path(self) -> &'static str {
+        match self {
+            Protocol::V3 => "/",
+            Protocol::V4 => "/v4/websocket",
+        }
+    }
+}
+
+pub struct TestServer {
+    addr: SocketAddr,
+    http: reqwest::Client,
+    directory: PathBuf,
+}
+
+impl TestServer {
+    pub async fn start() -> Self {
+        Self::with_voice(VoiceConfiguration::default()).await
+    }
+
+    /// Local files and streams are the only sources, and tests only stream from
+    /// localhost, so nothing reaches out to the internet. A fixture track and
+    /// recordings live in a fresh directory, the only local root.
+    pub async fn with_voice(voice: VoiceConfiguration) -> Self {
+        let directory =
+            std::env::temp_dir().join(format!("jukebox-test-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join("fixture.ogg"), fixture_track()).unwrap();
+        std::fs::write(directory.join("broken.txt"), "not audio").unwrap();
+        std::fs::write(directory.with_extension("ogg"), fixture_track()).unwrap();
+
+        let mut media = MediaConfiguration::default();
+        media.server.password = PASSWORD.to_owned();
+        media.sources.local = true;
+        media.sources.local_roots = vec![directory.to_string_lossy().into_owned()];
+        media.sources.http = false;
+        let voice = VoiceConfiguration {
+            recording_directory: directory.join("recordings").to_string_lossy().into_owned(),
+            prebuffer_frames: 1,
+            ..voice
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Server::_new(
+            PASSWORD.to_owned(),
+            addr,
+            SourceRegistry::from_config(&media),
+            voice,
+        );
+        tokio::spawn(server.serve(listener));
+
+        Self {
+            addr,
+            http: reqwest::Client::builder()
+                .timeout(MESSAGE_TIMEOUT)
+                .build()
+                .unwrap(),
+            directory,
+        }
+    }
+
+    /// Identifier of a 5 second local track
+    pub fn track(&self) -> String {
+        self.directory
+            .join("fixture.ogg")
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// Identifier of a file that exists but fails to load
+    pub fn broken_track(&self) -> String {
+        self.directory
+            .join("broken.txt")
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// Identifier of a playable file next to the local root, rather than in it
+    pub fn outside_track(&self) -> String {
+        self.directory
+            .with_extension("ogg")
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    /// Connects with the headers every client sends and waits for ready
+    pub async fn connect(&self, protocol: Protocol) -> LavalinkClient {
+        self.connect_with(protocol, &[]).await.unwrap()
+    }
+
+    /// Connects with extra headers, `None` removes one of the default ones.
+    /// Returns the status code if the server refuses the upgrade.
+    pub async fn connect_with(
+        &self,
+        protocol: Protocol,
+        headers: &[(&'static str, Option<&str>)],
+    ) -> Result<LavalinkClient, StatusCode> {
+        let url = format!("ws://{}{}", self.addr, protocol.path());
+        let mut request = url.into_client_request().unwrap();
+        let defaults = [
+            ("Authorization", Some(PASSWORD)),
+            ("User-Id", Some(USER_ID)),
+            ("Client-Name", Some(CLIENT_NAME)),
+        ];
+        for (name, value) in defaults.iter().chain(headers) {
+            match value {
+                Some(value) => request.headers_mut().insert(*name, value.parse().unwrap()),
+                None => request.headers_mut().remove(*name),
+            };
+        }
+        let connecting = time::timeout(MESSAGE_TIMEOUT, tokio_tungstenite::connect_async(request));
+        let ws = match connecting.await.expect("timed out connecting") {
+            Ok((ws, _)) => ws,
+            Err(tungstenite::Error::Http(response)) => {
+                return Err(StatusCode::from_u16(response.status().as_u16()).unwrap())
+            }
+            Err(e) => panic!("could not connect: {}", e),
+        };
+        let mut client = LavalinkClient {
+            ws,
+            ready: Value::Null,
+        };
+        client.ready = client.wait_for_op("ready").await;
+        Ok(client)
+    }
+
+    /// Sends a REST request and returns the status and json body, `Null` if
+    /// there is none
+    pub async fn request(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<Value>,
+    ) -> (StatusCode, Value) {
+        let mut request = self
+            .http
+            .request(method, format!("http://{}{}", self.addr, path))
+            .header("Authorization", PASSWORD);
+        if let Some(body) = body {
+            request = request
+                .header("Content-Type", "application/json")
+                .body(body.to_string());
+        }
+        let response = request.send().await.unwrap();
+        let status = StatusCode::from_u16(response.status().as_u16()).unwrap();
+        let bytes = response.bytes().await.unwrap();
+        let body = match bytes.is_empty() {
+            true => Value::Null,
+            false => serde_json::from_slice(&bytes)
+                .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&bytes).into_owned())),
+        };
+        (status, body)
+    }
+
+    pub async fn get(&self, path: &str) -> (StatusCode, Value) {
+        self.request(Method::GET, path, None).await
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        _ = std::fs::remove_dir_all(&self.directory);
+        _ = std::fs::remove_file(self.directory.with_extension("ogg"));
+    }
+}
+
+pub struct LavalinkClient {
+    ws: WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>,
+    /// The ready the server greeted us with
+    pub ready: Value,
+}
+
+impl LavalinkClient {
+    pub fn session_id(&self) -> &str {
+        self.ready["sessionId"]
+            .as_str()
+            .expect("ready without a session id")
+    }
+
+    pub async fn send(&mut self, payload: Value) {
+        self.ws
+            .send(Message::Text(payload.to_string().into()))
+            .await
+            .unwrap();
+    }
+
+    /// The next json message, panics if none arrives in time
+    pub async fn next(&mut self) -> Value {
+        loop {
+            let message = time::timeout(MESSAGE_TIMEOUT, self.ws.next())
+                .await
+                .expect("timed out waiting for a message")
+                .expect("websocket closed")
+                .unwrap();
+            if let Message::Text(text) = message {
+                return serde_json::from_str(&text).unwrap();
+            }
+        }
+    }
+
+    /// Skips messages until one with `op`
+    pub async fn wait_for_op(&mut self, op: &str) -> Value {
+        loop {
+            let message = self.next().await;
+            if message["op"] == op {
+                return message;
+            }
+        }
+    }
+
+    /// Skips messages until an event of `event_type`
+    pub async fn wait_for_event(&mut self, event_type: &str) -> Value {
+        loop {
+            let message = self.wait_for_op("event").await;
+            if message["type"] == event_type {
+                return message;
+            }
+        }
+    }
+
+    pub async fn close(mut self) {
+        _ = self.ws.close(None).await;
+    }
+}
+
+/// Five seconds of opus in an Ogg file
+fn fixture_track() -> Vec<u8> {
+    let (mut writer, mut file) = ogg_opus(5);
+    file.extend(writer.finish());
+    file
+}
+
+// the start of an Ogg file with `seconds` of opus, not quite silence so nothing
+// treats it specially
+fn ogg_opus(seconds: usize) -> (OggOpusWriter, Vec<u8>) {
+    let mut writer = OggOpusWriter::new(1);
+    let mut file = writer.headers(2);
+    for _ in 0..seconds * 50 {
+        if let Some(page) = writer.push(vec![0xfc, 0xff, 0xfe], 960) {
+            file.extend(page);
+        }
+    }
+    (writer, file)
+}
+
+/// Url of an Ogg stream that sends a second of opus and then nothing, without
+/// ever ending
+pub async fn stalling_stream() -> String {
+    let (_, body) = ogg_opus(1);
+    serve_stream(body, true).await
+}
+
+/// Url of an Ogg stream that sends a second of opus and then something that
+/// isn't an Ogg page
+pub async fn corrupt_stream() -> String {
+    let (_, mut body) = ogg_opus(1);
+    body.extend([0x42; 64]);
+    serve_stream(body, false).await
+}
+
+// chunked, so it passes as a live stream. `hold` keeps the connection open
+// after the body instead of ending it
+async fn serve_stream(body: Vec<u8>, hold: bool) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let Ok((mut socket, _)) = listener.accept().await else {
+                return;
+            };
+            let body = body.clone();
+            tokio::spawn(async move {
+                // whatever was asked for, the answer is the same
+                let mut request = [0u8; 1024];
+                _ = socket.read(&mut request).await;
+                let mut response = b"HTTP/1.1 200 OK\r\n\
+                    Content-Type: audio/ogg\r\n\
+                    Transfer-Encoding: chunked\r\n\r\n"
+                    .to_vec();
+                response.extend(format!("{:x}\r\n", body.len()).as_bytes());
+                response.extend(&body);
+                response.extend(b"\r\n");
+                if !hold {
+                    response.extend(b"0\r\n\r\n");
+                }
+                if socket.write_all(&response).await.is_ok() && hold {
+                    // held open until the client gives up on it
+                    _ = socket.read(&mut request).await;
+                }
+            });
+        }
+    });
+    format!("http://{}/stream.ogg", addr)
+}
+
+pub fn golden(name: &str) -> Value {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(format!("{}.json", name));
+    let json = std::fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("could not read {}: {}", path.display(), e));
+    serde_json::from_str(&json).unwrap()
+}
+
+/// Checks that `actual` has everything the golden payload has, with the same
+/// types. Values only have to match for discriminators like `op`, anything
+/// that's `null` in the golden payload may be anything, and extra fields are
+/// fine since clients ignore them.
+pub fn assert_compatible(actual: &Value, name: &str) {
+    let mut mismatches = Vec::new();
+    compare(actual, &golden(name), "$", &mut mismatches);
+    assert!(
+        mismatches.is_empty(),
+        "not compatible with {}:\n{}\n\ngot {:#}",
+        name,
+        mismatches.join("\n"),
+        actual
+    );
+}
+
+fn compare(actual: &Value, golden: &Value, path: &str, mismatches: &mut Vec<String>) {
+    match (golden, actual) {
+        (Value::Null, _) => {}
+        (Value::Object(golden), Value::Object(actual)) => {
+            for (key, golden) in golden {
+                let path = format!("{}.{}", path, key);
+                match actual.get(key) {
+                    None => mismatches.push(format!("{} is missing", path)),
+                    Some(actual) if DISCRIMINATORS.contains(&key.as_str()) && actual != golden => {
+                        mismatches.push(format!("{} is {}, expected {}", path, actual, golden))
+                    }
+                    Some(actual) => compare(actual, golden, &path, mismatches),
+                }
+            }
+        }
+        // every element has to look like the first golden one, and there has to
+        // be one if the golden array has any
+        (Value::Array(golden), Value::Array(actual)) => {
+            if let Some(golden) = golden.first() {
+                if actual.is_empty() {
+                    mismatches.push(format!("{} is empty", path));
+                }
+                for (i, actual) in actual.iter().enumerate() {
+                    compare(actual, golden, &format!("{}[{}]", path, i), mismatches);
+                }
+            }
+        }
+        (Value::Bool(_), Value::Bool(_))
+        | (Value::Number(_), Value::Number(_))
+        | (Value::String(_), Value::String(_)) => {}
+        (golden, actual) => mismatches.push(format!(
+            "{} is {}, expected something like {}",
+            path, actual, golden
+        )),
+    }
+}
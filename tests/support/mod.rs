@@ -0,0 +1,6 @@
+//! Stand-ins for the services jukebox talks to, for driving it end to end in
+//! tests. Not every test uses all of it.
+#![allow(dead_code)]
+
+pub mod lavalink;
+pub mod voice_server;
@@ -0,0 +1,413 @@
+//! A fake discord voice server: a websocket gateway that walks clients through
+//! the handshake and acks their heartbeats, and a udp socket that answers ip
+//! discovery and decrypts the audio sent to it.
+
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm,
+};
+use byteorder::{ByteOrder, NetworkEndian};
+use chacha20poly1305::XChaCha20Poly1305;
+use crypto_secretbox::XSalsa20Poly1305;
+use futures_util::{SinkExt, StreamExt};
+use jukebox::{
+    client::payloads::{VoiceUpdate, VoiceUpdateEvent},
+    crypto::EncryptionMode,
+};
+use serde_json::{json, Value};
+use tokio::{
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    time,
+};
+use tokio_tungstenite::tungstenite::Message;
+
+const IDENTIFY: u64 = 0;
+const SELECT_PROTOCOL: u64 = 1;
+const READY: u64 = 2;
+const HEARTBEAT: u64 = 3;
+const SESSION_DESCRIPTION: u64 = 4;
+const SPEAKING: u64 = 5;
+const HEARTBEAT_ACK: u64 = 6;
+const RESUME: u64 = 7;
+const HELLO: u64 = 8;
+const RESUMED: u64 = 9;
+
+const DISCOVERY_LEN: usize = 74;
+const KEEPALIVE_LEN: usize = 8;
+// how long a test waits for something to happen before failing
+const EVENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub const GUILD_ID: &str = "1";
+pub const SESSION_ID: &str = "mock-session";
+pub const TOKEN: &str = "mock-token";
+
+#[derive(Debug, Clone)]
+pub struct MockOptions {
+    /// Offered in Ready, in this order
+    pub modes: Vec<EncryptionMode>,
+    pub heartbeat_interval: Duration,
+    pub ssrc: u32,
+}
+
+impl Default for MockOptions {
+    fn default() -> Self {
+        Self {
+            modes: EncryptionMode::ALL.to_vec(),
+            heartbeat_interval: Duration::from_secs(5),
+            ssrc: 1,
+        }
+    }
+}
+
+/// An opus frame the server received and decrypted
+#[derive(Debug, Clone)]
+pub struct ReceivedAudio {
+    pub ssrc: u32,
+    pub sequence: u16,
+    pub timestamp: u32,
+    pub opus: Vec<u8>,
+    pub received_at: Instant,
+}
+
+/// What clients did, in the order the server saw it
+#[derive(Debug)]
+pub enum MockEvent {
+    /// The `d` of an identify
+    Identified(Value),
+    SelectedProtocol(EncryptionMode),
+    Heartbeat,
+    /// The `d` of a speaking update
+    Speaking(Value),
+    Resumed,
+    Keepalive,
+    Audio(ReceivedAudio),
+}
+
+// the mode and key handed out in the session description
+type Session = Arc<Mutex<Option<(EncryptionMode, [u8; 32])>>>;
+
+pub struct MockVoiceServer {
+    gateway_addr: SocketAddr,
+    udp_addr: SocketAddr,
+    events_rx: UnboundedReceiver<MockEvent>,
+}
+
+impl MockVoiceServer {
+    /// Listens on ephemeral ports of localhost, until the test's runtime shuts
+    /// down
+    pub async fn start(options: MockOptions) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let gateway_addr = listener.local_addr().unwrap();
+        let udp_addr = socket.local_addr().unwrap();
+        let (events_tx, events_rx) = unbounded_channel();
+        let session = Session::default();
+
+        tokio::spawn(serve_udp(
+            socket,
+            options.ssrc,
+            session.clone(),
+            events_tx.clone(),
+        ));
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let gateway = Gateway {
+                    options: options.clone(),
+                    udp_addr,
+                    session: session.clone(),
+                    events_tx: events_tx.clone(),
+                };
+                tokio::spawn(gateway.serve(stream));
+            }
+        });
+
+        Self {
+            gateway_addr,
+            udp_addr,
+            events_rx,
+        }
+    }
+
+    pub fn endpoint(&self) -> String {
+        format!("ws://{}", self.gateway_addr)
+    }
+
+    pub fn udp_addr(&self) -> SocketAddr {
+        self.udp_addr
+    }
+
+    /// A voice update pointing at this server
+    pub fn voice_update(&self) -> VoiceUpdate {
+        VoiceUpdate {
+            session_id: SESSION_ID.to_owned(),
+            channel_id: None,
+            event: VoiceUpdateEvent {
+                token: TOKEN.to_owned(),
+                guild_id: GUILD_ID.to_owned(),
+                endpoint: self.endpoint(),
+            },
+        }
+    }
+
+    /// Panics if nothing happens in a while
+    pub async fn next_event(&mut self) -> MockEvent {
+        time::timeout(EVENT_TIMEOUT, self.events_rx.recv())
+            .await
+            .expect("timed out waiting for the client")
+            .expect("mock server stopped")
+    }
+
+    /// Skips events until `f` picks one
+    pub async fn wait_for<T>(&mut self, mut f: impl FnMut(MockEvent) -> Option<T>) -> T {
+        loop {
+            if let Some(value) = f(self.next_event().await) {
+                return value;
+            }
+        }
+    }
+
+    pub async fn identified(&mut self) -> Value {
+        self.wait_for(|event| match event {
+            MockEvent::Identified(identify) => Some(identify),
+            _ => None,
+        })
+        .await
+    }
+
+    pub async fn selected_mode(&mut self) -> EncryptionMode {
+        self.wait_for(|event| match event {
+            MockEvent::SelectedProtocol(mode) => Some(mode),
+            _ => None,
+        })
+        .await
+    }
+
+    pub async fn speaking(&mut self) -> Value {
+        self.wait_for(|event| match event {
+            MockEvent::Speaking(speaking) => Some(speaking),
+            _ => None,
+        })
+        .await
+    }
+
+    pub async fn next_audio(&mut self) -> ReceivedAudio {
+        self.wait_for(|event| match event {
+            MockEvent::Audio(audio) => Some(audio),
+            _ => None,
+        })
+        .await
+    }
+}
+
+// the opcode of a gateway message, and a gateway message with opcode `op`
+fn opcode(message: &Value) -> Option<u64> {
+    message["op"].as_u64()
+}
+
+fn payload(op: u64, d: Value) -> Message {
+    Message::Text(json!({ "op": op, "d": d }).to_string().into())
+}
+
+struct Gateway {
+    options: MockOptions,
+    udp_addr: SocketAddr,
+    session: Session,
+    events_tx: UnboundedSender<MockEvent>,
+}
+
+impl Gateway {
+    async fn serve(self, stream: TcpStream) {
+        let Ok(ws) = tokio_tungstenite::accept_async(stream).await else {
+            return;
+        };
+        let (mut write, mut read) = ws.split();
+
+        let hello = json!({
+            "v": 8,
+            "heartbeat_interval": self.options.heartbeat_interval.as_millis() as f64,
+        });
+        if write.send(payload(HELLO, hello)).await.is_err() {
+            return;
+        }
+        while let Some(Ok(message)) = read.next().await {
+            let Message::Text(text) = message else {
+                continue;
+            };
+            let message: Value = serde_json::from_str(&text).expect("client sent invalid json");
+            let d = message["d"].clone();
+            let response = match opcode(&message) {
+                Some(IDENTIFY) => {
+                    _ = self.events_tx.send(MockEvent::Identified(d));
+                    Some(payload(READY, self.ready()))
+                }
+                Some(SELECT_PROTOCOL) => Some(payload(SESSION_DESCRIPTION, self.select(&d))),
+                Some(HEARTBEAT) => {
+                    _ = self.events_tx.send(MockEvent::Heartbeat);
+                    // v8 acks with just the nonce out of the object
+                    let ack = match d.get("t") {
+                        Some(nonce) => json!({ "t": nonce }),
+                        None => d,
+                    };
+                    Some(payload(HEARTBEAT_ACK, ack))
+                }
+                Some(SPEAKING) => {
+                    _ = self.events_tx.send(MockEvent::Speaking(d));
+                    None
+                }
+                Some(RESUME) => {
+                    _ = self.events_tx.send(MockEvent::Resumed);
+                    Some(payload(RESUMED, Value::Null))
+                }
+                _ => None,
+            };
+            if let Some(response) = response {
+                if write.send(response).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    fn ready(&self) -> Value {
+        json!({
+            "ssrc": self.options.ssrc,
+            "ip": self.udp_addr.ip().to_string(),
+            "port": self.udp_addr.port(),
+            "modes": self.options.modes,
+        })
+    }
+
+    fn select(&self, d: &Value) -> Value {
+        let mode: EncryptionMode =
+            serde_json::from_value(d["data"]["mode"].clone()).expect("unknown encryption mode");
+        assert!(
+            self.options
+                .modes
+                .iter()
+                .any(|offered| std::mem::discriminant(offered) == std::mem::discriminant(&mode)),
+            "client selected {:?}, which wasn't offered",
+            mode
+        );
+        let secret_key: [u8; 32] = rand::random();
+        *self.session.lock().unwrap() = Some((mode, secret_key));
+        _ = self.events_tx.send(MockEvent::SelectedProtocol(mode));
+        json!({ "mode": mode, "secret_key": secret_key })
+    }
+}
+
+async fn serve_udp(
+    socket: UdpSocket,
+    ssrc: u32,
+    session: Session,
+    events_tx: UnboundedSender<MockEvent>,
+) {
+    let mut buf = [0u8; 2048];
+    while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+        let packet = &buf[..len];
+        match len {
+            DISCOVERY_LEN if NetworkEndian::read_u16(&packet[0..2]) == 0x1 => {
+                _ = socket.send_to(&discovery_response(ssrc, from), from).await;
+            }
+            KEEPALIVE_LEN => {
+                _ = events_tx.send(MockEvent::Keepalive);
+                _ = socket.send_to(packet, from).await;
+            }
+            // rtcp sender reports
+            _ if len >= 2 && (200..=204).contains(&packet[1]) => {}
+            _ => {
+                let session = session.lock().unwrap();
+                let (mode, key) = session
+                    .as_ref()
+                    .expect("audio before the session description");
+                let audio = decrypt(packet, mode, key).expect("undecryptable rtp packet");
+                _ = events_tx.send(MockEvent::Audio(audio));
+            }
+        }
+    }
+}
+
+fn discovery_response(ssrc: u32, from: SocketAddr) -> [u8; DISCOVERY_LEN] {
+    let mut response = [0u8; DISCOVERY_LEN];
+    NetworkEndian::write_u16(&mut response[0..2], 0x2);
+    NetworkEndian::write_u16(&mut response[2..4], 70);
+    NetworkEndian::write_u32(&mut response[4..8], ssrc);
+    let ip = from.ip().to_string();
+    response[8..8 + ip.len()].copy_from_slice(ip.as_bytes());
+    NetworkEndian::write_u16(&mut response[DISCOVERY_LEN - 2..], from.port());
+    response
+}
+
+// jukebox sends neither csrcs nor header extensions. this goes by discord's docs
+// rather than jukebox's crypto module, so the two can't agree on a mistake.
+fn decrypt(packet: &[u8], mode: &EncryptionMode, key: &[u8; 32]) -> Option<ReceivedAudio> {
+    if packet.len() < 12 || packet[0] != 0x80 {
+        return None;
+    }
+    let (header, body) = packet.split_at(12);
+    // the nonces that are counters go big endian at the front, zeroes after
+    let counter_nonce = |len: usize| {
+        let (body, counter) = body.split_at(body.len().checked_sub(4)?);
+        let mut nonce = vec![0u8; len];
+        nonce[..4].copy_from_slice(counter);
+        Some((body, nonce))
+    };
+    let opus = match mode {
+        // the header is authenticated, not encrypted
+        EncryptionMode::Aes256GcmRtpSize(_) => {
+            let (ciphertext, nonce) = counter_nonce(12)?;
+            let payload = Payload {
+                msg: ciphertext,
+                aad: header,
+            };
+            Aes256Gcm::new_from_slice(key)
+                .ok()?
+                .decrypt(nonce.as_slice().into(), payload)
+        }
+        EncryptionMode::XChaCha20Poly1305RtpSize(_) => {
+            let (ciphertext, nonce) = counter_nonce(24)?;
+            let payload = Payload {
+                msg: ciphertext,
+                aad: header,
+            };
+            XChaCha20Poly1305::new_from_slice(key)
+                .ok()?
+                .decrypt(nonce.as_slice().into(), payload)
+        }
+        EncryptionMode::XSalsa20Poly1305Lite(_) => {
+            let (ciphertext, nonce) = counter_nonce(24)?;
+            XSalsa20Poly1305::new_from_slice(key)
+                .ok()?
+                .decrypt(nonce.as_slice().into(), ciphertext)
+        }
+        // a random nonce after the ciphertext
+        EncryptionMode::XSalsa20Poly1305Suffix => {
+            let (ciphertext, nonce) = body.split_at(body.len().checked_sub(24)?);
+            XSalsa20Poly1305::new_from_slice(key)
+                .ok()?
+                .decrypt(nonce.into(), ciphertext)
+        }
+        // the rtp header is the nonce
+        EncryptionMode::XSalsa20Poly1305 => {
+            let mut nonce = [0u8; 24];
+            nonce[..12].copy_from_slice(header);
+            XSalsa20Poly1305::new_from_slice(key)
+                .ok()?
+                .decrypt(&nonce.into(), body)
+        }
+    }
+    .ok()?;
+    Some(ReceivedAudio {
+        ssrc: NetworkEndian::read_u32(&packet[8..12]),
+        sequence: NetworkEndian::read_u16(&packet[2..4]),
+        timestamp: NetworkEndian::read_u32(&packet[4..8]),
+        opus,
+        received_at: Instant::now(),
+    })
+}
@@ -0,0 +1,234 @@
+mod support;
+
+use std::{mem::discriminant, time::Duration};
+
+use futures_util::stream;
+use jukebox::{
+    config::VoiceConfiguration,
+    crypto::EncryptionMode,
+    source::PacketStream,
+    voice::{VoiceEvent, VoiceManager},
+};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+use support::voice_server::{MockOptions, MockVoiceServer, GUILD_ID, SESSION_ID, TOKEN};
+
+const USER_ID: &str = "1234";
+
+fn config() -> VoiceConfiguration {
+    VoiceConfiguration {
+        prebuffer_frames: 1,
+        ..Default::default()
+    }
+}
+
+async fn connect(
+    server: &MockVoiceServer,
+    config: &VoiceConfiguration,
+) -> (VoiceManager, UnboundedReceiver<VoiceEvent>) {
+    let (events_tx, events_rx) = unbounded_channel();
+    let manager = VoiceManager::new(USER_ID, server.voice_update(), config, events_tx)
+        .await
+        .expect("could not connect to the mock voice server");
+    (manager, events_rx)
+}
+
+// distinct, recognizable opus-ish frames
+fn frames(count: u8) -> Vec<Vec<u8>> {
+    (0..count).map(|i| vec![0xfc, i, 0xff - i]).collect()
+}
+
+fn packet_stream(frames: Vec<Vec<u8>>) -> PacketStream {
+    Box::pin(stream::iter(frames.into_iter().map(Ok)))
+}
+
+#[tokio::test]
+async fn identifies_with_the_voice_update() {
+    let mut server = MockVoiceServer::start(MockOptions::default()).await;
+    let (manager, _events) = connect(&server, &config()).await;
+
+    let identify = server.identified().await;
+    assert_eq!(identify["server_id"], GUILD_ID);
+    assert_eq!(identify["user_id"], USER_ID);
+    assert_eq!(identify["session_id"], SESSION_ID);
+    assert_eq!(identify["token"], TOKEN);
+    // no channel id in the voice update, so no DAVE either
+    assert!(identify.get("max_dave_protocol_version").is_none());
+    assert!(manager.is_connected());
+}
+
+#[tokio::test]
+async fn picks_the_most_preferred_offered_mode() {
+    let mut server = MockVoiceServer::start(MockOptions {
+        modes: vec![
+            EncryptionMode::XSalsa20Poly1305,
+            EncryptionMode::XChaCha20Poly1305RtpSize(0),
+        ],
+        ..Default::default()
+    })
+    .await;
+    let _connection = connect(&server, &config()).await;
+    assert_eq!(
+        discriminant(&server.selected_mode().await),
+        discriminant(&EncryptionMode::XChaCha20Poly1305RtpSize(0))
+    );
+}
+
+#[tokio::test]
+async fn fails_without_a_common_mode() {
+    let server = MockVoiceServer::start(MockOptions {
+        modes: vec![EncryptionMode::XSalsa20Poly1305],
+        ..Default::default()
+    })
+    .await;
+    let config = VoiceConfiguration {
+        encryption_modes: vec![EncryptionMode::Aes256GcmRtpSize(0)],
+        ..config()
+    };
+    let (events_tx, _events_rx) = unbounded_channel();
+    let result = VoiceManager::new(USER_ID, server.voice_update(), &config, events_tx).await;
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn audio_decrypts_in_every_mode() {
+    for mode in EncryptionMode::ALL {
+        let mut server = MockVoiceServer::start(MockOptions {
+            modes: vec![mode],
+            ..Default::default()
+        })
+        .await;
+        let (mut manager, _events) = connect(&server, &config()).await;
+        assert_eq!(
+            discriminant(&server.selected_mode().await),
+            discriminant(&mode)
+        );
+
+        let sent = frames(5);
+        manager
+            .play_stream(packet_stream(sent.clone()), Duration::ZERO, None)
+            .await
+            .unwrap();
+        for frame in &sent {
+            assert_eq!(&server.next_audio().await.opus, frame, "{:?}", mode);
+        }
+    }
+}
+
+#[tokio::test]
+async fn frames_are_paced_and_numbered() {
+    const FRAMES: u8 = 50;
+    let mut server = MockVoiceServer::start(MockOptions {
+        ssrc: 42,
+        ..Default::default()
+    })
+    .await;
+    let (mut manager, _events) = connect(&server, &config()).await;
+    manager
+        .play_stream(packet_stream(frames(FRAMES)), Duration::ZERO, None)
+        .await
+        .unwrap();
+
+    let first = server.next_audio().await;
+    assert_eq!(first.ssrc, 42);
+    let mut last = first.clone();
+    for _ in 1..FRAMES {
+        let audio = server.next_audio().await;
+        assert_eq!(audio.sequence, last.sequence.wrapping_add(1));
+        assert_eq!(audio.timestamp, last.timestamp.wrapping_add(960));
+        last = audio;
+    }
+    // 20ms a frame, with some slack for a busy test machine
+    let elapsed = last.received_at - first.received_at;
+    let expected = Duration::from_millis(20 * (FRAMES as u64 - 1));
+    assert!(
+        elapsed >= expected - Duration::from_millis(100)
+            && elapsed <= expected + Duration::from_millis(500),
+        "{} frames took {:?}",
+        FRAMES,
+        elapsed
+    );
+}
+
+#[tokio::test]
+async fn shared_sender_sends_every_players_audio() {
+    let config = VoiceConfiguration {
+        shared_sender: true,
+        ..config()
+    };
+    let mut players = Vec::new();
+    for i in 0..2 {
+        let server = MockVoiceServer::start(MockOptions::default()).await;
+        let (mut manager, events) = connect(&server, &config).await;
+        let sent: Vec<Vec<u8>> = frames(10)
+            .into_iter()
+            .map(|f| [f, vec![i]].concat())
+            .collect();
+        manager
+            .play_stream(packet_stream(sent.clone()), Duration::ZERO, None)
+            .await
+            .unwrap();
+        players.push((server, manager, events, sent));
+    }
+
+    for (server, _, _, sent) in &mut players {
+        for frame in sent.iter() {
+            assert_eq!(&server.next_audio().await.opus, frame);
+        }
+    }
+    // the playback ran out on its own
+    for (_, _, events, _) in &mut players {
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(5), events.recv())
+                .await
+                .expect("the track never finished");
+            if matches!(
+                event.expect("the connection went away"),
+                VoiceEvent::TrackFinished
+            ) {
+                break;
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn speaks_while_playing() {
+    let mut server = MockVoiceServer::start(MockOptions {
+        ssrc: 7,
+        ..Default::default()
+    })
+    .await;
+    let (mut manager, _events) = connect(&server, &config()).await;
+    manager
+        .play_stream(packet_stream(frames(3)), Duration::ZERO, None)
+        .await
+        .unwrap();
+
+    let speaking = server.speaking().await;
+    assert_eq!(speaking["ssrc"], 7);
+    assert_eq!(speaking["speaking"], 1);
+}
+
+#[tokio::test]
+async fn heartbeats_are_acknowledged() {
+    let mut server = MockVoiceServer::start(MockOptions {
+        heartbeat_interval: Duration::from_millis(50),
+        ..Default::default()
+    })
+    .await;
+    let (manager, _events) = connect(&server, &config()).await;
+
+    server
+        .wait_for(|event| {
+            matches!(event, support::voice_server::MockEvent::Heartbeat).then_some(())
+        })
+        .await;
+    for _ in 0..50 {
+        if manager.ping().is_some() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("heartbeat was never acknowledged");
+}